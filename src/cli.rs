@@ -0,0 +1,316 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use failure::Error;
+use install::InstallOptions;
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+// Directory name used under $XDG_CONFIG_HOME and /etc when looking for config files.
+const CONFIG_DIRECTORY_NAME: &str = "rusty-build-light";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Drive the lights until stopped. The default if no subcommand is given.
+    Run,
+    // Load the config, report any problems with it, and exit.
+    CheckConfig,
+    // Poll every light once, print what it found, and exit.
+    Status,
+    // Run the power-on test on every light, and exit.
+    TestLeds,
+    // Print the most recent status changes of one light, or of every light, and exit.
+    History { light: Option<String>, count: u32 },
+    // Print failure rates, time to recovery and flakiness for one light, or for every light, and exit.
+    Stats { light: Option<String> },
+    // Set up the build light as a systemd service, or undo that.
+    Install(InstallOptions),
+    Uninstall(InstallOptions),
+    Version,
+}
+
+pub struct Args {
+    pub command: Command,
+    pub config_path: Option<PathBuf>,
+    pub log_config_path: Option<PathBuf>,
+    // Poll as usual, but leave the GPIO pins alone.
+    pub dry_run: bool,
+}
+
+pub fn parse_args() -> Args {
+    parse_args_from(env::args_os())
+}
+
+fn parse_args_from<I, T>(args: I) -> Args
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = App::new(crate_name!())
+        .version(crate_version!())
+        .about("Shows the status of CI servers and other services on RGB LEDs.")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Path to config.toml. Searched for next to the executable, in $XDG_CONFIG_HOME/rusty-build-light and in /etc/rusty-build-light by default.")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-config")
+                .long("log-config")
+                .value_name("FILE")
+                .help("Path to log4rs.yml. Searched for in the same places as config.toml by default.")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Poll as usual, but don't touch the GPIO pins.")
+                .global(true),
+        )
+        .subcommand(SubCommand::with_name("run").about("Drive the lights until stopped (default)"))
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Check the config file for problems and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("status").about("Poll every light once, print the results and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("test-leds").about("Run the power-on test on every light and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Print each light's most recent status changes and exit")
+                .arg(
+                    Arg::with_name("light")
+                        .value_name("LIGHT")
+                        .help("Only show this light, e.g. jenkins"),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .short("n")
+                        .long("count")
+                        .value_name("N")
+                        .help("How many status changes to show for each light")
+                        .default_value("10"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Print each light's failure rates, time to recovery and flaky jobs and exit")
+                .arg(
+                    Arg::with_name("light")
+                        .value_name("LIGHT")
+                        .help("Only show this light, e.g. jenkins"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("install")
+                .about("Install a systemd unit, and a config.toml to fill in")
+                .arg(root_arg())
+                .arg(
+                    Arg::with_name("user")
+                        .long("user")
+                        .value_name("USER")
+                        .help("The user the service runs as. Needs access to the GPIO pins.")
+                        .default_value("root"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("uninstall")
+                .about("Remove the systemd unit")
+                .arg(root_arg())
+                .arg(
+                    Arg::with_name("purge")
+                        .long("purge")
+                        .help("Also remove the installed config.toml"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("version").about("Print the version and exit"))
+        .get_matches_from(args);
+
+    let command = match matches.subcommand() {
+        ("check-config", _) => Command::CheckConfig,
+        ("status", _) => Command::Status,
+        ("test-leds", _) => Command::TestLeds,
+        ("history", Some(history_matches)) => Command::History {
+            light: history_matches.value_of("light").map(String::from),
+            count: value_t!(history_matches, "count", u32).unwrap_or_else(|e| e.exit()),
+        },
+        ("stats", Some(stats_matches)) => Command::Stats {
+            light: stats_matches.value_of("light").map(String::from),
+        },
+        ("install", Some(install_matches)) => Command::Install(install_options(install_matches)),
+        ("uninstall", Some(uninstall_matches)) => {
+            Command::Uninstall(install_options(uninstall_matches))
+        }
+        ("version", _) => Command::Version,
+        _ => Command::Run,
+    };
+
+    // Global args given after the subcommand only show up in the subcommand's matches.
+    let sub_matches = matches.subcommand().1;
+    let value_of = |name: &str| {
+        sub_matches
+            .and_then(|x: &ArgMatches| x.value_of_os(name))
+            .or_else(|| matches.value_of_os(name))
+            .map(PathBuf::from)
+    };
+
+    Args {
+        command,
+        config_path: value_of("config"),
+        log_config_path: value_of("log-config"),
+        dry_run: matches.is_present("dry-run")
+            || sub_matches.map_or(false, |x| x.is_present("dry-run")),
+    }
+}
+
+fn root_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("root")
+        .long("root")
+        .value_name("DIR")
+        .help("Install under this directory instead of /, e.g. when building a package.")
+        .default_value("/")
+}
+
+fn install_options(matches: &ArgMatches) -> InstallOptions {
+    InstallOptions {
+        root: matches.value_of_os("root").map(PathBuf::from).unwrap_or_default(),
+        user: matches.value_of("user").unwrap_or("root").to_string(),
+        purge: matches.is_present("purge"),
+    }
+}
+
+// Uses the path given on the command line if there is one. Otherwise, returns the first of the
+// default locations that file_name exists in.
+pub fn find_file(path: Option<&PathBuf>, file_name: &str) -> Result<PathBuf, Error> {
+    if let Some(path) = path {
+        return if path.is_file() {
+            Ok(path.clone())
+        } else {
+            Err(format_err!("{} does not exist.", path.display()))
+        };
+    }
+
+    let candidates = search_paths(file_name);
+    candidates
+        .iter()
+        .find(|x| x.is_file())
+        .cloned()
+        .ok_or_else(|| {
+            let searched: Vec<String> = candidates.iter().map(|x| x.display().to_string()).collect();
+            format_err!(
+                "Unable to find {}. Looked in: {}",
+                file_name,
+                searched.join(", ")
+            )
+        })
+}
+
+// Next to the executable comes first, so existing installs that rely on build.rs copying the
+// config files into target/ keep working.
+fn search_paths(file_name: &str) -> Vec<PathBuf> {
+    let mut directories = Vec::new();
+    if let Some(exe_directory) = env::current_exe()
+        .ok()
+        .and_then(|x| x.parent().map(PathBuf::from))
+    {
+        directories.push(exe_directory);
+    }
+    let xdg_config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")));
+    if let Some(xdg_config_home) = xdg_config_home {
+        directories.push(xdg_config_home.join(CONFIG_DIRECTORY_NAME));
+    }
+    directories.push(PathBuf::from("/etc").join(CONFIG_DIRECTORY_NAME));
+
+    directories.into_iter().map(|x| x.join(file_name)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_subcommand_runs() {
+        let args = parse_args_from(vec!["rusty_build_light"]);
+
+        assert_eq!(args.command, Command::Run);
+        assert!(args.config_path.is_none());
+        assert!(!args.dry_run);
+    }
+
+    #[test]
+    fn global_flags_work_after_subcommand() {
+        let args = parse_args_from(vec![
+            "rusty_build_light",
+            "status",
+            "--config",
+            "/tmp/config.toml",
+            "--dry-run",
+        ]);
+
+        assert_eq!(args.command, Command::Status);
+        assert_eq!(args.config_path, Some(PathBuf::from("/tmp/config.toml")));
+        assert!(args.dry_run);
+    }
+
+    #[test]
+    fn history_takes_a_light_and_count() {
+        let args = parse_args_from(vec!["rusty_build_light", "history", "jenkins", "-n", "5"]);
+
+        assert_eq!(
+            args.command,
+            Command::History {
+                light: Some("jenkins".to_string()),
+                count: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn stats_takes_an_optional_light() {
+        let args = parse_args_from(vec!["rusty_build_light", "stats"]);
+
+        assert_eq!(args.command, Command::Stats { light: None });
+    }
+
+    #[test]
+    fn install_takes_a_root_and_user() {
+        let args = parse_args_from(vec![
+            "rusty_build_light",
+            "install",
+            "--root",
+            "/tmp/root",
+            "--user",
+            "pi",
+        ]);
+
+        assert_eq!(
+            args.command,
+            Command::Install(InstallOptions {
+                root: PathBuf::from("/tmp/root"),
+                user: "pi".to_string(),
+                purge: false,
+            })
+        );
+    }
+
+    #[test]
+    fn config_is_searched_for_in_xdg_and_etc() {
+        let paths = search_paths("config.toml");
+
+        assert_eq!(
+            paths.last(),
+            Some(&PathBuf::from("/etc/rusty-build-light/config.toml"))
+        );
+        assert!(paths.len() >= 2);
+    }
+}
@@ -1,13 +1,426 @@
+use config_validation::{check_config, ConfigProblem, InvalidConfig, Severity};
+use failure::Error;
+use debounce::DebouncePolicy;
+use retry::RetryPolicy;
+use scheduler::PollSchedule;
+use secrets::Secret;
+use supervisor::{GiveUpAction, RestartPolicy};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::Duration;
+
 #[derive(Deserialize)]
 pub struct Config {
-    pub allowed_failures: u32,
+    // No longer used, each light has its own restart budget in [supervisor.<light>]. Only kept to warn about it.
+    pub allowed_failures: Option<u32>,
 
     pub jenkins_username: String,
-    pub jenkins_password: String,
+    pub jenkins_password: Secret,
     pub jenkins_base_url: String,
     pub jenkins_led_pins: Vec<u16>,
+    // How many jobs' last builds are fetched at once, and how long a poll waits for them all.
+    pub jenkins_max_concurrent_fetches: Option<usize>,
+    pub jenkins_fetch_deadline_secs: Option<u64>,
 
-    pub unity_cloud_api_token: String,
+    pub unity_cloud_api_token: Secret,
     pub unity_base_url: String,
     pub unity_led_pins: Vec<u16>,
+
+    pub webhook_listen_address: Option<String>,
+    pub webhook_secret: Option<Secret>,
+
+    pub health_check_led_pins: Option<Vec<u16>>,
+    #[serde(default)]
+    pub health_check_endpoints: Vec<HealthCheckEndpoint>,
+
+    pub alertmanager_base_url: Option<String>,
+    #[serde(default)]
+    pub alertmanager_matchers: Vec<String>,
+    #[serde(default = "default_alertmanager_severity_label")]
+    pub alertmanager_severity_label: String,
+    pub alertmanager_led_pins: Option<Vec<u16>>,
+
+    // The colour every LED is left showing once the build light exits, as R, G, B. Off if not set.
+    pub offline_led_color: Option<Vec<i32>>,
+
+    // Where each light's last status is saved, so it can be shown again straight after a restart. "" to not save it.
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+
+    #[serde(default)]
+    pub schedule: HashMap<String, PollScheduleConfig>,
+
+    #[serde(default)]
+    pub retry: HashMap<String, RetryPolicyConfig>,
+
+    #[serde(default)]
+    pub supervisor: HashMap<String, RestartPolicyConfig>,
+
+    #[serde(default)]
+    pub debounce: HashMap<String, DebounceConfig>,
+
+    #[serde(default)]
+    pub http: HttpConfig,
+
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    #[serde(default)]
+    pub history: HistoryConfig,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config, Error> {
+        let mut config_text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut config_text))
+            .map_err(|err| format_err!("Unable to read config file: {}", err))?;
+        Config::from_toml(&config_text)
+    }
+
+    // Warnings are logged, and any errors fail the whole config.
+    pub fn from_toml(config_text: &str) -> Result<Config, Error> {
+        let (config, problems) = check_config(config_text);
+        let (errors, warnings): (Vec<ConfigProblem>, Vec<ConfigProblem>) = problems
+            .into_iter()
+            .partition(|x| x.severity == Severity::Error);
+        for warning in &warnings {
+            warn!("Config file {}", warning);
+        }
+        match config {
+            Some(config) if errors.is_empty() => Ok(config),
+            _ => Err(InvalidConfig { problems: errors }.into()),
+        }
+    }
+
+    pub fn offline_color(&self) -> (i32, i32, i32) {
+        match self.offline_led_color {
+            Some(ref color) if color.len() == 3 => (color[0], color[1], color[2]),
+            _ => (0, 0, 0),
+        }
+    }
+
+    // Any values missing from the light's [schedule.<light>] section fall back to the integration's defaults.
+    pub fn poll_schedule(&self, light: &str, default: PollSchedule) -> PollSchedule {
+        match self.schedule.get(light) {
+            Some(overrides) => PollSchedule {
+                interval: overrides
+                    .interval_secs
+                    .map_or(default.interval, Duration::from_secs),
+                jitter: overrides
+                    .jitter_secs
+                    .map_or(default.jitter, Duration::from_secs),
+                min_spacing: overrides
+                    .min_spacing_secs
+                    .map_or(default.min_spacing, Duration::from_secs),
+            },
+            None => default,
+        }
+    }
+
+    // Likewise, anything missing from [retry.<light>] falls back to RetryPolicy's defaults.
+    pub fn retry_policy(&self, light: &str) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        match self.retry.get(light) {
+            Some(overrides) => RetryPolicy {
+                max_attempts: overrides.max_attempts.unwrap_or(default.max_attempts),
+                initial_delay: overrides
+                    .initial_delay_ms
+                    .map_or(default.initial_delay, Duration::from_millis),
+                max_delay: overrides
+                    .max_delay_ms
+                    .map_or(default.max_delay, Duration::from_millis),
+                failure_threshold: overrides
+                    .failure_threshold
+                    .unwrap_or(default.failure_threshold),
+                open_duration: overrides
+                    .open_secs
+                    .map_or(default.open_duration, Duration::from_secs),
+                max_open_duration: overrides
+                    .max_open_secs
+                    .map_or(default.max_open_duration, Duration::from_secs),
+            },
+            None => default,
+        }
+    }
+
+    // And anything missing from [supervisor.<light>] falls back to RestartPolicy's defaults.
+    pub fn restart_policy(&self, light: &str) -> RestartPolicy {
+        let default = RestartPolicy::default();
+        match self.supervisor.get(light) {
+            Some(overrides) => RestartPolicy {
+                max_restarts: overrides.max_restarts.unwrap_or(default.max_restarts),
+                window: overrides
+                    .window_secs
+                    .map_or(default.window, Duration::from_secs),
+                initial_delay: overrides
+                    .restart_delay_ms
+                    .map_or(default.initial_delay, Duration::from_millis),
+                max_delay: overrides
+                    .max_restart_delay_ms
+                    .map_or(default.max_delay, Duration::from_millis),
+                on_give_up: overrides.on_give_up.unwrap_or(default.on_give_up),
+            },
+            None => default,
+        }
+    }
+
+    // Lights without a [debounce.<light>] section show every failure straight away.
+    pub fn debounce_policy(&self, light: &str) -> DebouncePolicy {
+        match self.debounce.get(light) {
+            Some(overrides) => DebouncePolicy {
+                failing_polls: overrides.failing_polls,
+                failing_for: overrides.failing_secs.map(Duration::from_secs),
+                flaky_jobs_warn: overrides.flaky_jobs_warn.unwrap_or(false),
+            },
+            None => DebouncePolicy::default(),
+        }
+    }
+
+    // Whether anything the light depends on differs between the two configs, meaning it needs restarting to pick up the change.
+    pub fn light_changed(&self, other: &Config, light: &str) -> bool {
+        let integration_changed = match light {
+            "jenkins" => {
+                self.jenkins_username != other.jenkins_username
+                    || self.jenkins_password != other.jenkins_password
+                    || self.jenkins_base_url != other.jenkins_base_url
+                    || self.jenkins_led_pins != other.jenkins_led_pins
+                    || self.jenkins_max_concurrent_fetches != other.jenkins_max_concurrent_fetches
+                    || self.jenkins_fetch_deadline_secs != other.jenkins_fetch_deadline_secs
+            }
+            "unity" => {
+                self.unity_cloud_api_token != other.unity_cloud_api_token
+                    || self.unity_base_url != other.unity_base_url
+                    || self.unity_led_pins != other.unity_led_pins
+            }
+            "health" => {
+                self.health_check_led_pins != other.health_check_led_pins
+                    || self.health_check_endpoints != other.health_check_endpoints
+            }
+            "alertmanager" => {
+                self.alertmanager_base_url != other.alertmanager_base_url
+                    || self.alertmanager_matchers != other.alertmanager_matchers
+                    || self.alertmanager_severity_label != other.alertmanager_severity_label
+                    || self.alertmanager_led_pins != other.alertmanager_led_pins
+            }
+            _ => true,
+        };
+        integration_changed
+            || self.schedule.get(light) != other.schedule.get(light)
+            || self.retry.get(light) != other.retry.get(light)
+            || self.supervisor.get(light) != other.supervisor.get(light)
+            || self.debounce.get(light) != other.debounce.get(light)
+            || self.http_client_config(light) != other.http_client_config(light)
+    }
+
+    // Settings in [http.<light>] take precedence over the ones in [http].
+    pub fn http_client_config(&self, light: &str) -> HttpClientConfig {
+        let defaults = &self.http.defaults;
+        match self.http.lights.get(light) {
+            Some(overrides) => HttpClientConfig {
+                timeout_secs: overrides.timeout_secs.or(defaults.timeout_secs),
+                http_proxy: overrides
+                    .http_proxy
+                    .clone()
+                    .or_else(|| defaults.http_proxy.clone()),
+                https_proxy: overrides
+                    .https_proxy
+                    .clone()
+                    .or_else(|| defaults.https_proxy.clone()),
+                no_proxy: overrides
+                    .no_proxy
+                    .clone()
+                    .or_else(|| defaults.no_proxy.clone()),
+                ca_bundles: overrides
+                    .ca_bundles
+                    .clone()
+                    .or_else(|| defaults.ca_bundles.clone()),
+                client_identity_file: overrides
+                    .client_identity_file
+                    .clone()
+                    .or_else(|| defaults.client_identity_file.clone()),
+                client_identity_password: overrides
+                    .client_identity_password
+                    .clone()
+                    .or_else(|| defaults.client_identity_password.clone()),
+                user_agent: overrides
+                    .user_agent
+                    .clone()
+                    .or_else(|| defaults.user_agent.clone()),
+            },
+            None => defaults.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq)]
+pub struct HealthCheckEndpoint {
+    pub url: String,
+    #[serde(default = "default_health_check_method")]
+    pub method: String,
+    #[serde(default = "default_expected_status_codes")]
+    pub expected_status_codes: Vec<u16>,
+    pub body_contains: Option<String>,
+    pub body_regex: Option<String>,
+    pub max_latency_ms: Option<u64>,
+}
+
+#[derive(Deserialize, PartialEq)]
+pub struct PollScheduleConfig {
+    pub interval_secs: Option<u64>,
+    pub jitter_secs: Option<u64>,
+    pub min_spacing_secs: Option<u64>,
+}
+
+// The [http] section holds the settings for every light, and [http.<light>] sections override them per light.
+#[derive(Deserialize, Default)]
+pub struct HttpConfig {
+    #[serde(flatten)]
+    pub defaults: HttpClientConfig,
+    #[serde(flatten)]
+    pub lights: HashMap<String, HttpClientConfig>,
+}
+
+#[derive(Deserialize, Default, Clone, PartialEq)]
+pub struct HttpClientConfig {
+    pub timeout_secs: Option<u64>,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Option<Vec<String>>,
+    pub ca_bundles: Option<Vec<String>>,
+    pub client_identity_file: Option<String>,
+    pub client_identity_password: Option<Secret>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Deserialize, PartialEq)]
+pub struct RetryPolicyConfig {
+    pub max_attempts: Option<u32>,
+    pub initial_delay_ms: Option<u64>,
+    pub max_delay_ms: Option<u64>,
+    pub failure_threshold: Option<u32>,
+    pub open_secs: Option<u64>,
+    pub max_open_secs: Option<u64>,
+}
+
+#[derive(Deserialize, PartialEq)]
+pub struct RestartPolicyConfig {
+    pub max_restarts: Option<u32>,
+    pub window_secs: Option<u64>,
+    pub restart_delay_ms: Option<u64>,
+    pub max_restart_delay_ms: Option<u64>,
+    pub on_give_up: Option<GiveUpAction>,
+}
+
+#[derive(Deserialize, PartialEq)]
+pub struct DebounceConfig {
+    pub failing_polls: Option<u32>,
+    pub failing_secs: Option<u64>,
+    pub flaky_jobs_warn: Option<bool>,
+}
+
+// Where every status change and build result is recorded, to be looked back on with the history subcommand.
+#[derive(Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HistoryConfig {
+    // An SQLite database, created if it doesn't exist. "" to not record any history.
+    pub file: String,
+    // Anything older is deleted. 0 keeps everything.
+    pub retention_days: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            file: "history.db".to_string(),
+            retention_days: 90,
+        }
+    }
+}
+
+// Only used if there's no log4rs.yml.
+#[derive(Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub level: String,
+    // Also log to this file, if set, rotating it once it reaches max_file_size_mb.
+    pub file: Option<String>,
+    pub max_file_size_mb: u64,
+    // How many rotated files to keep.
+    pub max_files: u32,
+    // Write structured events to this file, one JSON object per line.
+    pub events_file: Option<String>,
+    // Log to journald instead of the console. Events go to journald too, with their fields as journal fields.
+    pub journald: bool,
+    // Levels for individual log targets, e.g. jenkins = "debug".
+    pub levels: HashMap<String, String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: "info".to_string(),
+            file: None,
+            max_file_size_mb: 10,
+            max_files: 10,
+            events_file: None,
+            journald: false,
+            levels: HashMap::new(),
+        }
+    }
+}
+
+fn default_health_check_method() -> String {
+    "GET".to_string()
+}
+
+fn default_expected_status_codes() -> Vec<u16> {
+    vec![200]
+}
+
+fn default_state_file() -> String {
+    "state.json".to_string()
+}
+
+fn default_alertmanager_severity_label() -> String {
+    "severity".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"jenkins_username = "build-light"
+jenkins_password = "hunter2"
+jenkins_base_url = "https://jenkins.example.com"
+jenkins_led_pins = [17, 27, 22]
+unity_cloud_api_token = "token"
+unity_base_url = "https://build-api.cloud.unity3d.com/api/v1/orgs/org/projects/project/buildtargets"
+unity_led_pins = [5, 6, 13]
+"#;
+
+    #[test]
+    fn only_affected_lights_change() {
+        let old_config = Config::from_toml(CONFIG).unwrap();
+        let new_config = Config::from_toml(
+            &CONFIG.replace("[17, 27, 22]", "[17, 27, 23]"),
+        ).unwrap();
+
+        assert!(new_config.light_changed(&old_config, "jenkins"));
+        assert!(!new_config.light_changed(&old_config, "unity"));
+        assert!(!new_config.light_changed(&old_config, "health"));
+    }
+
+    #[test]
+    fn per_light_sections_only_change_their_light() {
+        let old_config = Config::from_toml(CONFIG).unwrap();
+        let new_config =
+            Config::from_toml(&format!("{}\n[http.unity]\ntimeout_secs = 60\n", CONFIG))
+                .unwrap();
+
+        assert!(!new_config.light_changed(&old_config, "jenkins"));
+        assert!(new_config.light_changed(&old_config, "unity"));
+    }
 }
@@ -0,0 +1,677 @@
+use config_file::{Config, HttpClientConfig};
+use failure::Fail;
+use integrations::http_health_integration::HealthCheck;
+use lights::LIGHT_KEYS;
+use logging::parse_level;
+use reqwest::Url;
+use secrets::resolve_secrets;
+use serde_ignored;
+use serde_path_to_error;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use toml;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    // Worth fixing, but the build light can run as-is.
+    Warning,
+    // The build light won't start until it's fixed.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    // Path to the offending key, e.g. "health_check_endpoints[1].url". Empty if the problem isn't with any one key.
+    pub key: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        write!(f, "{}: ", self.severity)?;
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+// Every error found in a config file, reported together so they can all be fixed in one go.
+#[derive(Debug)]
+pub struct InvalidConfig {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Found {} problem(s) in the config file:", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n    {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for InvalidConfig {}
+
+// Loads the config, and checks it for anything that would stop a light from working.
+// Returns the config if it could be loaded at all, along with every problem found in it.
+pub fn check_config(config_text: &str) -> (Option<Config>, Vec<ConfigProblem>) {
+    let mut checker = ConfigChecker {
+        config_text,
+        problems: Vec::new(),
+    };
+    let config = checker.load();
+    if let Some(ref config) = config {
+        checker.check_deprecated(config);
+        checker.check_pins(config);
+        checker.check_offline_color(config);
+        checker.check_jenkins_fetches(config);
+        checker.check_debounce(config);
+        checker.check_light_sections(config);
+        checker.check_urls(config);
+        checker.check_credentials(config);
+        checker.check_logging(config);
+    }
+    checker
+        .problems
+        .sort_by_key(|x| x.line.unwrap_or(usize::max_value()));
+    (config, checker.problems)
+}
+
+struct ConfigChecker<'a> {
+    config_text: &'a str,
+    problems: Vec<ConfigProblem>,
+}
+
+impl<'a> ConfigChecker<'a> {
+    fn add(&mut self, severity: Severity, key: &str, message: String) {
+        self.problems.push(ConfigProblem {
+            severity,
+            key: key.to_string(),
+            line: find_line(self.config_text, key),
+            message,
+        });
+    }
+
+    fn load(&mut self) -> Option<Config> {
+        // The TOML parser's errors already say which line they're on.
+        let mut value: toml::Value = match self.config_text.parse() {
+            Ok(value) => value,
+            Err(e) => {
+                self.add(Severity::Error, "", e.to_string());
+                return None;
+            }
+        };
+        if let Err(e) = resolve_secrets(&mut value) {
+            self.add(Severity::Error, "", e.to_string());
+            return None;
+        }
+        self.remove_unknown_http_keys(&mut value);
+
+        let mut unknown_keys = Vec::new();
+        let result: Result<Config, _> = {
+            let mut on_unknown_key = |path: serde_ignored::Path| unknown_keys.push(key_path(&path));
+            serde_path_to_error::deserialize(serde_ignored::Deserializer::new(
+                value,
+                &mut on_unknown_key,
+            ))
+        };
+        for key in unknown_keys {
+            self.warn_unknown_key(&key);
+        }
+        match result {
+            Ok(config) => Some(config),
+            Err(e) => {
+                self.add(Severity::Error, &e.path().to_string(), e.inner().to_string());
+                None
+            }
+        }
+    }
+
+    // [http] holds the defaults and a table per light side by side, so serde takes any key that isn't a default
+    // for a light's table, and fails with a confusing type error. Such keys are warned about and dropped instead.
+    // Being flattened, the lights' tables don't report their own unknown keys either, so they're checked here too.
+    fn remove_unknown_http_keys(&mut self, value: &mut toml::Value) {
+        let http = match value.get_mut("http").and_then(toml::Value::as_table_mut) {
+            Some(http) => http,
+            None => return,
+        };
+        let defaults: toml::value::Table = http
+            .iter()
+            .filter(|x| !x.1.is_table())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        for key in unknown_http_client_keys(defaults) {
+            http.remove(&key);
+            self.warn_unknown_key(&format!("http.{}", key));
+        }
+
+        for (light, table) in http.iter_mut() {
+            let table = match table.as_table_mut() {
+                Some(table) => table,
+                None => continue,
+            };
+            for key in unknown_http_client_keys(table.clone()) {
+                table.remove(&key);
+                self.warn_unknown_key(&format!("http.{}.{}", light, key));
+            }
+        }
+    }
+
+    fn warn_unknown_key(&mut self, key: &str) {
+        let message = unknown_key_message(key);
+        self.add(Severity::Warning, key, message);
+    }
+
+    fn check_deprecated(&mut self, config: &Config) {
+        if config.allowed_failures.is_some() {
+            self.add(
+                Severity::Warning,
+                "allowed_failures",
+                "No longer used. Each light now has its own restart budget, set in [supervisor.<light>]."
+                    .to_string(),
+            );
+        }
+    }
+
+    fn check_pins(&mut self, config: &Config) {
+        let lights = [
+            ("jenkins_led_pins", Some(&config.jenkins_led_pins)),
+            ("unity_led_pins", Some(&config.unity_led_pins)),
+            ("health_check_led_pins", config.health_check_led_pins.as_ref()),
+            ("alertmanager_led_pins", config.alertmanager_led_pins.as_ref()),
+        ];
+        let mut used_pins: HashMap<u16, &str> = HashMap::new();
+        for &(key, pins) in &lights {
+            let pins = match pins {
+                Some(pins) => pins,
+                None => continue,
+            };
+            if pins.len() != 3 {
+                self.add(
+                    Severity::Error,
+                    key,
+                    format!(
+                        "Expected 3 pins, in the order R, G, B, but found {}.",
+                        pins.len()
+                    ),
+                );
+            }
+            for &pin in pins {
+                match used_pins.get(&pin) {
+                    Some(&other_key) if other_key == key => self.add(
+                        Severity::Error,
+                        key,
+                        format!("Pin {} is listed more than once.", pin),
+                    ),
+                    Some(&other_key) => self.add(
+                        Severity::Error,
+                        key,
+                        format!("Pin {} is already used by {}.", pin, other_key),
+                    ),
+                    None => {
+                        used_pins.insert(pin, key);
+                    }
+                }
+            }
+        }
+    }
+
+    // Soft PWM stops when we exit, so the offline colour can't have anything in-between.
+    fn check_offline_color(&mut self, config: &Config) {
+        let color = match config.offline_led_color {
+            Some(ref color) => color,
+            None => return,
+        };
+        if color.len() != 3 || color.iter().any(|&x| x != 0 && x != 100) {
+            self.add(
+                Severity::Error,
+                "offline_led_color",
+                "Expected 3 values, in the order R, G, B, each either 0 or 100.".to_string(),
+            );
+        }
+    }
+
+    // With either at 0, no job would ever be fetched.
+    fn check_jenkins_fetches(&mut self, config: &Config) {
+        let limits = [
+            (
+                "jenkins_max_concurrent_fetches",
+                config.jenkins_max_concurrent_fetches.map(|x| x as u64),
+            ),
+            ("jenkins_fetch_deadline_secs", config.jenkins_fetch_deadline_secs),
+        ];
+        for &(key, value) in &limits {
+            if value == Some(0) {
+                self.add(Severity::Error, key, "Must be at least 1.".to_string());
+            }
+        }
+    }
+
+    fn check_debounce(&mut self, config: &Config) {
+        for (light, debounce) in &config.debounce {
+            if debounce.failing_polls == Some(0) {
+                self.add(
+                    Severity::Error,
+                    &format!("debounce.{}.failing_polls", light),
+                    "Must be at least 1.".to_string(),
+                );
+            }
+            if debounce.flaky_jobs_warn == Some(true) && config.history.file.is_empty() {
+                self.add(
+                    Severity::Warning,
+                    &format!("debounce.{}.flaky_jobs_warn", light),
+                    "Has no effect while history is turned off, since flaky jobs are found from the history."
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    // A misspelled light in e.g. [schedule.jenkns] would otherwise leave the light on its defaults without a word.
+    fn check_light_sections(&mut self, config: &Config) {
+        let sections = [
+            ("http", config.http.lights.keys().collect::<Vec<_>>()),
+            ("schedule", config.schedule.keys().collect()),
+            ("retry", config.retry.keys().collect()),
+            ("supervisor", config.supervisor.keys().collect()),
+            ("debounce", config.debounce.keys().collect()),
+        ];
+        for &(section, ref lights) in &sections {
+            for light in lights {
+                if !LIGHT_KEYS.contains(&light.as_str()) {
+                    self.add(
+                        Severity::Warning,
+                        &format!("{}.{}", section, light),
+                        format!(
+                            "Unknown light. Expected one of {}. It will be ignored.",
+                            LIGHT_KEYS.join(", ")
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_urls(&mut self, config: &Config) {
+        self.check_base_url("jenkins_base_url", &config.jenkins_base_url);
+        self.check_base_url("unity_base_url", &config.unity_base_url);
+        if let Some(ref base_url) = config.alertmanager_base_url {
+            self.check_base_url("alertmanager_base_url", base_url);
+        }
+
+        for (index, endpoint) in config.health_check_endpoints.iter().enumerate() {
+            let key = format!("health_check_endpoints[{}]", index);
+            if self.check_url(&format!("{}.url", key), &endpoint.url) {
+                if let Err(e) = HealthCheck::from_config(endpoint) {
+                    self.add(Severity::Error, &key, e.to_string());
+                }
+            }
+        }
+
+        self.check_http_section("http", &config.http.defaults);
+        for (light, http_config) in &config.http.lights {
+            self.check_http_section(&format!("http.{}", light), http_config);
+        }
+
+        if let Some(ref listen_address) = config.webhook_listen_address {
+            if let Err(e) = listen_address.parse::<SocketAddr>() {
+                self.add(
+                    Severity::Error,
+                    "webhook_listen_address",
+                    format!("Not a valid address and port, e.g. 0.0.0.0:8080: {}", e),
+                );
+            }
+        }
+    }
+
+    // Paths get appended to base URLs as-is, so a trailing slash ends up doubled.
+    fn check_base_url(&mut self, key: &str, url: &str) {
+        if self.check_url(key, url) && url.ends_with('/') {
+            self.add(
+                Severity::Error,
+                key,
+                format!("Must not end with a slash. Use {}", url.trim_end_matches('/')),
+            );
+        }
+    }
+
+    // Returns whether the URL is usable.
+    fn check_url(&mut self, key: &str, url: &str) -> bool {
+        if url.is_empty() {
+            self.add(Severity::Error, key, "Must be set.".to_string());
+            return false;
+        }
+        match Url::parse(url) {
+            Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => true,
+            Ok(_) => {
+                self.add(
+                    Severity::Error,
+                    key,
+                    format!("{} must be an http:// or https:// URL.", url),
+                );
+                false
+            }
+            Err(e) => {
+                self.add(
+                    Severity::Error,
+                    key,
+                    format!("{} is not a valid URL: {}", url, e),
+                );
+                false
+            }
+        }
+    }
+
+    fn check_http_section(&mut self, section: &str, http_config: &HttpClientConfig) {
+        if let Some(ref proxy) = http_config.http_proxy {
+            self.check_url(&format!("{}.http_proxy", section), proxy);
+        }
+        if let Some(ref proxy) = http_config.https_proxy {
+            self.check_url(&format!("{}.https_proxy", section), proxy);
+        }
+        // A light can't start without them, so catch them here rather than when the light starts.
+        for ca_bundle in http_config.ca_bundles.iter().flatten() {
+            self.check_file_exists(&format!("{}.ca_bundles", section), ca_bundle);
+        }
+        if let Some(ref identity_file) = http_config.client_identity_file {
+            self.check_file_exists(&format!("{}.client_identity_file", section), identity_file);
+        }
+    }
+
+    fn check_file_exists(&mut self, key: &str, path: &str) {
+        if !Path::new(path).is_file() {
+            self.add(Severity::Error, key, format!("{} does not exist.", path));
+        }
+    }
+
+    fn check_logging(&mut self, config: &Config) {
+        if let Err(e) = parse_level(&config.logging.level) {
+            self.add(Severity::Error, "logging.level", e.to_string());
+        }
+        for (target, level) in &config.logging.levels {
+            if let Err(e) = parse_level(level) {
+                self.add(Severity::Error, &format!("logging.levels.{}", target), e.to_string());
+            }
+        }
+    }
+
+    // Some servers allow anonymous access, so missing credentials don't stop the build light from starting.
+    fn check_credentials(&mut self, config: &Config) {
+        if config.jenkins_username.is_empty() || config.jenkins_password.expose().is_empty() {
+            let key = if config.jenkins_username.is_empty() {
+                "jenkins_username"
+            } else {
+                "jenkins_password"
+            };
+            self.add(
+                Severity::Warning,
+                key,
+                "Is empty. Requests to Jenkins will only work if it allows anonymous access."
+                    .to_string(),
+            );
+        }
+        if config.unity_cloud_api_token.expose().is_empty() {
+            self.add(
+                Severity::Warning,
+                "unity_cloud_api_token",
+                "Is empty. Unity Cloud will reject every request.".to_string(),
+            );
+        }
+    }
+}
+
+// Top-level keys of a table that's meant to be an HttpClientConfig, that it doesn't have.
+fn unknown_http_client_keys(table: toml::value::Table) -> Vec<String> {
+    let mut unknown_keys = Vec::new();
+    // Type errors are left for loading the whole config to report, with their full path.
+    let _ = serde_ignored::deserialize::<_, _, HttpClientConfig>(toml::Value::Table(table), |path| {
+        unknown_keys.push(key_path(&path))
+    });
+    unknown_keys
+}
+
+// Keys that have been replaced get told what by, rather than just being ignored.
+fn unknown_key_message(key: &str) -> String {
+    let is_split_timeout =
+        key.ends_with(".connect_timeout_secs") || key.ends_with(".read_timeout_secs");
+    if key.starts_with("http.") && is_split_timeout {
+        return "No longer used. Replaced by timeout_secs, which covers the whole request.".to_string();
+    }
+    "Unknown key. It will be ignored.".to_string()
+}
+
+// Formats the paths serde_ignored reports the same way as serde_path_to_error's, e.g. "health_check_endpoints[1].url".
+fn key_path(path: &serde_ignored::Path) -> String {
+    match *path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => format!("{}[{}]", key_path(parent), index),
+        serde_ignored::Path::Map { parent, ref key } => {
+            let parent = key_path(parent);
+            if parent.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", parent, key)
+            }
+        }
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => key_path(parent),
+    }
+}
+
+// "health_check_endpoints[1].url" -> ["health_check_endpoints", "1", "url"]
+fn key_segments(key: &str) -> Vec<String> {
+    key.replace('[', ".")
+        .replace(']', "")
+        .split('.')
+        .map(|x| x.trim().trim_matches('"').to_string())
+        .filter(|x| !x.is_empty() && x != "?")
+        .collect()
+}
+
+// Finds the line a key is set on, or failing that, the line of the closest table it's in.
+// Line numbers are 1-based. Doesn't understand all of TOML, e.g. inline tables, but copes with
+// everything the example config uses.
+fn find_line(config_text: &str, key: &str) -> Option<usize> {
+    let target = key_segments(key);
+    if target.is_empty() {
+        return None;
+    }
+
+    let mut table: Vec<String> = Vec::new();
+    let mut array_table_counts: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut best_match: Option<(usize, usize)> = None;
+    for (index, line) in config_text.lines().enumerate() {
+        let line = line.trim();
+        let path = if line.starts_with("[[") {
+            let name = key_segments(line.trim_start_matches('[').split(']').next().unwrap_or(""));
+            let count = array_table_counts.entry(name.clone()).or_insert(0);
+            table = name;
+            table.push(count.to_string());
+            *count += 1;
+            table.clone()
+        } else if line.starts_with('[') {
+            table = key_segments(line.trim_start_matches('[').split(']').next().unwrap_or(""));
+            table.clone()
+        } else if line.starts_with('#') {
+            continue;
+        } else {
+            match line.find('=') {
+                Some(equals) => {
+                    let mut path = table.clone();
+                    path.extend(key_segments(&line[..equals]));
+                    path
+                }
+                None => continue,
+            }
+        };
+
+        let is_better_match =
+            best_match.map_or(true, |(matched_segments, _)| path.len() > matched_segments);
+        if target.starts_with(&path) && is_better_match {
+            best_match = Some((path.len(), index + 1));
+        }
+    }
+    best_match.map(|(_, line)| line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID_CONFIG: &str = r#"jenkins_username = "build-light"
+jenkins_password = "hunter2"
+jenkins_base_url = "https://jenkins.example.com"
+jenkins_led_pins = [17, 27, 22]
+unity_cloud_api_token = "token"
+unity_base_url = "https://build-api.cloud.unity3d.com/api/v1/orgs/org/projects/project/buildtargets"
+unity_led_pins = [5, 6, 13]
+health_check_led_pins = [19, 26, 21]
+
+[[health_check_endpoints]]
+url = "https://staging.example.com/health"
+
+[[health_check_endpoints]]
+url = "https://api.staging.example.com/health"
+"#;
+
+    fn problems(config_text: &str) -> Vec<ConfigProblem> {
+        check_config(config_text).1
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        let (config, problems) = check_config(VALID_CONFIG);
+
+        assert!(config.is_some());
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn every_pin_problem_is_reported_with_its_line() {
+        let config_text = VALID_CONFIG
+            .replace("[17, 27, 22]", "[17, 27]")
+            .replace("[5, 6, 13]", "[5, 6, 17]");
+        let problems = problems(&config_text);
+
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert_eq!(problems[0].key, "jenkins_led_pins");
+        assert_eq!(problems[0].line, Some(4));
+        assert_eq!(problems[1].key, "unity_led_pins");
+        assert_eq!(problems[1].line, Some(7));
+        assert!(problems.iter().all(|x| x.severity == Severity::Error));
+    }
+
+    #[test]
+    fn bad_urls_are_errors() {
+        let config_text = VALID_CONFIG
+            .replace(
+                "https://jenkins.example.com",
+                "https://jenkins.example.com/",
+            )
+            .replace("https://api.staging.example.com/health", "not a url");
+        let problems = problems(&config_text);
+
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert_eq!(problems[0].key, "jenkins_base_url");
+        assert_eq!(problems[1].key, "health_check_endpoints[1].url");
+        assert_eq!(problems[1].line, Some(14));
+    }
+
+    #[test]
+    fn unknown_keys_and_empty_credentials_are_warnings() {
+        let config_text = VALID_CONFIG.replace("\"hunter2\"", "\"\"")
+            + "\n[schedule.jenkins]\ninterval_seconds = 30\n";
+        let (config, problems) = check_config(&config_text);
+
+        assert!(config.is_some());
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems.iter().all(|x| x.severity == Severity::Warning));
+        let unknown_key = problems
+            .iter()
+            .find(|x| x.key == "schedule.jenkins.interval_seconds")
+            .unwrap();
+        assert_eq!(unknown_key.line, Some(17));
+    }
+
+    #[test]
+    fn unknown_http_keys_and_lights_are_warnings() {
+        let config_text = VALID_CONFIG.to_string()
+            + "\n[http]\nconect_timeout_secs = 5\ntimeout_secs = 10\n\
+               [http.jenkns]\nuser_agent = \"build-light\"\n\
+               [schedule.untiy]\ninterval_secs = 30\n";
+        let (config, problems) = check_config(&config_text);
+
+        let config = config.unwrap();
+        assert_eq!(config.http.defaults.timeout_secs, Some(10));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems.iter().all(|x| x.severity == Severity::Warning));
+        let keys: Vec<(&str, Option<usize>)> =
+            problems.iter().map(|x| (x.key.as_str(), x.line)).collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("http.conect_timeout_secs", Some(17)),
+                ("http.jenkns", Some(19)),
+                ("schedule.untiy", Some(21)),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowed_failures_is_deprecated() {
+        let config_text = format!("allowed_failures = 0\n{}", VALID_CONFIG);
+        let (config, problems) = check_config(&config_text);
+
+        assert!(config.is_some());
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert_eq!(problems[0].key, "allowed_failures");
+        assert_eq!(problems[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn split_timeouts_point_at_timeout_secs() {
+        let config_text = VALID_CONFIG.to_string()
+            + "\n[http]\nread_timeout_secs = 10\n[http.jenkins]\nconnect_timeout_secs = 5\n";
+        let (config, problems) = check_config(&config_text);
+
+        assert!(config.is_some());
+        let keys: Vec<&str> = problems.iter().map(|x| x.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["http.read_timeout_secs", "http.jenkins.connect_timeout_secs"]
+        );
+        assert!(problems
+            .iter()
+            .all(|x| x.severity == Severity::Warning && x.message.contains("timeout_secs")));
+    }
+
+    #[test]
+    fn type_errors_point_at_their_key() {
+        let config_text = VALID_CONFIG.replace("[5, 6, 13]", "\"5, 6, 13\"");
+        let (config, problems) = check_config(&config_text);
+
+        assert!(config.is_none());
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert_eq!(problems[0].key, "unity_led_pins");
+        assert_eq!(problems[0].line, Some(7));
+    }
+}
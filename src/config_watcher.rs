@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// Notices when the config file should be reloaded: either it's been modified, or we've been sent a SIGHUP.
+// SIGHUP is handled along with the other signals, see shutdown::handle_signals.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    last_modified: Option<SystemTime>,
+    reload_requested: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path, reload_requested: Arc<AtomicBool>) -> ConfigWatcher {
+        ConfigWatcher {
+            path: path.to_path_buf(),
+            last_modified: modified_time(path),
+            reload_requested,
+        }
+    }
+
+    // Whether anything has happened since the last call that means the config should be reloaded.
+    pub fn should_reload(&mut self) -> bool {
+        let signaled = self.reload_requested.swap(false, Ordering::SeqCst);
+        if signaled {
+            info!("SIGHUP received, reloading config file...");
+        }
+
+        // Editors often replace the file rather than writing to it, so it can briefly be missing.
+        // Wait until it's back rather than treating that as a change.
+        let modified = match modified_time(&self.path) {
+            Some(modified) => modified,
+            None => return signaled,
+        };
+        let changed = self.last_modified != Some(modified);
+        self.last_modified = Some(modified);
+        if changed {
+            info!("{} has changed, reloading...", self.path.display());
+        }
+        signaled || changed
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn modifying_the_file_triggers_a_reload_once() {
+        let path = env::temp_dir().join("rusty_build_light_test_watched_config.toml");
+        fs::write(&path, "allowed_failures = 0").unwrap();
+        let mut watcher = ConfigWatcher::new(&path, Arc::new(AtomicBool::new(false)));
+        assert!(!watcher.should_reload());
+
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&path, "allowed_failures = 1").unwrap();
+        let reloads = (watcher.should_reload(), watcher.should_reload());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloads, (true, false));
+    }
+
+    #[test]
+    fn sighup_triggers_a_reload_once() {
+        let path = env::temp_dir().join("rusty_build_light_test_sighup_config.toml");
+        fs::write(&path, "allowed_failures = 0").unwrap();
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        let mut watcher = ConfigWatcher::new(&path, Arc::clone(&reload_requested));
+
+        reload_requested.store(true, Ordering::SeqCst);
+        let reloads = (watcher.should_reload(), watcher.should_reload());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloads, (true, false));
+    }
+}
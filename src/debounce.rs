@@ -0,0 +1,142 @@
+use remote_status::RemoteStatus;
+use status_report::StatusReport;
+use std::time::{Duration, Instant};
+
+// Keeps a light from going red over a failure that might not last, e.g. a job that fails once and passes
+// on rerun. Off unless the light has a [debounce.<light>] section.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DebouncePolicy {
+    // Failing polls in a row before the light shows red.
+    pub failing_polls: Option<u32>,
+    // How long the light has to keep failing before it shows red.
+    // If both are set, whichever is reached first shows red. If neither is, red is shown straight away.
+    pub failing_for: Option<Duration>,
+    // Show failing jobs that the history says are flaky as warnings, rather than failures.
+    pub flaky_jobs_warn: bool,
+}
+
+// Tracks how long a light's polls have been failing. Lasts as long as the light.
+pub struct Debouncer {
+    policy: DebouncePolicy,
+    failing_polls: u32,
+    failing_since: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(policy: DebouncePolicy) -> Debouncer {
+        Debouncer {
+            policy,
+            failing_polls: 0,
+            failing_since: None,
+        }
+    }
+
+    // The status the light should show for a poll, given the status it's showing now. Until a failure's
+    // lasted long enough, that's whatever was already showing. Only passing or warning ends a failure:
+    // a poll that's in progress or unknown, e.g. while the job's rerun, neither counts towards it nor resets it.
+    pub fn filter(&mut self, status: RemoteStatus, showing: Option<RemoteStatus>, now: Instant) -> RemoteStatus {
+        match status {
+            RemoteStatus::Failing => {}
+            RemoteStatus::Passing | RemoteStatus::Warning => {
+                self.failing_polls = 0;
+                self.failing_since = None;
+                return status;
+            }
+            RemoteStatus::InProgress | RemoteStatus::Unknown => return status,
+        }
+
+        self.failing_polls += 1;
+        let failing_since = *self.failing_since.get_or_insert(now);
+        let confirmed = match (self.policy.failing_polls, self.policy.failing_for) {
+            (None, None) => true,
+            (polls, duration) => {
+                polls.map_or(false, |x| self.failing_polls >= x)
+                    || duration.map_or(false, |x| now.duration_since(failing_since) >= x)
+            }
+        };
+        match showing {
+            // Nothing to hold a failure back with straight after starting up.
+            Some(showing) if !confirmed => showing,
+            _ => RemoteStatus::Failing,
+        }
+    }
+}
+
+// Marks failing entries that are flaky as warnings instead. If they were all that was failing,
+// the whole report is downgraded to a warning too.
+pub fn warn_for_flaky_jobs<F: Fn(&str) -> bool>(report: &mut StatusReport, is_flaky: F) {
+    let mut any_flaky = false;
+    for entry in &mut report.entries {
+        if entry.status == RemoteStatus::Failing && is_flaky(&entry.name) {
+            entry.status = RemoteStatus::Warning;
+            any_flaky = true;
+        }
+    }
+    if any_flaky
+        && report.status == RemoteStatus::Failing
+        && report.entries.iter().all(|x| x.status != RemoteStatus::Failing)
+    {
+        report.status = RemoteStatus::Warning;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status_report::StatusEntry;
+
+    #[test]
+    fn failures_are_held_back_until_they_last() {
+        let policy = DebouncePolicy {
+            failing_polls: Some(3),
+            failing_for: Some(Duration::from_secs(60)),
+            flaky_jobs_warn: false,
+        };
+        let mut debouncer = Debouncer::new(policy);
+        let passing = Some(RemoteStatus::Passing);
+        let start = Instant::now();
+
+        // Fails once, then passes on rerun: never shows red.
+        assert_eq!(debouncer.filter(RemoteStatus::Failing, passing, start), RemoteStatus::Passing);
+        assert_eq!(debouncer.filter(RemoteStatus::InProgress, passing, start), RemoteStatus::InProgress);
+        assert_eq!(debouncer.filter(RemoteStatus::Passing, passing, start), RemoteStatus::Passing);
+
+        // Three failing polls in a row.
+        assert_eq!(debouncer.filter(RemoteStatus::Failing, passing, start), RemoteStatus::Passing);
+        assert_eq!(debouncer.filter(RemoteStatus::Failing, passing, start), RemoteStatus::Passing);
+        assert_eq!(debouncer.filter(RemoteStatus::Failing, passing, start), RemoteStatus::Failing);
+
+        // Or failing for long enough.
+        debouncer.filter(RemoteStatus::Passing, passing, start);
+        assert_eq!(debouncer.filter(RemoteStatus::Failing, passing, start), RemoteStatus::Passing);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(debouncer.filter(RemoteStatus::Failing, passing, later), RemoteStatus::Failing);
+
+        assert_eq!(
+            Debouncer::new(policy).filter(RemoteStatus::Failing, None, start),
+            RemoteStatus::Failing
+        );
+    }
+
+    #[test]
+    fn flaky_failures_are_only_warnings() {
+        let entry = |name: &str| StatusEntry {
+            name: name.to_string(),
+            status: RemoteStatus::Failing,
+            latency: None,
+            detail: None,
+            build: None,
+        };
+        let mut report =
+            StatusReport::with_entries(RemoteStatus::Failing, vec![entry("flaky"), entry("broken")]);
+
+        warn_for_flaky_jobs(&mut report, |job| job == "flaky");
+        assert_eq!(report.entries[0].status, RemoteStatus::Warning);
+        assert_eq!(report.status, RemoteStatus::Failing);
+
+        report.entries.pop();
+        report.entries[0].status = RemoteStatus::Failing;
+        warn_for_flaky_jobs(&mut report, |job| job == "flaky");
+        assert_eq!(report.status, RemoteStatus::Warning);
+    }
+}
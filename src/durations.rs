@@ -0,0 +1,6 @@
+use std::time::Duration;
+
+// Duration::as_millis needs Rust 1.33.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
@@ -1,3 +1,12 @@
+use hyper;
+use native_tls;
+use reqwest;
+use reqwest::header::{Headers, RetryAfter};
+use reqwest::StatusCode;
+use secrets::redact_url;
+use std::io;
+use std::time::Duration;
+
 #[derive(Debug, Fail)]
 pub enum UnityRetrievalError {
     #[fail(
@@ -5,6 +14,149 @@ pub enum UnityRetrievalError {
     )]
     NoBuildsReturned,
 
-    #[fail(display = "Unity Cloud Build returned an HTTP error: {}", http_error_message)]
-    HttpError { http_error_message: String },
+    #[fail(display = "Unity Cloud Build returned an HTTP error: {}", _0)]
+    HttpError(#[cause] NetworkError),
+}
+
+impl UnityRetrievalError {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            UnityRetrievalError::NoBuildsReturned => "no_builds_returned",
+            UnityRetrievalError::HttpError(ref e) => e.kind(),
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum NetworkError {
+    #[fail(display = "Unable to parse url: {}", url)]
+    InvalidUrl { url: String },
+
+    // Covers DNS failures, refused connections and connections dropped partway through.
+    #[fail(display = "Unable to connect to {}: {}", url, message)]
+    ConnectionFailed { url: String, message: String },
+
+    #[fail(display = "HTTP call to {} timed out.", url)]
+    TimedOut { url: String },
+
+    #[fail(display = "TLS error while connecting to {}: {}", url, message)]
+    Tls { url: String, message: String },
+
+    // 401 or 403. Retrying won't help until someone fixes the credentials in config.
+    #[fail(
+        display = "HTTP call to {} was refused with code: {}. Check the configured credentials.",
+        url, status
+    )]
+    Unauthorized { url: String, status: StatusCode },
+
+    #[fail(display = "HTTP call to {} failed with code: 404 Not Found", url)]
+    NotFound { url: String },
+
+    #[fail(display = "HTTP call to {} was rate limited.", url)]
+    RateLimited {
+        url: String,
+        retry_after: Option<Duration>,
+    },
+
+    #[fail(display = "HTTP call to {} failed with server error: {}", url, status)]
+    ServerError { url: String, status: StatusCode },
+
+    #[fail(display = "HTTP call to {} failed with code: {}", url, status)]
+    UnexpectedStatus { url: String, status: StatusCode },
+
+    // The response wasn't the JSON we expected. path is where in the document it went wrong, e.g. jobs[3].color
+    #[fail(
+        display = "Response from {} didn't match the expected format at {}: {}",
+        url, path, message
+    )]
+    InvalidResponse {
+        url: String,
+        path: String,
+        message: String,
+    },
+}
+
+impl NetworkError {
+    // Works out what actually went wrong from the layers of errors reqwest and hyper wrap it in.
+    pub fn from_reqwest(url: &str, err: &reqwest::Error) -> NetworkError {
+        let url = redact_url(url);
+        if let Some(status) = err.status() {
+            return NetworkError::from_status(&url, status, &Headers::new());
+        }
+
+        let io_error = match err.get_ref() {
+            Some(inner) => match inner.downcast_ref::<hyper::Error>() {
+                Some(hyper::Error::Timeout) => return NetworkError::TimedOut { url },
+                Some(hyper::Error::Io(io_error)) => Some(io_error),
+                _ => inner.downcast_ref::<io::Error>(),
+            },
+            None => None,
+        };
+        if let Some(io_error) = io_error {
+            if io_error.kind() == io::ErrorKind::TimedOut {
+                return NetworkError::TimedOut { url };
+            }
+            // Failed TLS handshakes come back from the connector as an IO error wrapping the TLS one.
+            if io_error
+                .get_ref()
+                .map_or(false, |inner| inner.is::<native_tls::Error>())
+            {
+                return NetworkError::Tls {
+                    url,
+                    message: io_error.to_string(),
+                };
+            }
+        }
+
+        NetworkError::ConnectionFailed {
+            url,
+            message: err.to_string(),
+        }
+    }
+
+    pub fn from_status(url: &str, status: StatusCode, headers: &Headers) -> NetworkError {
+        let url = redact_url(url);
+        match status {
+            StatusCode::Unauthorized | StatusCode::Forbidden => {
+                NetworkError::Unauthorized { url, status }
+            }
+            StatusCode::NotFound => NetworkError::NotFound { url },
+            StatusCode::TooManyRequests => NetworkError::RateLimited {
+                url,
+                retry_after: match headers.get::<RetryAfter>() {
+                    Some(RetryAfter::Delay(delay)) => Some(*delay),
+                    _ => None,
+                },
+            },
+            status if status.is_server_error() => NetworkError::ServerError { url, status },
+            status => NetworkError::UnexpectedStatus { url, status },
+        }
+    }
+
+    // A short, stable name for the kind of error, for structured logs.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            NetworkError::InvalidUrl { .. } => "invalid_url",
+            NetworkError::ConnectionFailed { .. } => "connection_failed",
+            NetworkError::TimedOut { .. } => "timed_out",
+            NetworkError::Tls { .. } => "tls",
+            NetworkError::Unauthorized { .. } => "unauthorized",
+            NetworkError::NotFound { .. } => "not_found",
+            NetworkError::RateLimited { .. } => "rate_limited",
+            NetworkError::ServerError { .. } => "server_error",
+            NetworkError::UnexpectedStatus { .. } => "unexpected_status",
+            NetworkError::InvalidResponse { .. } => "invalid_response",
+        }
+    }
+
+    // Whether trying again in a little while might go better.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            NetworkError::ConnectionFailed { .. }
+            | NetworkError::TimedOut { .. }
+            | NetworkError::RateLimited { .. }
+            | NetworkError::ServerError { .. } => true,
+            _ => false,
+        }
+    }
 }
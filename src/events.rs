@@ -0,0 +1,131 @@
+use chrono::Utc;
+use durations;
+use remote_status::RemoteStatus;
+use retry::CircuitState;
+use serde_json;
+use status_report::StatusReport;
+use std::time::Duration;
+
+// Events are logged under this target, so they can be sent somewhere of their own. See [logging] in config.toml.
+pub const EVENTS_TARGET: &str = "events";
+
+// Something a light did, logged as a JSON object so it can be shipped to a log pipeline and queried.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    // An integration polled its server.
+    Poll {
+        integration: &'a str,
+        status: RemoteStatus,
+        // How many of the jobs, builds, endpoints or alerts the status was derived from are in each state.
+        passing: usize,
+        warning: usize,
+        failing: usize,
+        in_progress: usize,
+        unknown: usize,
+        duration_ms: u64,
+        circuit_state: CircuitState,
+        error_kind: Option<&'a str>,
+        error: Option<&'a str>,
+    },
+    // A poll was due, but the circuit breaker is open.
+    PollSkipped {
+        integration: &'a str,
+        circuit_state: CircuitState,
+    },
+    // A light's status changed, from either a poll or a webhook.
+    Transition {
+        integration: &'a str,
+        from: Option<RemoteStatus>,
+        to: RemoteStatus,
+        source: &'a str,
+    },
+    // A light's LED started showing something different.
+    LedChanged {
+        integration: &'a str,
+        led: &'a str,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub fn poll(integration: &'a str, report: &'a StatusReport, duration: Duration) -> Event<'a> {
+        let count = |status| report.entries.iter().filter(|x| x.status == status).count();
+        Event::Poll {
+            integration,
+            status: report.status,
+            passing: count(RemoteStatus::Passing),
+            warning: count(RemoteStatus::Warning),
+            failing: count(RemoteStatus::Failing),
+            in_progress: count(RemoteStatus::InProgress),
+            unknown: count(RemoteStatus::Unknown),
+            duration_ms: durations::millis(duration),
+            circuit_state: report.circuit_state,
+            error_kind: report.error_kind,
+            error: report.error.as_ref().map(String::as_str),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TimestampedEvent<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+pub fn emit(event: &Event) {
+    let timestamped = TimestampedEvent {
+        time: Utc::now().to_rfc3339(),
+        event,
+    };
+    match serde_json::to_string(&timestamped) {
+        Ok(json) => info!(target: EVENTS_TARGET, "{}", json),
+        Err(e) => warn!("Unable to serialize event. Error: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use status_report::StatusEntry;
+
+    #[test]
+    fn poll_events_have_counts_and_error_fields() {
+        let mut report = StatusReport::with_entries(
+            RemoteStatus::Failing,
+            vec![
+                StatusEntry {
+                    name: "build".to_string(),
+                    status: RemoteStatus::Failing,
+                    latency: None,
+                    detail: None,
+                    build: None,
+                },
+                StatusEntry {
+                    name: "deploy".to_string(),
+                    status: RemoteStatus::InProgress,
+                    latency: None,
+                    detail: None,
+                    build: None,
+                },
+            ],
+        );
+        report.error_kind = Some("timed_out");
+        let event = Event::poll("jenkins", &report, Duration::from_millis(1500));
+        let json: Value = serde_json::to_value(&TimestampedEvent {
+            time: "now".to_string(),
+            event: &event,
+        }).unwrap();
+
+        assert_eq!(json["event"], "poll");
+        assert_eq!(json["time"], "now");
+        assert_eq!(json["integration"], "jenkins");
+        assert_eq!(json["status"], "failing");
+        assert_eq!(json["failing"], 1);
+        assert_eq!(json["in_progress"], 1);
+        assert_eq!(json["duration_ms"], 1500);
+        assert_eq!(json["circuit_state"], "closed");
+        assert_eq!(json["error_kind"], "timed_out");
+    }
+}
@@ -0,0 +1,145 @@
+use errors::NetworkError;
+use futures::{future, Future};
+use network::{HttpClient, HttpFetcher, HttpResponse, ResponseFuture};
+use reqwest::header::{ETag, EntityTag, Headers, IfNoneMatch};
+use reqwest::{Method, StatusCode, Url};
+use retry::RetryPolicy;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+// Serves canned responses by URL, for testing integrations without a real server.
+// Any URL it hasn't been given a response for fails, the same way an unreachable server would.
+#[derive(Default)]
+pub struct FixtureFetcher {
+    responses: HashMap<String, FixtureResponse>,
+    // For delayed responses. Set by into_client.
+    handle: Option<Handle>,
+}
+
+struct FixtureResponse {
+    status: StatusCode,
+    body: String,
+    etag: Option<EntityTag>,
+    // How long to take to respond, like a busy server.
+    delay: Duration,
+}
+
+impl FixtureFetcher {
+    pub fn new() -> FixtureFetcher {
+        FixtureFetcher::default()
+    }
+
+    // Responds to url with a 200 and the contents of tests/fixtures/<fixture>.
+    pub fn with_fixture(self, url: &str, fixture: &str) -> FixtureFetcher {
+        self.with_response(url, StatusCode::Ok, read_fixture(fixture), None)
+    }
+
+    // Like with_fixture, but also sends an ETag, and responds with 304 Not Modified to requests that already have it.
+    pub fn with_etag_fixture(self, url: &str, fixture: &str, etag: &str) -> FixtureFetcher {
+        let etag = EntityTag::new(false, etag.to_string());
+        self.with_response(url, StatusCode::Ok, read_fixture(fixture), Some(etag))
+    }
+
+    // Responds to url with the given status code and an empty body.
+    pub fn with_status(self, url: &str, status: StatusCode) -> FixtureFetcher {
+        self.with_response(url, status, String::new(), None)
+    }
+
+    // Makes the response already given for url take this long to arrive.
+    pub fn with_delay(mut self, url: &str, delay: Duration) -> FixtureFetcher {
+        self.responses
+            .get_mut(url)
+            .expect("with_delay needs a response for the URL first")
+            .delay = delay;
+        self
+    }
+
+    fn with_response(
+        mut self,
+        url: &str,
+        status: StatusCode,
+        body: String,
+        etag: Option<EntityTag>,
+    ) -> FixtureFetcher {
+        self.responses
+            .insert(
+                url.to_string(),
+                FixtureResponse {
+                    status,
+                    body,
+                    etag,
+                    delay: Duration::from_millis(0),
+                },
+            );
+        self
+    }
+
+    // Doesn't retry, so failing requests don't slow the tests down.
+    pub fn into_client(mut self, handle: &Handle) -> HttpClient {
+        self.handle = Some(handle.clone());
+        HttpClient::with_fetcher(
+            Rc::new(self),
+            RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            },
+            handle,
+        )
+    }
+}
+
+impl HttpFetcher for FixtureFetcher {
+    fn fetch(&self, _method: Method, url: Url, headers: Headers) -> ResponseFuture<HttpResponse> {
+        let fixture = match self.responses.get(url.as_str()) {
+            Some(fixture) => fixture,
+            None => {
+                return Box::new(future::err(NetworkError::ConnectionFailed {
+                    url: url.to_string(),
+                    message: "No fixture for this URL.".to_string(),
+                }))
+            }
+        };
+
+        let mut response = HttpResponse {
+            status: fixture.status,
+            headers: Headers::new(),
+            body: fixture.body.clone(),
+            latency: fixture.delay,
+        };
+        if let Some(ref etag) = fixture.etag {
+            response.headers.set(ETag(etag.clone()));
+            if let Some(IfNoneMatch::Items(etags)) = headers.get::<IfNoneMatch>() {
+                if etags.contains(etag) {
+                    response.status = StatusCode::NotModified;
+                    response.body = String::new();
+                }
+            }
+        }
+        if fixture.delay == Duration::from_millis(0) {
+            return Box::new(future::ok(response));
+        }
+        let handle = self.handle.as_ref().expect("into_client sets the handle");
+        Box::new(
+            Timeout::new(fixture.delay, handle)
+                .unwrap()
+                .then(move |_| Ok(response)),
+        )
+    }
+}
+
+// Reads tests/fixtures/<fixture>.
+fn read_fixture(fixture: &str) -> String {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/fixtures");
+    path.push(fixture);
+    let mut body = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut body))
+        .unwrap_or_else(|err| panic!("Unable to read fixture {:?}: {}", path, err));
+    body
+}
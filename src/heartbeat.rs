@@ -0,0 +1,87 @@
+use remote_status::RemoteStatus;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long a light gets to start up, or to finish a single poll, before it's considered stuck.
+// Generous, since a poll can be several requests, each with its own retries.
+pub const POLL_STALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Lets the main loop see whether a light is still making progress, and what it's currently showing.
+// Clones share the same state.
+#[derive(Clone)]
+pub struct Heartbeat {
+    state: Arc<Mutex<HeartbeatState>>,
+}
+
+struct HeartbeatState {
+    // The light is stalled if it hasn't beaten again by this time.
+    deadline: Instant,
+    status: Option<RemoteStatus>,
+    // Given up on after failing too often. Never counts as stalled, since it's not meant to be making progress.
+    disabled: bool,
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        Heartbeat {
+            state: Arc::new(Mutex::new(HeartbeatState {
+                deadline: Instant::now() + POLL_STALL_TIMEOUT,
+                status: None,
+                disabled: false,
+            })),
+        }
+    }
+
+    // Called by the light to say it's still going, and will beat again within the given time.
+    pub fn beat(&self, next_beat_within: Duration) {
+        self.lock().deadline = Instant::now() + next_beat_within;
+    }
+
+    pub fn set_status(&self, status: RemoteStatus) {
+        self.lock().status = Some(status);
+    }
+
+    pub fn set_disabled(&self) {
+        self.lock().disabled = true;
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.lock().disabled
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        let state = self.lock();
+        !state.disabled && Instant::now() > state.deadline
+    }
+
+    // None until the light's first poll finishes.
+    pub fn status(&self) -> Option<RemoteStatus> {
+        self.lock().status
+    }
+
+    // A light may have panicked while holding the lock. The state's still fine.
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, HeartbeatState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn missing_a_beat_is_a_stall() {
+        let heartbeat = Heartbeat::new();
+        assert!(!heartbeat.is_stalled());
+
+        heartbeat.beat(Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(30));
+        assert!(heartbeat.is_stalled());
+
+        heartbeat.clone().beat(Duration::from_secs(1));
+        assert!(!heartbeat.is_stalled());
+    }
+}
@@ -0,0 +1,621 @@
+use chrono::{self, DateTime, Local, TimeZone, Utc};
+use config_file::HistoryConfig;
+use durations;
+use failure::Error;
+use remote_status::RemoteStatus;
+use rusqlite::{Connection, OptionalExtension, NO_PARAMS};
+use status_report::StatusReport;
+use std::cmp;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Times are stored as seconds since the Unix epoch, statuses by name, e.g. "in_progress".
+// Transitions are what each light showed. Polled statuses are what its polls found, before debouncing and
+// flaky jobs are applied, and are what stats are worked out from.
+// Each migration is applied once, in order. The database's user_version is how many have been applied.
+const MIGRATIONS: &[&str] = &["
+CREATE TABLE IF NOT EXISTS transitions (
+    id INTEGER PRIMARY KEY,
+    integration TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    source TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transitions_by_time ON transitions (integration, time);
+
+CREATE TABLE IF NOT EXISTS job_results (
+    id INTEGER PRIMARY KEY,
+    integration TEXT NOT NULL,
+    job TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    build_number INTEGER,
+    duration_ms INTEGER,
+    detail TEXT,
+    revision TEXT
+);
+CREATE INDEX IF NOT EXISTS job_results_by_time ON job_results (integration, job, time);
+
+CREATE TABLE IF NOT EXISTS polled_statuses (
+    id INTEGER PRIMARY KEY,
+    integration TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS polled_statuses_by_time ON polled_statuses (integration, time);
+"];
+
+// Rows past the retention period are deleted at most this often.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Records every status change, and every new result of each job, build or endpoint, in an SQLite database.
+// Shared by every light. Failing to record something is logged, but never stops a light.
+// Writes are made on a thread of their own, in the order they're asked for, so a slow disk, or the database being
+// locked by the history subcommand, never holds up the event loop.
+pub struct History {
+    // None if history is turned off, or the database couldn't be opened.
+    reader: Option<Mutex<Connection>>,
+    writer: Option<Mutex<Sender<Write>>>,
+    // How many times new job results have been recorded, for any integration.
+    results_recorded: Arc<AtomicUsize>,
+}
+
+// Owned by the writer thread.
+struct Database {
+    connection: Connection,
+    retention: Option<Duration>,
+    last_pruned: Instant,
+}
+
+// Each write is made in a transaction of its own. Times are when it was asked for.
+enum Write {
+    Transition {
+        integration: String,
+        time: i64,
+        from: Option<RemoteStatus>,
+        to: RemoteStatus,
+        source: String,
+    },
+    PolledStatus {
+        integration: String,
+        time: i64,
+        status: RemoteStatus,
+    },
+    Results {
+        integration: String,
+        time: i64,
+        results: Vec<NewResult>,
+    },
+    // Answered once every write asked for before it has been made.
+    Flush(Sender<()>),
+}
+
+// A job's result from a poll, which is only recorded if its status or build has changed.
+struct NewResult {
+    job: String,
+    status: RemoteStatus,
+    build_number: Option<i64>,
+    duration_ms: Option<i64>,
+    detail: Option<String>,
+    revision: Option<String>,
+}
+
+pub struct Transition {
+    pub time: DateTime<Utc>,
+    pub from: Option<RemoteStatus>,
+    pub to: RemoteStatus,
+    // Where the new status came from, i.e. "poll" or "webhook".
+    pub source: String,
+}
+
+pub struct JobResult {
+    pub job: String,
+    // Seconds since the Unix epoch.
+    pub time: i64,
+    pub status: RemoteStatus,
+    pub revision: Option<String>,
+}
+
+impl History {
+    pub fn from_config(config: &HistoryConfig) -> History {
+        if config.file.is_empty() {
+            return History::turned_off();
+        }
+        History::open(Path::new(&config.file), config.retention_days).unwrap_or_else(|err| {
+            warn!("History won't be recorded. {}", err);
+            History::turned_off()
+        })
+    }
+
+    fn turned_off() -> History {
+        History {
+            reader: None,
+            writer: None,
+            results_recorded: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn open(path: &Path, retention_days: u32) -> Result<History, Error> {
+        let connect = || {
+            Connection::open(path)
+                .and_then(|connection| {
+                    // The history subcommand may be reading while a light's writing.
+                    connection.busy_timeout(Duration::from_secs(5))?;
+                    migrate(&connection)?;
+                    Ok(connection)
+                })
+                .map_err(|err| format_err!("Unable to open {}: {}", path.display(), err))
+        };
+        let connection = connect()?;
+        let reader = connect()?;
+        let mut database = Database {
+            connection,
+            retention: match retention_days {
+                0 => None,
+                days => Some(Duration::from_secs(u64::from(days) * 24 * 60 * 60)),
+            },
+            last_pruned: Instant::now(),
+        };
+        database.prune()?;
+
+        let (writer, writes) = mpsc::channel();
+        let results_recorded = Arc::new(AtomicUsize::new(0));
+        let recorded = Arc::clone(&results_recorded);
+        thread::Builder::new()
+            .name("history".to_string())
+            .spawn(move || write_history(database, &writes, &recorded))
+            .map_err(|err| format_err!("Unable to start writing history: {}", err))?;
+        Ok(History {
+            reader: Some(Mutex::new(reader)),
+            writer: Some(Mutex::new(writer)),
+            results_recorded,
+        })
+    }
+
+    pub fn record_transition(
+        &self,
+        integration: &str,
+        from: Option<RemoteStatus>,
+        to: RemoteStatus,
+        source: &str,
+    ) {
+        self.write(Write::Transition {
+            integration: integration.to_string(),
+            time: Utc::now().timestamp(),
+            from,
+            to,
+            source: source.to_string(),
+        });
+    }
+
+    // Only recorded if the status has changed since the last poll.
+    pub fn record_polled_status(&self, integration: &str, status: RemoteStatus) {
+        self.write(Write::PolledStatus {
+            integration: integration.to_string(),
+            time: Utc::now().timestamp(),
+            status,
+        });
+    }
+
+    // Only records entries whose status or build has changed since they were last recorded, so polling the
+    // same finished build over and over only records it once. Entries without a status of their own, e.g.
+    // jobs that couldn't be fetched, aren't recorded. See results_recorded for when anything new was.
+    pub fn record_results(&self, integration: &str, report: &StatusReport) {
+        let results = report
+            .entries
+            .iter()
+            .filter(|x| x.status != RemoteStatus::Unknown || x.build.is_some())
+            .map(|entry| NewResult {
+                job: entry.name.clone(),
+                status: entry.status,
+                build_number: entry.build.as_ref().map(|x| x.number as i64),
+                duration_ms: entry
+                    .build
+                    .as_ref()
+                    .and_then(|x| x.duration)
+                    .map(|x| durations::millis(x) as i64),
+                detail: entry.detail.clone(),
+                revision: entry.build.as_ref().and_then(|x| x.revision.clone()),
+            })
+            .collect();
+        self.write(Write::Results {
+            integration: integration.to_string(),
+            time: Utc::now().timestamp(),
+            results,
+        });
+    }
+
+    // Goes up each time new job results are recorded, for any integration, so that what's worked out from them
+    // can be worked out again.
+    pub fn results_recorded(&self) -> usize {
+        self.results_recorded.load(Ordering::SeqCst)
+    }
+
+    // Waits for every write asked for so far to be made, e.g. before exiting.
+    pub fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        self.write(Write::Flush(done));
+        // The writer thread's gone if history is turned off, or it panicked.
+        let _ = flushed.recv();
+    }
+
+    // The integrations that have anything recorded, in alphabetical order.
+    pub fn integrations(&self) -> Result<Vec<String>, Error> {
+        self.read(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT integration FROM transitions UNION SELECT integration FROM polled_statuses
+                 UNION SELECT integration FROM job_results ORDER BY integration",
+            )?;
+            let integrations = statement
+                .query_map(NO_PARAMS, |row| row.get(0))?
+                .collect();
+            integrations
+        })
+    }
+
+    // The integration's most recent transitions, newest first.
+    pub fn transitions(&self, integration: &str, count: u32) -> Result<Vec<Transition>, Error> {
+        self.read(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT time, from_status, to_status, source FROM transitions WHERE integration = ?
+                 ORDER BY time DESC, id DESC LIMIT ?",
+            )?;
+            let transitions = statement
+                .query_map(params![integration, i64::from(count)], |row| {
+                    let from: Option<String> = row.get(1)?;
+                    let to: String = row.get(2)?;
+                    Ok(Transition {
+                        time: Utc.timestamp(row.get(0)?, 0),
+                        from: from.and_then(|x| RemoteStatus::from_name(&x)),
+                        to: RemoteStatus::from_name(&to).unwrap_or(RemoteStatus::Unknown),
+                        source: row.get(3)?,
+                    })
+                })?
+                .collect();
+            transitions
+        })
+    }
+
+    // Every status the integration's polls have found, and when, oldest first. Unlike its transitions, these
+    // are from before debouncing and flaky jobs.
+    pub fn polled_statuses(&self, integration: &str) -> Result<Vec<(i64, RemoteStatus)>, Error> {
+        self.read(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT time, status FROM polled_statuses WHERE integration = ? ORDER BY time, id",
+            )?;
+            let statuses = statement
+                .query_map(params![integration], |row| {
+                    let status: String = row.get(1)?;
+                    Ok((
+                        row.get(0)?,
+                        RemoteStatus::from_name(&status).unwrap_or(RemoteStatus::Unknown),
+                    ))
+                })?
+                .collect();
+            statuses
+        })
+    }
+
+    // Every result recorded for the integration's jobs, grouped by job, oldest first.
+    pub fn job_results(&self, integration: &str) -> Result<Vec<JobResult>, Error> {
+        self.read(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT job, time, status, revision FROM job_results WHERE integration = ?
+                 ORDER BY job, time, id",
+            )?;
+            let results = statement
+                .query_map(params![integration], |row| {
+                    let status: String = row.get(2)?;
+                    Ok(JobResult {
+                        job: row.get(0)?,
+                        time: row.get(1)?,
+                        status: RemoteStatus::from_name(&status).unwrap_or(RemoteStatus::Unknown),
+                        revision: row.get(3)?,
+                    })
+                })?
+                .collect();
+            results
+        })
+    }
+
+    // For the history subcommand. Prints the most recent transitions of the given light, or of every light,
+    // newest first, along with how long each status lasted.
+    pub fn print_transitions(&self, light: Option<&str>, count: u32) -> Result<(), Error> {
+        let integrations = match light {
+            Some(light) => vec![light.to_string()],
+            None => self.integrations()?,
+        };
+        if integrations.is_empty() {
+            println!("No status changes recorded yet.");
+        }
+        for integration in integrations {
+            println!("{}:", integration);
+            let transitions = self.transitions(&integration, count)?;
+            if transitions.is_empty() {
+                println!("    No status changes recorded.");
+            }
+            // The newest status is still going.
+            let mut until = None;
+            for transition in transitions {
+                let lasted = until
+                    .unwrap_or_else(Utc::now)
+                    .signed_duration_since(transition.time);
+                println!(
+                    "    {}  {} -> {} ({}), {} {}",
+                    transition.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+                    transition.from.map_or("none", RemoteStatus::name),
+                    transition.to.name(),
+                    transition.source,
+                    if until.is_some() { "lasted" } else { "for" },
+                    format_duration(lasted)
+                );
+                until = Some(transition.time);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, write: Write) {
+        if let Some(ref writer) = self.writer {
+            // Only fails if the writer thread's panicked, which has already been logged.
+            let _ = lock(writer).send(write);
+        }
+    }
+
+    fn read<T, F>(&self, func: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<T>,
+    {
+        match self.reader {
+            Some(ref reader) => {
+                func(&lock(reader)).map_err(|err| format_err!("Unable to read history: {}", err))
+            }
+            None => Err(format_err!("History isn't being recorded.")),
+        }
+    }
+}
+
+// Runs on the writer thread until the History's dropped.
+fn write_history(mut database: Database, writes: &Receiver<Write>, results_recorded: &AtomicUsize) {
+    for write in writes.iter() {
+        let integration = match write {
+            Write::Transition { ref integration, .. }
+            | Write::PolledStatus { ref integration, .. }
+            | Write::Results { ref integration, .. } => integration.clone(),
+            Write::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        match database.write(&write) {
+            Ok(true) => {
+                results_recorded.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Unable to record history for {}. Error: {}", integration, e),
+        }
+        if database.last_pruned.elapsed() >= PRUNE_INTERVAL {
+            if let Err(e) = database.prune() {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+impl Database {
+    // Returns whether any new job results were recorded.
+    fn write(&mut self, write: &Write) -> rusqlite::Result<bool> {
+        let transaction = self.connection.transaction()?;
+        let mut recorded = false;
+        match *write {
+            Write::Transition {
+                ref integration,
+                time,
+                from,
+                to,
+                ref source,
+            } => {
+                transaction.execute(
+                    "INSERT INTO transitions (integration, time, from_status, to_status, source)
+                     VALUES (?, ?, ?, ?, ?)",
+                    params![integration, time, from.map(RemoteStatus::name), to.name(), source],
+                )?;
+            }
+            Write::PolledStatus {
+                ref integration,
+                time,
+                status,
+            } => {
+                let last: Option<String> = transaction
+                    .query_row(
+                        "SELECT status FROM polled_statuses WHERE integration = ? ORDER BY time DESC, id DESC LIMIT 1",
+                        params![integration],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if last.as_ref().map(String::as_str) != Some(status.name()) {
+                    transaction.execute(
+                        "INSERT INTO polled_statuses (integration, time, status) VALUES (?, ?, ?)",
+                        params![integration, time, status.name()],
+                    )?;
+                }
+            }
+            Write::Results {
+                ref integration,
+                time,
+                ref results,
+            } => {
+                for result in results {
+                    let last: Option<(String, Option<i64>)> = transaction
+                        .query_row(
+                            "SELECT status, build_number FROM job_results WHERE integration = ? AND job = ?
+                             ORDER BY time DESC, id DESC LIMIT 1",
+                            params![integration, result.job],
+                            |row| Ok((row.get(0)?, row.get(1)?)),
+                        )
+                        .optional()?;
+                    if last == Some((result.status.name().to_string(), result.build_number)) {
+                        continue;
+                    }
+                    transaction.execute(
+                        "INSERT INTO job_results
+                         (integration, job, time, status, build_number, duration_ms, detail, revision)
+                         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                        params![
+                            integration,
+                            result.job,
+                            time,
+                            result.status.name(),
+                            result.build_number,
+                            result.duration_ms,
+                            result.detail,
+                            result.revision,
+                        ],
+                    )?;
+                    recorded = true;
+                }
+            }
+            Write::Flush(_) => {}
+        }
+        transaction.commit()?;
+        Ok(recorded)
+    }
+
+    fn prune(&mut self) -> Result<(), Error> {
+        self.last_pruned = Instant::now();
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+        let cutoff = Utc::now().timestamp() - retention.as_secs() as i64;
+        self.connection
+            .execute("DELETE FROM transitions WHERE time < ?", [cutoff])
+            .and_then(|_| {
+                self.connection
+                    .execute("DELETE FROM polled_statuses WHERE time < ?", [cutoff])
+            })
+            .and_then(|_| {
+                self.connection
+                    .execute("DELETE FROM job_results WHERE time < ?", [cutoff])
+            })
+            .map_err(|err| format_err!("Unable to delete old history: {}", err))?;
+        Ok(())
+    }
+}
+
+fn migrate(connection: &Connection) -> rusqlite::Result<()> {
+    let version: i64 = connection.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        connection.execute_batch(migration)?;
+        connection.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+    }
+    Ok(())
+}
+
+// E.g. "2d 3h", "3h 12m", "12m 5s" or "5s".
+pub fn format_duration(duration: chrono::Duration) -> String {
+    let seconds = cmp::max(duration.num_seconds(), 0);
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+// A light may have panicked while holding the lock. What it guards is still fine.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status_report::{BuildInfo, StatusEntry};
+    use std::env;
+    use std::fs;
+
+    fn entry(status: RemoteStatus, number: u64) -> StatusEntry {
+        StatusEntry {
+            name: "web-client".to_string(),
+            status,
+            latency: None,
+            detail: None,
+            build: Some(BuildInfo {
+                number,
+                duration: Some(Duration::from_secs(60)),
+                revision: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn transitions_are_listed_newest_first_and_unchanged_results_recorded_once() {
+        let path = env::temp_dir().join("rusty_build_light_test_history.db");
+        let _ = fs::remove_file(&path);
+        let history = History::open(&path, 90).unwrap();
+
+        history.record_transition("jenkins", None, RemoteStatus::Passing, "poll");
+        history.record_transition("jenkins", Some(RemoteStatus::Passing), RemoteStatus::Failing, "webhook");
+        history.record_transition("unity", None, RemoteStatus::Unknown, "poll");
+        for report in &[
+            StatusReport::with_entries(RemoteStatus::Passing, vec![entry(RemoteStatus::Passing, 1)]),
+            StatusReport::with_entries(RemoteStatus::Passing, vec![entry(RemoteStatus::Passing, 1)]),
+            StatusReport::with_entries(RemoteStatus::Failing, vec![entry(RemoteStatus::Failing, 2)]),
+        ] {
+            history.record_results("jenkins", report);
+        }
+        history.flush();
+        assert_eq!(history.results_recorded(), 2);
+
+        assert_eq!(history.integrations().unwrap(), vec!["jenkins", "unity"]);
+        let transitions = history.transitions("jenkins", 10).unwrap();
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions[0].from, Some(RemoteStatus::Passing));
+        assert_eq!(transitions[0].to, RemoteStatus::Failing);
+        assert_eq!(transitions[0].source, "webhook");
+        assert_eq!(history.transitions("jenkins", 1).unwrap().len(), 1);
+        let result_count: i64 = history
+            .read(|connection| {
+                connection.query_row("SELECT COUNT(*) FROM job_results", NO_PARAMS, |row| row.get(0))
+            })
+            .unwrap();
+        assert_eq!(result_count, 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn polled_statuses_are_kept_apart_from_what_the_light_showed() {
+        let path = env::temp_dir().join("rusty_build_light_test_polled_statuses.db");
+        let _ = fs::remove_file(&path);
+        let history = History::open(&path, 90).unwrap();
+
+        // A failure that debouncing kept off the light.
+        history.record_transition("jenkins", None, RemoteStatus::Passing, "poll");
+        for &status in &[RemoteStatus::Passing, RemoteStatus::Failing, RemoteStatus::Failing, RemoteStatus::Passing] {
+            history.record_polled_status("jenkins", status);
+        }
+        history.flush();
+
+        let statuses: Vec<RemoteStatus> = history
+            .polled_statuses("jenkins")
+            .unwrap()
+            .into_iter()
+            .map(|(_, status)| status)
+            .collect();
+        assert_eq!(statuses, vec![RemoteStatus::Passing, RemoteStatus::Failing, RemoteStatus::Passing]);
+        assert_eq!(history.transitions("jenkins", 10).unwrap().len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use failure::Error;
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Where things are installed, relative to the root.
+const UNIT_PATH: &str = "lib/systemd/system/build-light.service";
+const CONFIG_DIRECTORY: &str = "etc/rusty-build-light";
+// The service's working directory, where relative paths in config.toml (e.g. log files) end up.
+// systemd creates it, owned by the service's user, as its StateDirectory.
+const STATE_DIRECTORY_NAME: &str = "rusty-build-light";
+const STATE_DIRECTORY: &str = "var/lib/rusty-build-light";
+
+// The example config, comments and all.
+const CONFIG_SKELETON: &str = include_str!("../config/config.toml");
+
+#[derive(Debug, PartialEq)]
+pub struct InstallOptions {
+    // Prefixed to every path that's written or removed. "/" for a real install.
+    pub root: PathBuf,
+    // The user the service runs as. Needs access to the GPIO pins.
+    pub user: String,
+    // Uninstall only: also remove config.toml and the working directory.
+    pub purge: bool,
+}
+
+// Writes the systemd unit, and a config.toml to fill in if there isn't one yet. An existing config.toml is left alone.
+pub fn install(options: &InstallOptions) -> Result<(), Error> {
+    let executable = env::current_exe()
+        .and_then(|x| x.canonicalize())
+        .map_err(|err| format_err!("Unable to find the path to this executable: {}", err))?;
+
+    let config_directory = options.root.join(CONFIG_DIRECTORY);
+    let config_path = config_directory.join("config.toml");
+    create_directory(&config_directory)?;
+    if config_path.exists() {
+        println!("Keeping existing {}", config_path.display());
+    } else {
+        // Secrets usually end up in here, so it's only readable by its owner.
+        write_file(&config_path, CONFIG_SKELETON, 0o600)?;
+        println!("Wrote {}", config_path.display());
+        if options.user != "root" {
+            // The user might not exist where a package is being built, so only a real install changes the owner.
+            if options.root == Path::new("/") {
+                change_owner(&config_path, &options.user)?;
+            } else {
+                println!(
+                    "    It's only readable by root. Make {} its owner, so the service can read it.",
+                    options.user
+                );
+            }
+        }
+    }
+
+    let unit_path = options.root.join(UNIT_PATH);
+    create_directory(unit_path.parent().unwrap())?;
+    write_file(&unit_path, &unit_file(&executable, &options.user), 0o644)?;
+    println!("Wrote {}", unit_path.display());
+
+    println!();
+    println!("Fill in {}, check it with:", config_path.display());
+    println!(
+        "    {} check-config --config /{}/config.toml",
+        executable.display(),
+        CONFIG_DIRECTORY
+    );
+    println!("then start the build light with:");
+    println!("    systemctl daemon-reload");
+    println!("    systemctl enable --now build-light.service");
+    Ok(())
+}
+
+// Removes the systemd unit. config.toml and the working directory are only removed when purging.
+pub fn uninstall(options: &InstallOptions) -> Result<(), Error> {
+    remove(&options.root.join(UNIT_PATH), false)?;
+    if options.purge {
+        remove(&options.root.join(CONFIG_DIRECTORY), true)?;
+        remove(&options.root.join(STATE_DIRECTORY), true)?;
+    } else {
+        println!(
+            "Keeping {}. Use --purge to remove it too.",
+            options.root.join(CONFIG_DIRECTORY).display()
+        );
+    }
+
+    println!();
+    println!("If the build light was running, stop it with:");
+    println!("    systemctl disable --now build-light.service");
+    println!("then run `systemctl daemon-reload`.");
+    Ok(())
+}
+
+// Paths in the unit are where things will be once installed, so they never include the root.
+fn unit_file(executable: &Path, user: &str) -> String {
+    format!(
+        "[Unit]
+Description=Rusty build light: shows the status of CI servers and other services on RGB LEDs.
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={executable} run --config /{config_directory}/config.toml
+ExecReload=/bin/kill -HUP $MAINPID
+StateDirectory={state_directory_name}
+WorkingDirectory=/{state_directory}
+User={user}
+WatchdogSec=60
+Restart=always
+RestartSec=3
+
+[Install]
+WantedBy=multi-user.target
+",
+        executable = executable.display(),
+        config_directory = CONFIG_DIRECTORY,
+        state_directory_name = STATE_DIRECTORY_NAME,
+        state_directory = STATE_DIRECTORY,
+        user = user
+    )
+}
+
+fn create_directory(path: &Path) -> Result<(), Error> {
+    fs::create_dir_all(path)
+        .map_err(|err| format_err!("Unable to create {}: {}", path.display(), err))
+}
+
+fn change_owner(path: &Path, user: &str) -> Result<(), Error> {
+    let status = Command::new("chown")
+        .arg(user)
+        .arg(path)
+        .status()
+        .map_err(|err| format_err!("Unable to run chown: {}", err))?;
+    if !status.success() {
+        return Err(format_err!(
+            "Unable to make {} the owner of {}",
+            user,
+            path.display()
+        ));
+    }
+    println!("Made {} the owner of {}", user, path.display());
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<(), Error> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|err| format_err!("Unable to write {}: {}", path.display(), err))
+}
+
+fn remove(path: &Path, is_directory: bool) -> Result<(), Error> {
+    if !path.exists() {
+        println!("{} is already gone", path.display());
+        return Ok(());
+    }
+    let result = if is_directory {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    result.map_err(|err| format_err!("Unable to remove {}: {}", path.display(), err))?;
+    println!("Removed {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn install_keeps_existing_config_and_uninstall_only_purges_when_asked() {
+        let root = env::temp_dir().join("rusty_build_light_test_install");
+        let _ = fs::remove_dir_all(&root);
+        let mut options = InstallOptions {
+            root: root.clone(),
+            user: "pi".to_string(),
+            purge: false,
+        };
+
+        install(&options).unwrap();
+        let unit = fs::read_to_string(root.join(UNIT_PATH)).unwrap();
+        assert!(unit.contains("User=pi\n"));
+        assert!(unit.contains(" run --config /etc/rusty-build-light/config.toml\n"));
+        let config_path = root.join(CONFIG_DIRECTORY).join("config.toml");
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), CONFIG_SKELETON);
+
+        fs::write(&config_path, "allowed_failures = 1").unwrap();
+        install(&options).unwrap();
+        assert_eq!(
+            fs::read_to_string(&config_path).unwrap(),
+            "allowed_failures = 1"
+        );
+
+        uninstall(&options).unwrap();
+        assert!(!root.join(UNIT_PATH).exists());
+        assert!(config_path.exists());
+
+        options.purge = true;
+        uninstall(&options).unwrap();
+        assert!(!config_path.exists());
+        assert!(!root.join(STATE_DIRECTORY).exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn service_user_owns_the_working_directory() {
+        let unit = unit_file(Path::new("/opt/rusty_build_light"), "pi");
+
+        // systemd creates the StateDirectory owned by User=, so the service can write state.json and history.db.
+        assert!(unit.contains("\nUser=pi\n"));
+        assert!(unit.contains("\nStateDirectory=rusty-build-light\n"));
+        assert!(unit.contains("\nWorkingDirectory=/var/lib/rusty-build-light\n"));
+    }
+}
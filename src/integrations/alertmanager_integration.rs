@@ -0,0 +1,290 @@
+use errors::NetworkError;
+use futures::{future, Future};
+use integrations::alertmanager_response::*;
+use integrations::remote_integration::ReportFuture;
+use network::{HttpClient, ResponseFuture};
+use remote_status::RemoteStatus;
+use reqwest::header::Headers;
+use reqwest::Url;
+use scheduler::PollSchedule;
+use status_report::{StatusEntry, StatusReport};
+use RemoteIntegration;
+
+const CRITICAL_SEVERITY: &str = "critical";
+const WARNING_SEVERITY: &str = "warning";
+
+pub struct AlertmanagerIntegration {
+    r: u16,
+    g: u16,
+    b: u16,
+    base_url: String,
+    matchers: Vec<String>,
+    severity_label: String,
+    http_client: HttpClient,
+}
+
+impl AlertmanagerIntegration {
+    pub fn new(
+        r: u16,
+        g: u16,
+        b: u16,
+        base_url: &str,
+        matchers: &[String],
+        severity_label: &str,
+        http_client: HttpClient,
+    ) -> AlertmanagerIntegration {
+        AlertmanagerIntegration {
+            r,
+            g,
+            b,
+            base_url: base_url.to_string(),
+            matchers: matchers.to_vec(),
+            severity_label: severity_label.to_string(),
+            http_client,
+        }
+    }
+
+    fn get_status_internal(&self) -> ResponseFuture<Vec<AlertmanagerAlert>> {
+        // Silenced and inhibited alerts are someone else's problem, so don't ask for them at all.
+        let alerts_url = format!("{base}/api/v2/alerts", base = self.base_url);
+        let mut url = match Url::parse(&alerts_url) {
+            Ok(url) => url,
+            Err(_) => return Box::new(future::err(NetworkError::InvalidUrl { url: alerts_url })),
+        };
+        url.query_pairs_mut()
+            .append_pair("active", "true")
+            .append_pair("silenced", "false")
+            .append_pair("inhibited", "false");
+        for matcher in &self.matchers {
+            url.query_pairs_mut().append_pair("filter", matcher);
+        }
+
+        let http_client = self.http_client.clone();
+        Box::new(
+            self.http_client
+                .get_url_response_with_retry(url.as_str(), Headers::new())
+                .map(move |(alerts, _): (Vec<AlertmanagerAlert>, Headers)| {
+                    let cache_stats = http_client.cache_stats();
+                    debug!(
+                        target: "alertmanager",
+                        "{} unchanged responses served from cache, {} downloaded so far.",
+                        cache_stats.hits, cache_stats.misses
+                    );
+                    alerts
+                        .into_iter()
+                        .filter(|alert| alert.status.state == AlertmanagerAlertState::Active)
+                        .collect()
+                }),
+        )
+    }
+}
+
+impl RemoteIntegration for AlertmanagerIntegration {
+    const DEFAULT_SCHEDULE: PollSchedule = PollSchedule::from_secs(10, 0, 0);
+
+    fn get_red_id(&self) -> u16 {
+        self.r
+    }
+    fn get_green_id(&self) -> u16 {
+        self.g
+    }
+    fn get_blue_id(&self) -> u16 {
+        self.b
+    }
+
+    fn get_report(&mut self) -> ReportFuture {
+        let severity_label = self.severity_label.clone();
+        Box::new(
+            self.get_status_internal()
+                .then(move |alerts| Ok(report(&severity_label, alerts))),
+        )
+    }
+}
+
+fn report(severity_label: &str, alerts: Result<Vec<AlertmanagerAlert>, NetworkError>) -> StatusReport {
+    let alerts = match alerts {
+        Ok(alerts) => alerts,
+        Err(e) => {
+            warn!(
+                target: "alertmanager",
+                "Failed to retrieve alerts from Alertmanager. Details: {}",
+                e
+            );
+            return StatusReport::failed(e.kind(), e.to_string());
+        }
+    };
+
+    let entries: Vec<StatusEntry> = alerts
+        .iter()
+        .map(|alert| {
+            let severity = alert
+                .labels
+                .get(severity_label)
+                .map_or("", |x| x.as_str());
+            let status = match severity {
+                CRITICAL_SEVERITY => RemoteStatus::Failing,
+                WARNING_SEVERITY => RemoteStatus::Warning,
+                // Informational alerts and the like don't affect the light.
+                _ => RemoteStatus::Passing,
+            };
+            StatusEntry {
+                name: alert
+                    .labels
+                    .get("alertname")
+                    .cloned()
+                    .unwrap_or_else(|| "(unnamed alert)".to_string()),
+                status,
+                latency: None,
+                detail: Some(format!("{}={}", severity_label, severity)),
+                build: None,
+            }
+        })
+        .collect();
+
+    let critical_count = entries
+        .iter()
+        .filter(|x| x.status == RemoteStatus::Failing)
+        .count();
+    let warning_count = entries
+        .iter()
+        .filter(|x| x.status == RemoteStatus::Warning)
+        .count();
+
+    info!(
+        target: "alertmanager",
+        "{} matching alerts firing. {} critical, {} warnings.",
+        entries.len(),
+        critical_count,
+        warning_count
+    );
+
+    let status = if critical_count > 0 {
+        RemoteStatus::Failing
+    } else if warning_count > 0 {
+        RemoteStatus::Warning
+    } else {
+        RemoteStatus::Passing
+    };
+
+    StatusReport::with_entries(status, entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture_fetcher::FixtureFetcher;
+    use tokio_core::reactor::Core;
+
+    const BASE_URL: &str = "http://alertmanager.example.com";
+    const ALERTS_URL: &str = "http://alertmanager.example.com/api/v2/alerts?active=true&silenced=false&inhibited=false";
+
+    fn get_report_for(
+        fetcher: FixtureFetcher,
+        matchers: &[String],
+        severity_label: &str,
+    ) -> StatusReport {
+        let mut core = Core::new().unwrap();
+        let mut integration = AlertmanagerIntegration::new(
+            0,
+            1,
+            2,
+            BASE_URL,
+            matchers,
+            severity_label,
+            fetcher.into_client(&core.handle()),
+        );
+        core.run(integration.get_report()).unwrap()
+    }
+
+    fn get_report(fixture: &str) -> StatusReport {
+        let fetcher = FixtureFetcher::new().with_fixture(ALERTS_URL, fixture);
+        get_report_for(fetcher, &[], "severity")
+    }
+
+    fn entry_status(report: &StatusReport, alert_name: &str) -> RemoteStatus {
+        report
+            .entries
+            .iter()
+            .find(|x| x.name == alert_name)
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn no_alerts_is_passing() {
+        let report = get_report("alertmanager/alerts_none.json");
+
+        assert_eq!(report.status, RemoteStatus::Passing);
+        assert!(report.entries.is_empty());
+        assert!(report.error.is_none());
+    }
+
+    #[test]
+    fn warning_alert_is_a_warning() {
+        let report = get_report("alertmanager/alerts_warning.json");
+
+        // DiskFull is critical, but silenced.
+        assert_eq!(report.status, RemoteStatus::Warning);
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(entry_status(&report, "HighMemoryUsage"), RemoteStatus::Warning);
+        assert_eq!(entry_status(&report, "NodeRebooted"), RemoteStatus::Passing);
+    }
+
+    #[test]
+    fn critical_alert_is_failing() {
+        let report = get_report("alertmanager/alerts_critical.json");
+
+        assert_eq!(report.status, RemoteStatus::Failing);
+        assert_eq!(entry_status(&report, "InstanceDown"), RemoteStatus::Failing);
+        assert_eq!(entry_status(&report, "HighMemoryUsage"), RemoteStatus::Warning);
+        // No severity at all.
+        assert_eq!(entry_status(&report, "Watchdog"), RemoteStatus::Passing);
+    }
+
+    #[test]
+    fn severity_comes_from_the_configured_label() {
+        let fixture = "alertmanager/alerts_priority_label.json";
+
+        let by_priority = get_report_for(
+            FixtureFetcher::new().with_fixture(ALERTS_URL, fixture),
+            &[],
+            "priority",
+        );
+        let by_severity = get_report_for(
+            FixtureFetcher::new().with_fixture(ALERTS_URL, fixture),
+            &[],
+            "severity",
+        );
+
+        assert_eq!(by_priority.status, RemoteStatus::Failing);
+        assert_eq!(by_priority.entries[0].detail, Some("priority=critical".to_string()));
+        assert_eq!(by_severity.status, RemoteStatus::Warning);
+    }
+
+    #[test]
+    fn matchers_are_sent_as_filters() {
+        let url = format!(
+            "{}&filter=team%3D%22platform%22&filter=env%3D%7E%22prod.*%22",
+            ALERTS_URL
+        );
+        let fetcher = FixtureFetcher::new().with_fixture(&url, "alertmanager/alerts_critical.json");
+        let matchers = vec![
+            r#"team="platform""#.to_string(),
+            r#"env=~"prod.*""#.to_string(),
+        ];
+
+        let report = get_report_for(fetcher, &matchers, "severity");
+
+        // The fetcher only answers the exact URL, so anything else would fail to connect.
+        assert!(report.error.is_none());
+        assert_eq!(report.status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn unreachable_alertmanager_is_an_error() {
+        let report = get_report_for(FixtureFetcher::new(), &[], "severity");
+
+        assert_eq!(report.status, RemoteStatus::Unknown);
+        assert_eq!(report.error_kind, Some("connection_failed"));
+    }
+}
@@ -0,0 +1,21 @@
+use std::collections::HashMap;
+
+// A single alert, as returned by Alertmanager's /api/v2/alerts endpoint.
+#[derive(Deserialize)]
+pub struct AlertmanagerAlert {
+    pub labels: HashMap<String, String>,
+    pub status: AlertmanagerAlertStatus,
+}
+
+#[derive(Deserialize)]
+pub struct AlertmanagerAlertStatus {
+    pub state: AlertmanagerAlertState,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertmanagerAlertState {
+    Unprocessed,
+    Active,
+    Suppressed,
+}
@@ -0,0 +1,374 @@
+use config_file::HealthCheckEndpoint;
+use durations;
+use failure::Error;
+use futures::{future, Future};
+use integrations::remote_integration::ReportFuture;
+use network::{HttpClient, HttpResponse};
+use regex::Regex;
+use remote_status::RemoteStatus;
+use reqwest::Method;
+use scheduler::PollSchedule;
+use status_report::{StatusEntry, StatusReport};
+use std::str::FromStr;
+use std::time::Duration;
+use RemoteIntegration;
+
+// A single endpoint to probe, with its config already parsed and validated.
+#[derive(Clone)]
+pub struct HealthCheck {
+    url: String,
+    method: Method,
+    expected_status_codes: Vec<u16>,
+    body_contains: Option<String>,
+    body_regex: Option<Regex>,
+    max_latency: Option<Duration>,
+}
+
+impl HealthCheck {
+    pub fn from_config(endpoint: &HealthCheckEndpoint) -> Result<HealthCheck, Error> {
+        let method = Method::from_str(&endpoint.method.to_uppercase()).map_err(|err| {
+            format_err!(
+                "Invalid HTTP method {} for health check {}: {}",
+                endpoint.method,
+                endpoint.url,
+                err
+            )
+        })?;
+        let body_regex = match endpoint.body_regex {
+            Some(ref pattern) => Some(Regex::new(pattern).map_err(|err| {
+                format_err!(
+                    "Invalid body_regex for health check {}: {}",
+                    endpoint.url,
+                    err
+                )
+            })?),
+            None => None,
+        };
+        Ok(HealthCheck {
+            url: endpoint.url.clone(),
+            method,
+            expected_status_codes: endpoint.expected_status_codes.clone(),
+            body_contains: endpoint.body_contains.clone(),
+            body_regex,
+            max_latency: endpoint.max_latency_ms.map(Duration::from_millis),
+        })
+    }
+}
+
+pub struct HttpHealthIntegration {
+    r: u16,
+    g: u16,
+    b: u16,
+    health_checks: Vec<HealthCheck>,
+    http_client: HttpClient,
+}
+
+impl HttpHealthIntegration {
+    pub fn new(
+        r: u16,
+        g: u16,
+        b: u16,
+        health_checks: Vec<HealthCheck>,
+        http_client: HttpClient,
+    ) -> HttpHealthIntegration {
+        HttpHealthIntegration {
+            r,
+            g,
+            b,
+            health_checks,
+            http_client,
+        }
+    }
+}
+
+// Healthy endpoints are Passing, slow ones are Warning, and anything else is Failing.
+fn probe(
+    http_client: &HttpClient,
+    health_check: HealthCheck,
+) -> Box<dyn Future<Item = StatusEntry, Error = ()>> {
+    Box::new(
+        http_client
+            .probe_url(health_check.method.clone(), &health_check.url)
+            .then(move |response| match response {
+                Ok(response) => Ok(check_response(&health_check, &response)),
+                Err(e) => Ok(StatusEntry {
+                    name: health_check.url.clone(),
+                    status: RemoteStatus::Failing,
+                    latency: None,
+                    detail: Some(format!("Request failed: {}", e)),
+                    build: None,
+                }),
+            }),
+    )
+}
+
+fn check_response(health_check: &HealthCheck, response: &HttpResponse) -> StatusEntry {
+    let mut problems = Vec::new();
+    let status_code = u16::from(response.status);
+    if !health_check.expected_status_codes.contains(&status_code) {
+        problems.push(format!("unexpected status code {}", status_code));
+    }
+    if let Some(ref expected) = health_check.body_contains {
+        if !response.body.contains(expected.as_str()) {
+            problems.push(format!("body does not contain \"{}\"", expected));
+        }
+    }
+    if let Some(ref regex) = health_check.body_regex {
+        if !regex.is_match(&response.body) {
+            problems.push(format!("body does not match /{}/", regex.as_str()));
+        }
+    }
+
+    let too_slow = health_check
+        .max_latency
+        .map_or(false, |max_latency| response.latency > max_latency);
+
+    let (status, detail) = if !problems.is_empty() {
+        (RemoteStatus::Failing, Some(problems.join(", ")))
+    } else if too_slow {
+        (
+            RemoteStatus::Warning,
+            Some(format!(
+                "slower than {} ms",
+                durations::millis(health_check.max_latency.unwrap())
+            )),
+        )
+    } else {
+        (RemoteStatus::Passing, None)
+    };
+
+    StatusEntry {
+        name: health_check.url.clone(),
+        status,
+        latency: Some(response.latency),
+        detail,
+        build: None,
+    }
+}
+
+impl RemoteIntegration for HttpHealthIntegration {
+    const DEFAULT_SCHEDULE: PollSchedule = PollSchedule::from_secs(10, 0, 0);
+
+    fn get_red_id(&self) -> u16 {
+        self.r
+    }
+    fn get_green_id(&self) -> u16 {
+        self.g
+    }
+    fn get_blue_id(&self) -> u16 {
+        self.b
+    }
+
+    // Endpoints are all probed at once.
+    fn get_report(&mut self) -> ReportFuture {
+        let probes: Vec<_> = self
+            .health_checks
+            .iter()
+            .map(|health_check| probe(&self.http_client, health_check.clone()))
+            .collect();
+        Box::new(future::join_all(probes).map(report))
+    }
+}
+
+fn report(entries: Vec<StatusEntry>) -> StatusReport {
+    let down_count = entries
+        .iter()
+        .filter(|x| x.status == RemoteStatus::Failing)
+        .count();
+    let healthy_count = entries
+        .iter()
+        .filter(|x| x.status == RemoteStatus::Passing)
+        .count();
+
+    let status = if entries.is_empty() {
+        RemoteStatus::Unknown
+    } else if healthy_count == entries.len() {
+        RemoteStatus::Passing
+    } else if down_count == entries.len() {
+        RemoteStatus::Failing
+    } else {
+        // Some endpoints are down or slow, but not all of them.
+        RemoteStatus::Warning
+    };
+
+    info!(
+        target: "health",
+        "{} healthy, {} down, {} degraded endpoints.",
+        healthy_count,
+        down_count,
+        entries.len() - healthy_count - down_count
+    );
+
+    let report = StatusReport::with_entries(status, entries);
+    for entry in &report.entries {
+        let latency = entry
+            .latency
+            .map_or("n/a".to_string(), |x| format!("{} ms", durations::millis(x)));
+        match entry.detail {
+            Some(ref detail) => info!(
+                target: "health",
+                "{} is {:?} ({}) in {}.",
+                entry.name, entry.status, detail, latency
+            ),
+            None => info!(
+                target: "health",
+                "{} is {:?} in {}.",
+                entry.name, entry.status, latency
+            ),
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture_fetcher::FixtureFetcher;
+    use reqwest::StatusCode;
+    use tokio_core::reactor::Core;
+
+    const API_URL: &str = "http://api.example.com/health";
+    const WEB_URL: &str = "http://www.example.com/health";
+
+    fn endpoint(url: &str) -> HealthCheckEndpoint {
+        HealthCheckEndpoint {
+            url: url.to_string(),
+            method: "get".to_string(),
+            expected_status_codes: vec![200],
+            body_contains: None,
+            body_regex: None,
+            max_latency_ms: None,
+        }
+    }
+
+    fn get_report(fetcher: FixtureFetcher, endpoints: &[HealthCheckEndpoint]) -> StatusReport {
+        let mut core = Core::new().unwrap();
+        let health_checks = endpoints
+            .iter()
+            .map(|x| HealthCheck::from_config(x).unwrap())
+            .collect();
+        let mut integration = HttpHealthIntegration::new(
+            0,
+            1,
+            2,
+            health_checks,
+            fetcher.into_client(&core.handle()),
+        );
+        core.run(integration.get_report()).unwrap()
+    }
+
+    #[test]
+    fn all_healthy_endpoints_is_passing() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(API_URL, "health/up.json")
+            .with_fixture(WEB_URL, "health/up.json");
+
+        let report = get_report(fetcher, &[endpoint(API_URL), endpoint(WEB_URL)]);
+
+        assert_eq!(report.status, RemoteStatus::Passing);
+        assert!(report.entries.iter().all(|x| x.status == RemoteStatus::Passing));
+    }
+
+    #[test]
+    fn unexpected_status_code_is_failing() {
+        let fetcher = FixtureFetcher::new().with_status(API_URL, StatusCode::ServiceUnavailable);
+
+        let report = get_report(fetcher, &[endpoint(API_URL)]);
+
+        assert_eq!(report.status, RemoteStatus::Failing);
+        assert_eq!(
+            report.entries[0].detail,
+            Some("unexpected status code 503".to_string())
+        );
+    }
+
+    #[test]
+    fn any_expected_status_code_is_healthy() {
+        let fetcher = FixtureFetcher::new().with_status(API_URL, StatusCode::Unauthorized);
+        let endpoint = HealthCheckEndpoint {
+            expected_status_codes: vec![200, 401],
+            ..endpoint(API_URL)
+        };
+
+        assert_eq!(get_report(fetcher, &[endpoint]).status, RemoteStatus::Passing);
+    }
+
+    #[test]
+    fn body_must_contain_the_expected_text() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(API_URL, "health/up.json")
+            .with_fixture(WEB_URL, "health/down.json");
+        let endpoints = [API_URL, WEB_URL]
+            .iter()
+            .map(|url| HealthCheckEndpoint {
+                body_contains: Some(r#""db": { "status": "UP" }"#.to_string()),
+                ..endpoint(url)
+            })
+            .collect::<Vec<_>>();
+
+        let report = get_report(fetcher, &endpoints);
+
+        assert_eq!(report.entries[0].status, RemoteStatus::Passing);
+        assert_eq!(report.entries[1].status, RemoteStatus::Failing);
+        assert_eq!(
+            report.entries[1].detail,
+            Some(r#"body does not contain ""db": { "status": "UP" }""#.to_string())
+        );
+    }
+
+    #[test]
+    fn body_must_match_the_regex() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(API_URL, "health/up.json")
+            .with_fixture(WEB_URL, "health/down.json");
+        let endpoints = [API_URL, WEB_URL]
+            .iter()
+            .map(|url| HealthCheckEndpoint {
+                body_regex: Some(r#""db":\s*\{\s*"status":\s*"UP""#.to_string()),
+                ..endpoint(url)
+            })
+            .collect::<Vec<_>>();
+
+        let report = get_report(fetcher, &endpoints);
+
+        assert_eq!(report.entries[0].status, RemoteStatus::Passing);
+        assert_eq!(report.entries[1].status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn slow_endpoint_is_a_warning() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(API_URL, "health/up.json")
+            .with_delay(API_URL, Duration::from_millis(50));
+        let endpoint = HealthCheckEndpoint {
+            max_latency_ms: Some(10),
+            ..endpoint(API_URL)
+        };
+
+        let report = get_report(fetcher, &[endpoint]);
+
+        assert_eq!(report.status, RemoteStatus::Warning);
+        assert_eq!(report.entries[0].detail, Some("slower than 10 ms".to_string()));
+    }
+
+    #[test]
+    fn some_endpoints_down_is_a_warning() {
+        // Nothing answers at WEB_URL.
+        let fetcher = FixtureFetcher::new().with_fixture(API_URL, "health/up.json");
+
+        let report = get_report(fetcher, &[endpoint(API_URL), endpoint(WEB_URL)]);
+
+        assert_eq!(report.status, RemoteStatus::Warning);
+        assert_eq!(report.entries[1].status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn all_endpoints_down_is_failing() {
+        let fetcher = FixtureFetcher::new().with_status(API_URL, StatusCode::InternalServerError);
+
+        let report = get_report(fetcher, &[endpoint(API_URL), endpoint(WEB_URL)]);
+
+        assert_eq!(report.status, RemoteStatus::Failing);
+    }
+}
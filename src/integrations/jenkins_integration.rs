@@ -1,10 +1,23 @@
-use failure::Error;
+use errors::NetworkError;
+use futures::{future, stream, Future, Stream};
 use integrations::jenkins_response::*;
-use network::{get_basic_credentials, get_url_response};
+use integrations::remote_integration::ReportFuture;
+use network::{get_basic_credentials, HttpClient, ResponseFuture};
 use remote_status::RemoteStatus;
 use reqwest::header::{Authorization, Headers};
+use scheduler::PollSchedule;
+use status_report::{BuildInfo, StatusEntry, StatusReport};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Timeout;
 use RemoteIntegration;
 
+// Used if jenkins_max_concurrent_fetches or jenkins_fetch_deadline_secs aren't set.
+pub const DEFAULT_MAX_CONCURRENT_FETCHES: usize = 4;
+pub const DEFAULT_FETCH_DEADLINE: Duration = Duration::from_secs(8);
+
 pub struct JenkinsIntegration {
     r: u16,
     g: u16,
@@ -12,9 +25,34 @@ pub struct JenkinsIntegration {
     username: String,
     password: String,
     base_url: String,
+    http_client: HttpClient,
+    // How many jobs' last builds are fetched at once.
+    max_concurrent_fetches: usize,
+    // How long a poll waits for the jobs' last builds, counted from the start of the poll.
+    fetch_deadline: Duration,
+    // The last build fetched for each job, to fall back on when a job isn't fetched in time.
+    // Shared with the poll that's under way, which updates it when it finishes.
+    last_builds: Rc<RefCell<HashMap<String, LastBuild>>>,
+}
+
+// A job's last build.
+#[derive(Clone)]
+struct LastBuild {
+    status: JenkinsBuildStatus,
+    // None for jobs that have never been built.
+    build: Option<BuildInfo>,
+}
+
+// What a poll found out about a single job.
+enum JobResult {
+    Fetched(LastBuild),
+    // Not fetched before the deadline. Has the last build from an earlier poll, if there was one.
+    Stale(Option<LastBuild>),
+    Failed(NetworkError),
 }
 
 impl JenkinsIntegration {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         r: u16,
         g: u16,
@@ -22,6 +60,9 @@ impl JenkinsIntegration {
         username: &str,
         password: &str,
         base_url: &str,
+        http_client: HttpClient,
+        max_concurrent_fetches: usize,
+        fetch_deadline: Duration,
     ) -> JenkinsIntegration {
         JenkinsIntegration {
             r: r,
@@ -30,64 +71,314 @@ impl JenkinsIntegration {
             username: username.to_string(),
             password: password.to_string(),
             base_url: base_url.to_string(),
+            http_client,
+            max_concurrent_fetches,
+            fetch_deadline,
+            last_builds: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 
-    fn get_status_internal(&self) -> Result<Vec<Result<JenkinsBuildStatus, Error>>, Error> {
+    fn get_status_internal(&self) -> ResponseFuture<Vec<(String, JobResult)>> {
+        let deadline = Instant::now() + self.fetch_deadline;
         let url_string = format!("{base}/api/json", base = self.base_url);
-        let mut auth_headers = Headers::new();
-        auth_headers.set(Authorization(get_basic_credentials(
-            self.username.as_str(),
-            Some(self.password.clone()),
-        )));
-
-        let all_jobs_response: Result<(JenkinsJobResponse, Headers), Error> =
-            get_url_response(&url_string, auth_headers.clone());
-
-        match all_jobs_response {
-            Ok((result, _)) => {
-                let results = result
+        let all_jobs_response = self.http_client.get_url_response_with_retry(
+            &url_string,
+            auth_headers(&self.username, &self.password),
+        );
+
+        let http_client = self.http_client.clone();
+        let base_url = self.base_url.clone();
+        let auth_headers = auth_headers(&self.username, &self.password);
+        let max_concurrent_fetches = self.max_concurrent_fetches;
+        let fetch_deadline = self.fetch_deadline;
+        let last_builds = Rc::clone(&self.last_builds);
+        Box::new(all_jobs_response.and_then(
+            move |(all_jobs, _): (JenkinsJobResponse, Headers)| {
+                let job_names: Vec<String> = all_jobs
                     .jobs
-                    .iter()
+                    .into_iter()
                     .filter(|job| {
                         job.color != JenkinsJobColor::Disabled
                             && job.color != JenkinsJobColor::DisabledAnime
                     })
-                    .map(|job| {
-                        let job_url_string = format!(
-                            "{base}/job/{job}/lastBuild/api/json",
-                            base = self.base_url,
-                            job = job.name
-                        );
-                        let job_response: Result<
-                            (JenkinsBuildResult, Headers),
-                            Error,
-                        > = get_url_response(&job_url_string, auth_headers.clone());
-
-                        match job_response {
-                            Ok((job_result, _)) => {
-                                if job_result.building {
-                                    Ok(JenkinsBuildStatus::Building)
-                                } else {
-                                    let unwrapped_result = job_result.build_result.unwrap();
-                                    Ok(unwrapped_result)
-                                }
+                    .map(|job| job.name)
+                    .collect();
+
+                get_last_builds(
+                    &http_client,
+                    &base_url,
+                    &auth_headers,
+                    job_names.clone(),
+                    max_concurrent_fetches,
+                    deadline,
+                )
+                .map(move |fetched| {
+                    let mut last_builds = last_builds.borrow_mut();
+                    let mut results = Vec::new();
+                    for (job_name, result) in job_names.into_iter().zip(fetched) {
+                        let result = match result {
+                            Some(Ok(last_build)) => {
+                                last_builds.insert(job_name.clone(), last_build.clone());
+                                JobResult::Fetched(last_build)
                             }
-                            Err(job_err) => {
-                                warn!("--Jenkins--: HTTP failure when attempting to get job result for job: {}. Error: {}", &job_url_string, job_err);
-                                Err(job_err)
+                            Some(Err(e)) => JobResult::Failed(e),
+                            None => {
+                                warn!(target: "jenkins", "Job {} wasn't fetched within {} seconds. Using its last known status, if any.", job_name, fetch_deadline.as_secs());
+                                JobResult::Stale(last_builds.get(&job_name).cloned())
                             }
-                        }
-                    })
-                    .collect();
-                Ok(results)
-            }
-            Err(err) => Err(err),
+                        };
+                        results.push((job_name, result));
+                    }
+                    results
+                })
+            },
+        ))
+    }
+
+    fn summarize(results: &[(String, JobResult)]) -> RemoteStatus {
+        let (retrieved, not_retrieved): (
+            Vec<Option<JenkinsBuildStatus>>,
+            Vec<Option<JenkinsBuildStatus>>,
+        ) = results
+            .iter()
+            .map(|(_, result)| match *result {
+                JobResult::Fetched(ref last_build) => Some(last_build.status),
+                JobResult::Stale(ref last_build) => last_build.as_ref().map(|x| x.status),
+                JobResult::Failed(_) => None,
+            })
+            .partition(|x| x.is_some());
+
+        let retrieved: Vec<JenkinsBuildStatus> =
+            retrieved.into_iter().map(|x| x.unwrap()).collect();
+        
+        let not_retrieved_count = not_retrieved.len();
+        let stale_count = results
+            .iter()
+            .filter(|(_, result)| match *result {
+                JobResult::Stale(_) => true,
+                _ => false,
+            })
+            .count();
+        let build_failures = *(&retrieved
+            .iter()
+            .filter(|x| {
+                **x == JenkinsBuildStatus::Failure || **x == JenkinsBuildStatus::Unstable
+            })
+            .count());
+        let indeterminate_count = *(&retrieved
+            .iter()
+            .filter(|x| {
+                **x != JenkinsBuildStatus::Failure
+                    && **x != JenkinsBuildStatus::Unstable
+                    && **x != JenkinsBuildStatus::Success
+            })
+            .count()) + not_retrieved_count;
+        let build_successes = *(&retrieved
+            .iter()
+            .filter(|x| **x == JenkinsBuildStatus::Success)
+            .count());
+
+        let builds_in_progress = *(&retrieved
+            .iter()
+            .filter(|x| **x == JenkinsBuildStatus::Building)
+            .count());
+
+        info!(target: "jenkins", "Retrieved {} jobs, failed to retrieve {} jobs, and {} were stale. Of those, {} succeeded, {} failed, and {} were indeterminate.", retrieved.len(), not_retrieved_count, stale_count, build_successes, build_failures, indeterminate_count);                
+
+        // No successes, or at least one failure
+        if build_successes == 0 || build_failures > 0 {
+            return RemoteStatus::Failing;
+        }                
+        // If no failures, immediately report any builds-in-progress
+        if  build_failures == 0 && builds_in_progress > 0 {
+            return RemoteStatus::InProgress;
+        }
+        // No failures, and more successes than indeterminates
+        if build_failures == 0 && build_successes > indeterminate_count {
+            return RemoteStatus::Passing;
+        }
+        // No failures, but more indeterminates than successes.
+        if build_failures == 0 && indeterminate_count > build_successes {
+            return RemoteStatus::Failing;
         }
+
+        // None of our other conditions apply
+        return RemoteStatus::Unknown;
+    }
+
+    fn entries(results: Vec<(String, JobResult)>) -> Vec<StatusEntry> {
+        results
+            .into_iter()
+            .map(|(name, result)| {
+                let (status, detail, build) = match result {
+                    JobResult::Fetched(last_build) => (
+                        job_status(last_build.status),
+                        describe(last_build.status),
+                        last_build.build,
+                    ),
+                    JobResult::Stale(Some(last_build)) => (
+                        job_status(last_build.status),
+                        Some(match describe(last_build.status) {
+                            Some(description) => format!("{}, stale", description),
+                            None => "Stale".to_string(),
+                        }),
+                        last_build.build,
+                    ),
+                    JobResult::Stale(None) => (
+                        RemoteStatus::Unknown,
+                        Some("Not fetched in time".to_string()),
+                        None,
+                    ),
+                    JobResult::Failed(e) => (
+                        RemoteStatus::Unknown,
+                        Some(format!("Request failed: {}", e)),
+                        None,
+                    ),
+                };
+                StatusEntry {
+                    name,
+                    status,
+                    latency: None,
+                    detail,
+                    build,
+                }
+            })
+            .collect()
+    }
+}
+
+fn auth_headers(username: &str, password: &str) -> Headers {
+    let mut headers = Headers::new();
+    headers.set(Authorization(get_basic_credentials(
+        username,
+        Some(password.to_string()),
+    )));
+    headers
+}
+
+// Fetches each job's last build, a few at a time, and returns the results in the same order as the jobs.
+// Gives up waiting at the deadline: anything not fetched by then is None. Requests that are still
+// under way by then are dropped, so a server that never answers can't tie anything up past the poll.
+fn get_last_builds(
+    http_client: &HttpClient,
+    base_url: &str,
+    auth_headers: &Headers,
+    job_names: Vec<String>,
+    max_concurrent_fetches: usize,
+    deadline: Instant,
+) -> ResponseFuture<Vec<Option<Result<LastBuild, NetworkError>>>> {
+    let results = Rc::new(RefCell::new(
+        job_names.iter().map(|_| None).collect::<Vec<_>>(),
+    ));
+    let deadline = match Timeout::new_at(deadline, http_client.handle()) {
+        Ok(deadline) => deadline,
+        Err(e) => {
+            return Box::new(future::err(NetworkError::ConnectionFailed {
+                url: base_url.to_string(),
+                message: e.to_string(),
+            }))
+        }
+    };
+
+    let fetches = {
+        let http_client = http_client.clone();
+        let base_url = base_url.to_string();
+        let auth_headers = auth_headers.clone();
+        let results = Rc::clone(&results);
+        stream::iter_ok::<_, ()>(job_names.into_iter().enumerate())
+            .map(move |(index, job_name)| {
+                get_last_build(&http_client, &base_url, &job_name, auth_headers.clone())
+                    .then(move |result| Ok((index, result)))
+            })
+            .buffer_unordered(max_concurrent_fetches)
+            .for_each(move |(index, result)| {
+                results.borrow_mut()[index] = Some(result);
+                Ok(())
+            })
+    };
+    Box::new(fetches.select2(deadline).then(move |_| Ok(results.replace(Vec::new()))))
+}
+
+fn get_last_build(
+    http_client: &HttpClient,
+    base_url: &str,
+    job_name: &str,
+    auth_headers: Headers,
+) -> ResponseFuture<LastBuild> {
+    let job_url_string = format!(
+        "{base}/job/{job}/lastBuild/api/json",
+        base = base_url,
+        job = job_name
+    );
+    let job_response = http_client.get_url_response_with_retry(&job_url_string, auth_headers);
+    Box::new(job_response.then(move |job_response| last_build(&job_url_string, job_response)))
+}
+
+fn last_build(
+    job_url_string: &str,
+    job_response: Result<(JenkinsBuildResult, Headers), NetworkError>,
+) -> Result<LastBuild, NetworkError> {
+    match job_response {
+        Ok((job_result, _)) => {
+            // Jenkins only fills in the result once the build has completely finished, including any
+            // post-build steps, which can be a while after it's stopped building.
+            let status = match job_result.build_result {
+                Some(result) if !job_result.building => result,
+                _ => JenkinsBuildStatus::Building,
+            };
+            let duration = job_result
+                .duration
+                .filter(|_| status != JenkinsBuildStatus::Building)
+                .map(Duration::from_millis);
+            let revision = job_result
+                .actions
+                .into_iter()
+                .filter_map(|x| x.last_built_revision)
+                .map(|x| x.sha1)
+                .next();
+            Ok(LastBuild {
+                status,
+                build: job_result.number.map(|number| BuildInfo {
+                    number,
+                    duration,
+                    revision,
+                }),
+            })
+        }
+        // Jobs that have never been built don't have a lastBuild at all.
+        Err(NetworkError::NotFound { .. }) => Ok(LastBuild {
+            status: JenkinsBuildStatus::NotBuilt,
+            build: None,
+        }),
+        Err(job_err) => {
+            warn!(target: "jenkins", "HTTP failure when attempting to get job result for job: {}. Error: {}", job_url_string, job_err);
+            Err(job_err)
+        }
+    }
+}
+
+fn job_status(status: JenkinsBuildStatus) -> RemoteStatus {
+    match status {
+        JenkinsBuildStatus::Success => RemoteStatus::Passing,
+        JenkinsBuildStatus::Failure | JenkinsBuildStatus::Unstable => RemoteStatus::Failing,
+        JenkinsBuildStatus::Building => RemoteStatus::InProgress,
+        JenkinsBuildStatus::NotBuilt | JenkinsBuildStatus::Aborted => RemoteStatus::Unknown,
+    }
+}
+
+// Only for statuses that don't speak for themselves.
+fn describe(status: JenkinsBuildStatus) -> Option<String> {
+    match status {
+        JenkinsBuildStatus::Unstable => Some("Unstable".to_string()),
+        JenkinsBuildStatus::Aborted => Some("Aborted".to_string()),
+        JenkinsBuildStatus::NotBuilt => Some("Never built".to_string()),
+        _ => None,
     }
 }
 
 impl RemoteIntegration for JenkinsIntegration {
+    const DEFAULT_SCHEDULE: PollSchedule = PollSchedule::from_secs(10, 0, 0);
+
     fn get_red_id(&self) -> u16 {
         self.r
     }
@@ -98,71 +389,213 @@ impl RemoteIntegration for JenkinsIntegration {
         self.b
     }
 
-    fn get_status(&mut self) -> RemoteStatus {
-        match self.get_status_internal() {
-            Ok(results) => {
-                let (retrieved, not_retrieved): (
-                    Vec<Result<JenkinsBuildStatus, Error>>,
-                    Vec<Result<JenkinsBuildStatus, Error>>,
-                ) = results.into_iter().partition(|x| x.is_ok());
-
-                let retrieved: Vec<JenkinsBuildStatus> =
-                    retrieved.into_iter().map(|x| x.unwrap()).collect();
-                
-                let not_retrieved_count = not_retrieved.len();
-                let build_failures = *(&retrieved
-                    .iter()
-                    .filter(|x| {
-                        **x == JenkinsBuildStatus::Failure || **x == JenkinsBuildStatus::Unstable
-                    })
-                    .count());
-                let indeterminate_count = *(&retrieved
-                    .iter()
-                    .filter(|x| {
-                        **x != JenkinsBuildStatus::Failure
-                            && **x != JenkinsBuildStatus::Unstable
-                            && **x != JenkinsBuildStatus::Success
-                    })
-                    .count()) + not_retrieved_count;
-                let build_successes = *(&retrieved
-                    .iter()
-                    .filter(|x| **x == JenkinsBuildStatus::Success)
-                    .count());
-
-                let builds_in_progress = *(&retrieved
-                    .iter()
-                    .filter(|x| **x == JenkinsBuildStatus::Building)
-                    .count());
-
-                info!("--Jenkins--: Retrieved {} jobs, failed to retrieve {} jobs. Of those, {} succeeded, {} failed, and {} were indeterminate.", retrieved.len(), not_retrieved_count, build_successes, build_failures, indeterminate_count);                
-
-                // No successes, or at least one failure
-                if build_successes == 0 || build_failures > 0 {
-                    return RemoteStatus::Failing;
-                }                
-                // If no failures, immediately report any builds-in-progress
-                if  build_failures == 0 && builds_in_progress > 0 {
-                    return RemoteStatus::InProgress;
-                }
-                // No failures, and more successes than indeterminates
-                if build_failures == 0 && build_successes > indeterminate_count {
-                    return RemoteStatus::Passing;
+    fn get_report(&mut self) -> ReportFuture {
+        let http_client = self.http_client.clone();
+        Box::new(self.get_status_internal().then(move |result| {
+            let report = match result {
+                Ok(results) => {
+                    let status = JenkinsIntegration::summarize(&results);
+                    StatusReport::with_entries(status, JenkinsIntegration::entries(results))
                 }
-                // No failures, but more indeterminates than successes.
-                if build_failures == 0 && indeterminate_count > build_successes {
-                    return RemoteStatus::Failing;
+                Err(e) => {
+                    match e {
+                        NetworkError::Unauthorized { .. } => error!(
+                            target: "jenkins",
+                            "Jenkins rejected jenkins_username and jenkins_password. Details: {}",
+                            e
+                        ),
+                        _ => warn!(
+                            target: "jenkins",
+                            "Failed to retrieve any jobs from Jenkins. Details: {}",
+                            e
+                        ),
+                    }
+                    StatusReport::failed(e.kind(), e.to_string())
                 }
+            };
 
-                // None of our other conditions apply
-                return RemoteStatus::Unknown;
-            }
-            Err(e) => {
-                warn!(
-                    "--Jenkins--: Failed to retrieve any jobs from Jenkins. Details: {}",
-                    e
-                );
-                return RemoteStatus::Unknown;
-            }
-        }
+            let cache_stats = http_client.cache_stats();
+            debug!(
+                target: "jenkins",
+                "{} unchanged responses served from cache, {} downloaded so far.",
+                cache_stats.hits, cache_stats.misses
+            );
+            Ok(report)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture_fetcher::FixtureFetcher;
+    use reqwest::StatusCode;
+    use tokio_core::reactor::Core;
+
+    const BASE_URL: &str = "http://jenkins.example.com";
+
+    fn job_url(job: &str) -> String {
+        format!("{}/job/{}/lastBuild/api/json", BASE_URL, job)
+    }
+
+    fn get_report_within(fetcher: FixtureFetcher, fetch_deadline: Duration) -> StatusReport {
+        let mut core = Core::new().unwrap();
+        let mut integration = JenkinsIntegration::new(
+            0,
+            1,
+            2,
+            "user",
+            "password",
+            BASE_URL,
+            fetcher.into_client(&core.handle()),
+            DEFAULT_MAX_CONCURRENT_FETCHES,
+            fetch_deadline,
+        );
+        core.run(integration.get_report()).unwrap()
+    }
+
+    fn get_report(fetcher: FixtureFetcher) -> StatusReport {
+        get_report_within(fetcher, DEFAULT_FETCH_DEADLINE)
+    }
+
+    #[test]
+    fn all_jobs_passing_is_passing() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_all_passing.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_fixture(&job_url("web-client"), "jenkins/build_success.json")
+            // Disabled, so this should never be looked at.
+            .with_fixture(&job_url("nightly-docs"), "jenkins/build_failure.json");
+
+        let report = get_report(fetcher);
+
+        assert_eq!(report.status, RemoteStatus::Passing);
+        assert!(report.error.is_none());
+        assert_eq!(
+            report.entries[0].build,
+            Some(BuildInfo {
+                number: 142,
+                duration: Some(Duration::from_millis(176003)),
+                revision: Some("4f1c2a9be0d3c7e65a8b1f0e2d9c3b7a6e5d4c3b".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn any_failing_job_is_failing() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_mixed.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_fixture(&job_url("web-client"), "jenkins/build_failure.json")
+            .with_fixture(&job_url("integration-tests"), "jenkins/build_success.json")
+            .with_fixture(&job_url("packaging"), "jenkins/build_in_progress.json");
+
+        assert_eq!(get_report(fetcher).status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn unstable_job_is_failing() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_mixed.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_fixture(&job_url("web-client"), "jenkins/build_success.json")
+            .with_fixture(&job_url("integration-tests"), "jenkins/build_unstable.json")
+            .with_fixture(&job_url("packaging"), "jenkins/build_success.json");
+
+        assert_eq!(get_report(fetcher).status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn building_job_without_failures_is_in_progress() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_mixed.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_fixture(&job_url("web-client"), "jenkins/build_success.json")
+            .with_fixture(&job_url("integration-tests"), "jenkins/build_success.json")
+            .with_fixture(&job_url("packaging"), "jenkins/build_in_progress.json");
+
+        assert_eq!(get_report(fetcher).status, RemoteStatus::InProgress);
+    }
+
+    #[test]
+    fn job_without_a_result_yet_is_in_progress() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_mixed.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_fixture(&job_url("web-client"), "jenkins/build_success.json")
+            .with_fixture(&job_url("integration-tests"), "jenkins/build_success.json")
+            .with_fixture(&job_url("packaging"), "jenkins/build_finishing.json");
+
+        let report = get_report(fetcher);
+
+        // Fetched, not stale, and without a duration until it's finished.
+        assert_eq!(report.status, RemoteStatus::InProgress);
+        let packaging = report.entries.iter().find(|x| x.name == "packaging").unwrap();
+        assert_eq!(packaging.status, RemoteStatus::InProgress);
+        assert_eq!(packaging.detail, None);
+        assert_eq!(packaging.build.as_ref().unwrap().duration, None);
+    }
+
+    #[test]
+    fn mostly_indeterminate_jobs_is_failing() {
+        // One success, two aborted builds, and one job that couldn't be retrieved at all.
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_mixed.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_fixture(&job_url("web-client"), "jenkins/build_aborted.json")
+            .with_fixture(&job_url("integration-tests"), "jenkins/build_aborted.json");
+
+        assert_eq!(get_report(fetcher).status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn as_many_unretrievable_jobs_as_successes_is_unknown() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_all_passing.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_status(&job_url("web-client"), StatusCode::InternalServerError);
+
+        let report = get_report(fetcher);
+
+        assert_eq!(report.status, RemoteStatus::Unknown);
+        assert!(report.error.is_none());
+    }
+
+    #[test]
+    fn slow_jobs_are_stale_instead_of_holding_up_the_poll() {
+        let fetcher = FixtureFetcher::new()
+            .with_fixture(&format!("{}/api/json", BASE_URL), "jenkins/jobs_all_passing.json")
+            .with_fixture(&job_url("api-server"), "jenkins/build_success.json")
+            .with_fixture(&job_url("web-client"), "jenkins/build_success.json")
+            .with_delay(&job_url("web-client"), Duration::from_secs(5));
+        let started = Instant::now();
+
+        let report = get_report_within(fetcher, Duration::from_millis(200));
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(report.status, RemoteStatus::Unknown);
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(report.entries[0].status, RemoteStatus::Passing);
+        assert_eq!(report.entries[1].name, "web-client");
+        assert_eq!(
+            report.entries[1].detail,
+            Some("Not fetched in time".to_string())
+        );
+    }
+
+    #[test]
+    fn unreachable_jenkins_is_an_error() {
+        let report = get_report(FixtureFetcher::new());
+
+        assert_eq!(report.status, RemoteStatus::Unknown);
+        assert!(report.error.is_some());
+    }
+
+    #[test]
+    fn rejected_credentials_are_an_error() {
+        let fetcher = FixtureFetcher::new()
+            .with_status(&format!("{}/api/json", BASE_URL), StatusCode::Unauthorized);
+
+        assert!(get_report(fetcher).error.is_some());
     }
 }
@@ -16,9 +16,29 @@ pub struct JenkinsBuildResult {
 
     #[serde(rename = "result")]
     pub build_result: Option<JenkinsBuildStatus>,
+
+    pub number: Option<u64>,
+    // In milliseconds. 0 while the build's still running.
+    pub duration: Option<u64>,
+
+    // Which revision was built is in one of these, if the job uses git.
+    #[serde(default)]
+    pub actions: Vec<JenkinsBuildAction>,
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize)]
+pub struct JenkinsBuildAction {
+    #[serde(rename = "lastBuiltRevision")]
+    pub last_built_revision: Option<JenkinsRevision>,
+}
+
+#[derive(Deserialize)]
+pub struct JenkinsRevision {
+    #[serde(rename = "SHA1")]
+    pub sha1: String,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum JenkinsBuildStatus {
     Success,
@@ -1,5 +1,8 @@
+pub mod alertmanager_integration;
+mod alertmanager_response;
+pub mod http_health_integration;
 pub mod jenkins_integration;
-mod jenkins_response;
+pub mod jenkins_response;
 pub mod remote_integration;
 pub mod unity_cloud_integration;
 mod unity_cloud_response;
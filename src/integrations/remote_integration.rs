@@ -1,8 +1,18 @@
-use RemoteStatus;
+use futures::Future;
+use scheduler::PollSchedule;
+use status_report::StatusReport;
+
+// A poll that's under way on the event loop. Anything that goes wrong ends up in the report, so it never fails.
+pub type ReportFuture = Box<dyn Future<Item = StatusReport, Error = ()>>;
 
 pub trait RemoteIntegration {
-    fn get_status(&mut self) -> RemoteStatus;
+    // How often to poll, unless overridden by a [schedule.<light>] section in config.
+    const DEFAULT_SCHEDULE: PollSchedule;
+
     fn get_red_id(&self) -> u16;
     fn get_green_id(&self) -> u16;
     fn get_blue_id(&self) -> u16;
+
+    // Starts a poll. Dropping the future abandons it, along with any requests it still has in flight.
+    fn get_report(&mut self) -> ReportFuture;
 }
@@ -1,14 +1,19 @@
-use errors::UnityRetrievalError;
-use failure::Error;
+use errors::{NetworkError, UnityRetrievalError};
+use futures::Future;
+use integrations::remote_integration::ReportFuture;
 use integrations::unity_cloud_response::*;
-use network::{get_basic_credentials, get_url_response};
+use network::{get_basic_credentials, HttpClient};
 use remote_status::RemoteStatus;
 use reqwest::header::{Authorization, ContentType, Headers};
+use scheduler::PollSchedule;
+use status_report::{BuildInfo, StatusEntry, StatusReport};
 use std::time::Duration;
-use std::time::Instant;
 use RemoteIntegration;
 
-const UNITY_SLEEP_DURATION: u64 = 1000 * 60;
+// The build targets polled, in the same order as get_status_internal's results.
+const BUILD_TARGETS: [&str; 2] = ["ios-development", "android-development"];
+
+type PlatformResult = Result<(UnityBuild, Headers), UnityRetrievalError>;
 
 pub struct UnityCloudIntegration {
     r: u16,
@@ -16,24 +21,29 @@ pub struct UnityCloudIntegration {
     b: u16,
     api_token: String,
     base_url: String,
-    last_tick: Instant,
-    last_status: RemoteStatus,
+    http_client: HttpClient,
 }
 
 impl UnityCloudIntegration {
-    pub fn new(r: u16, g: u16, b: u16, api_token: &str, base_url: &str) -> UnityCloudIntegration {
+    pub fn new(
+        r: u16,
+        g: u16,
+        b: u16,
+        api_token: &str,
+        base_url: &str,
+        http_client: HttpClient,
+    ) -> UnityCloudIntegration {
         UnityCloudIntegration {
             r: r,
             g: g,
             b: b,
             api_token: api_token.to_string(),
             base_url: base_url.to_string(),
-            last_tick: Instant::now() - Duration::from_millis(UNITY_SLEEP_DURATION),
-            last_status: RemoteStatus::Unknown,
+            http_client,
         }
     }
 
-    fn get_status_internal(&self) -> Vec<Result<(UnityBuildStatus, Headers), UnityRetrievalError>> {
+    fn get_status_internal(&self) -> Box<dyn Future<Item = Vec<PlatformResult>, Error = ()>> {
         let mut headers = Headers::new();
         let auth_header = get_basic_credentials(&self.api_token, None);
         headers.set(Authorization(auth_header));
@@ -43,50 +53,85 @@ impl UnityCloudIntegration {
             "{base}/ios-development/builds?per_page=1",
             base = self.base_url
         );
-        let ios_build_response =
-            UnityCloudIntegration::get_platform_status(&headers, ios_url.as_str());
+        let ios_build_response = get_platform_status(&self.http_client, &headers, ios_url);
 
         let android_url = format!(
             "{base}/android-development/builds?per_page=1",
             base = self.base_url
         );
         let android_build_response =
-            UnityCloudIntegration::get_platform_status(&headers, android_url.as_str());
-        vec![ios_build_response, android_build_response]
-    }
-
-    fn get_platform_status(
-        headers: &Headers,
-        url: &str,
-    ) -> Result<(UnityBuildStatus, Headers), UnityRetrievalError> {
-        let unity_build_response: Result<(Vec<UnityBuild>, Headers), Error> =
-            get_url_response(&url, headers.clone());
-        match unity_build_response {
-            Ok((mut unity_http_result, response_headers)) => {
-                if unity_http_result.len() != 0 {
-                    Ok((unity_http_result.remove(0).build_status, response_headers))
-                } else {
-                    warn!(
-                        "--Unity--: No builds retrieved from Unity Cloud for URL {}. Aborting...",
-                        url
+            get_platform_status(&self.http_client, &headers, android_url);
+
+        let http_client = self.http_client.clone();
+        Box::new(
+            ios_build_response
+                .then(Ok)
+                .join(android_build_response.then(Ok))
+                .map(move |(ios_build_response, android_build_response)| {
+                    let cache_stats = http_client.cache_stats();
+                    debug!(
+                        target: "unity",
+                        "{} unchanged responses served from cache, {} downloaded so far.",
+                        cache_stats.hits, cache_stats.misses
                     );
-                    Err(UnityRetrievalError::NoBuildsReturned)
+                    vec![ios_build_response, android_build_response]
+                }),
+        )
+    }
+}
+
+fn get_platform_status(
+    http_client: &HttpClient,
+    headers: &Headers,
+    url: String,
+) -> Box<dyn Future<Item = (UnityBuild, Headers), Error = UnityRetrievalError>> {
+    let unity_build_response = http_client.get_url_response_with_retry(&url, headers.clone());
+    Box::new(unity_build_response.then(
+        move |unity_build_response: Result<(Vec<UnityBuild>, Headers), NetworkError>| {
+            match unity_build_response {
+                Ok((mut unity_http_result, response_headers)) => {
+                    if unity_http_result.len() != 0 {
+                        Ok((unity_http_result.remove(0), response_headers))
+                    } else {
+                        warn!(
+                            target: "unity",
+                            "No builds retrieved from Unity Cloud for URL {}. Aborting...",
+                            url
+                        );
+                        Err(UnityRetrievalError::NoBuildsReturned)
+                    }
+                }
+                Err(unity_http_err) => {
+                    match unity_http_err {
+                        NetworkError::Unauthorized { .. } => error!(
+                            target: "unity",
+                            "Unity Cloud rejected unity_cloud_api_token. Error: {}",
+                            unity_http_err
+                        ),
+                        NetworkError::RateLimited { .. } => warn!(
+                            target: "unity",
+                            "Unity Cloud is rate limiting us. Consider raising interval_secs in [schedule.unity]. Error: {}",
+                            unity_http_err
+                        ),
+                        _ => warn!(
+                            target: "unity",
+                            "Failure getting Unity Cloud build status for url: {}. Error: {}",
+                            url, unity_http_err
+                        ),
+                    }
+                    Err(UnityRetrievalError::HttpError(unity_http_err))
                 }
             }
-            Err(unity_http_err) => {
-                warn!(
-                    "--Unity--: Failure getting Unity Cloud build status for url: {}. Error: {}",
-                    url, unity_http_err
-                );
-                Err(UnityRetrievalError::HttpError {
-                    http_error_message: unity_http_err.to_string(),
-                })
-            }
-        }
-    }
+        },
+    ))
 }
 
 impl RemoteIntegration for UnityCloudIntegration {
+    // Only poll once a minute, so we don't hit the API's rate limit. It claims we can
+    // inspect the rate limit header we get back to avoid that, but it doesn't work correctly.
+    // The minimum spacing keeps restarts from polling early, too.
+    const DEFAULT_SCHEDULE: PollSchedule = PollSchedule::from_secs(60, 0, 60);
+
     fn get_red_id(&self) -> u16 {
         self.r
     }
@@ -97,101 +142,265 @@ impl RemoteIntegration for UnityCloudIntegration {
         self.b
     }
 
-    fn get_status(&mut self) -> RemoteStatus {
-        // Poll this as frequently as the rest, but only actually do any work
-        // once every UNITY_SLEEP_DURATION, so we don't hit the API's
-        // rate limit. It claims we can inspet the rate limit header we get
-        // back to avoid that, but it doesn't work correctly.
-        if Instant::now() - self.last_tick < Duration::from_millis(UNITY_SLEEP_DURATION) {
-            let till_next = Duration::from_millis(UNITY_SLEEP_DURATION) - (Instant::now() - self.last_tick);
-            info!("--Unity-- Sleeping for another {} seconds.", till_next.as_secs());
-            return self.last_status;
-        }
+    fn get_report(&mut self) -> ReportFuture {
+        Box::new(self.get_status_internal().map(report))
+    }
+}
 
-        let unity_results = self.get_status_internal();
-        let (retrieved, not_retrieved): (
-            Vec<Result<(UnityBuildStatus, Headers), UnityRetrievalError>>,
-            Vec<Result<(UnityBuildStatus, Headers), UnityRetrievalError>>,
-        ) = unity_results.into_iter().partition(|x| x.is_ok());
+fn report(unity_results: Vec<Result<(UnityBuild, Headers), UnityRetrievalError>>) -> StatusReport {
+    if unity_results.iter().all(|x| x.is_err()) {
+        let errors: Vec<&UnityRetrievalError> = unity_results
+            .iter()
+            .filter_map(|x| x.as_ref().err())
+            .collect();
+        let messages: Vec<String> = errors.iter().map(|x| x.to_string()).collect();
+        // Both platforms usually fail for the same reason, so the first one's kind stands for both.
+        let error_kind = errors.first().map_or("unknown", |x| x.kind());
+        return StatusReport::failed(error_kind, messages.join(" "));
+    }
+    let entries = BUILD_TARGETS
+        .iter()
+        .zip(&unity_results)
+        .map(|(build_target, result)| build_target_entry(build_target, result))
+        .collect();
 
-        let retrieved_results: Vec<(UnityBuildStatus, Headers)> =
-            retrieved.into_iter().map(|x| x.unwrap()).collect();
-        let not_retrieved_results: Vec<UnityRetrievalError> =
-            not_retrieved.into_iter().map(|x| x.unwrap_err()).collect();
+    let (retrieved, not_retrieved): (
+        Vec<Result<(UnityBuild, Headers), UnityRetrievalError>>,
+        Vec<Result<(UnityBuild, Headers), UnityRetrievalError>>,
+    ) = unity_results.into_iter().partition(|x| x.is_ok());
 
-        let return_status: RemoteStatus;
+    let retrieved_results: Vec<(UnityBuildStatus, Headers)> = retrieved
+        .into_iter()
+        .map(|x| x.unwrap())
+        .map(|(build, headers)| (build.build_status, headers))
+        .collect();
+    let not_retrieved_results: Vec<UnityRetrievalError> =
+        not_retrieved.into_iter().map(|x| x.unwrap_err()).collect();
 
-        if not_retrieved_results.len() > 0 {
-            info!("--Unity--: At least one result not retrieved.");
+    let return_status: RemoteStatus;
+
+    if not_retrieved_results.len() > 0 {
+        info!(target: "unity", "At least one result not retrieved.");
+        return_status = RemoteStatus::Unknown;
+    } else {
+        let passing_builds = *(&retrieved_results
+            .iter()
+            .filter(|x| x.0 == UnityBuildStatus::Success)
+            .count());
+        let failing_builds = *(&retrieved_results
+            .iter()
+            .filter(|x| x.0 == UnityBuildStatus::Failure)
+            .count());
+        let in_progress_builds = *(&retrieved_results
+            .iter()
+            .filter(|x| {
+                x.0 == UnityBuildStatus::Queued
+                    || x.0 == UnityBuildStatus::SentToBuilder
+                    || x.0 == UnityBuildStatus::Started
+                    || x.0 == UnityBuildStatus::Restarted
+            })
+            .count());
+        let other_status_builds = *(&retrieved_results
+            .iter()
+            .filter(|x| {
+                x.0 != UnityBuildStatus::Success
+                    && x.0 != UnityBuildStatus::Failure
+                    && x.0 != UnityBuildStatus::Queued
+                    && x.0 != UnityBuildStatus::SentToBuilder
+                    && x.0 != UnityBuildStatus::Started
+                    && x.0 != UnityBuildStatus::Restarted
+            })
+            .count());
+
+        // More misc statuses than knowns
+        if other_status_builds > passing_builds + failing_builds + in_progress_builds {
+            info!(target: "unity", "More otherstatuses than passing AND failing.");
             return_status = RemoteStatus::Unknown;
-        } else {
-            let passing_builds = *(&retrieved_results
-                .iter()
-                .filter(|x| x.0 == UnityBuildStatus::Success)
-                .count());
-            let failing_builds = *(&retrieved_results
-                .iter()
-                .filter(|x| x.0 == UnityBuildStatus::Failure)
-                .count());
-            let in_progress_builds = *(&retrieved_results
-                .iter()
-                .filter(|x| {
-                    x.0 == UnityBuildStatus::Queued
-                        || x.0 == UnityBuildStatus::SentToBuilder
-                        || x.0 == UnityBuildStatus::Started
-                        || x.0 == UnityBuildStatus::Restarted
-                })
-                .count());
-            let other_status_builds = *(&retrieved_results
-                .iter()
-                .filter(|x| {
-                    x.0 != UnityBuildStatus::Success
-                        && x.0 != UnityBuildStatus::Failure
-                        && x.0 != UnityBuildStatus::Queued
-                        && x.0 != UnityBuildStatus::SentToBuilder
-                        && x.0 != UnityBuildStatus::Started
-                        && x.0 != UnityBuildStatus::Restarted
-                })
-                .count());
-
-            // More misc statuses than knowns
-            if other_status_builds > passing_builds + failing_builds + in_progress_builds {
-                info!("--Unity--: More otherstatuses than passing AND failing.");
-                return_status = RemoteStatus::Unknown;
-            }
-            // No failures and at least one building
-            else if failing_builds == 0 && in_progress_builds > 0 {
-                info!("--Unity--: No failures and at least one building");
-                return_status = RemoteStatus::InProgress;
-            }
-            // All passing or misc
-            else if passing_builds > 0 && failing_builds == 0 {
-                info!("--Unity--: All passing or misc.");
-                return_status = RemoteStatus::Passing;
-            }
-            // All failing or misc
-            else if passing_builds == 0 && failing_builds > 0 {
-                info!("--Unity--: All failing or misc.");
-                return_status = RemoteStatus::Failing;
-            }
-            // Both failing and passing
-            else if passing_builds > 0 && failing_builds > 0 {
-                info!("--Unity--: At least one failing AND passing.");
-                return_status = RemoteStatus::Failing;
-            }
-            // ?????
-            else {
-                info!("--Unity--: Unknown state.");
-                return_status = RemoteStatus::Unknown;
+        }
+        // No failures and at least one building
+        else if failing_builds == 0 && in_progress_builds > 0 {
+            info!(target: "unity", "No failures and at least one building");
+            return_status = RemoteStatus::InProgress;
+        }
+        // All passing or misc
+        else if passing_builds > 0 && failing_builds == 0 {
+            info!(target: "unity", "All passing or misc.");
+            return_status = RemoteStatus::Passing;
+        }
+        // All failing or misc
+        else if passing_builds == 0 && failing_builds > 0 {
+            info!(target: "unity", "All failing or misc.");
+            return_status = RemoteStatus::Failing;
+        }
+        // Both failing and passing
+        else if passing_builds > 0 && failing_builds > 0 {
+            info!(target: "unity", "At least one failing AND passing.");
+            return_status = RemoteStatus::Failing;
+        }
+        // ?????
+        else {
+            info!(target: "unity", "Unknown state.");
+            return_status = RemoteStatus::Unknown;
+        }
+
+        info!(
+            target: "unity",
+            "{} passing builds, {} failing builds, {} builds in progress, {} builds with misc statuses.",
+            passing_builds, failing_builds, in_progress_builds, other_status_builds
+        );
+    }
+    return StatusReport::with_entries(return_status, entries);
+}
+
+fn build_target_entry(
+    build_target: &str,
+    result: &Result<(UnityBuild, Headers), UnityRetrievalError>,
+) -> StatusEntry {
+    let build = match *result {
+        Ok((ref build, _)) => build,
+        Err(ref e) => {
+            return StatusEntry {
+                name: build_target.to_string(),
+                status: RemoteStatus::Unknown,
+                latency: None,
+                detail: Some(e.to_string()),
+                build: None,
             }
+        }
+    };
+    let (status, finished) = match build.build_status {
+        UnityBuildStatus::Success => (RemoteStatus::Passing, true),
+        UnityBuildStatus::Failure => (RemoteStatus::Failing, true),
+        UnityBuildStatus::Queued
+        | UnityBuildStatus::SentToBuilder
+        | UnityBuildStatus::Started
+        | UnityBuildStatus::Restarted => (RemoteStatus::InProgress, false),
+        UnityBuildStatus::Canceled => (RemoteStatus::Unknown, true),
+        UnityBuildStatus::Unknown => (RemoteStatus::Unknown, false),
+    };
+    StatusEntry {
+        name: build_target.to_string(),
+        status,
+        latency: None,
+        detail: match build.build_status {
+            UnityBuildStatus::Canceled => Some("Canceled".to_string()),
+            _ => None,
+        },
+        build: build.build.map(|number| BuildInfo {
+            number,
+            duration: build
+                .total_time_in_seconds
+                .filter(|_| finished)
+                .map(|x| Duration::from_millis((x * 1000.0) as u64)),
+            revision: build.last_built_revision.clone(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture_fetcher::FixtureFetcher;
+    use tokio_core::reactor::Core;
+
+    const BASE_URL: &str =
+        "https://build-api.cloud.unity3d.com/api/v1/orgs/example-org/projects/example-game/buildtargets";
 
-            info!(
-                "--Unity--: {} passing builds, {} failing builds, {} builds in progress, {} builds with misc statuses.",
-                passing_builds, failing_builds, in_progress_builds, other_status_builds
+    fn get_report(ios_fixture: Option<&str>, android_fixture: Option<&str>) -> StatusReport {
+        let mut fetcher = FixtureFetcher::new();
+        if let Some(fixture) = ios_fixture {
+            fetcher = fetcher.with_fixture(
+                &format!("{}/ios-development/builds?per_page=1", BASE_URL),
+                fixture,
+            );
+        }
+        if let Some(fixture) = android_fixture {
+            fetcher = fetcher.with_fixture(
+                &format!("{}/android-development/builds?per_page=1", BASE_URL),
+                fixture,
             );
         }
-        self.last_tick = Instant::now();
-        self.last_status = return_status;
-        return return_status;
+        let mut core = Core::new().unwrap();
+        let client = fetcher.into_client(&core.handle());
+        core.run(UnityCloudIntegration::new(0, 1, 2, "api-token", BASE_URL, client).get_report())
+            .unwrap()
+    }
+
+    #[test]
+    fn both_platforms_passing_is_passing() {
+        let report = get_report(
+            Some("unity/ios_success.json"),
+            Some("unity/android_success.json"),
+        );
+
+        assert_eq!(report.status, RemoteStatus::Passing);
+        assert!(report.error.is_none());
+    }
+
+    #[test]
+    fn one_platform_failing_is_failing() {
+        let report = get_report(
+            Some("unity/ios_success.json"),
+            Some("unity/android_failure.json"),
+        );
+
+        assert_eq!(report.status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn both_platforms_failing_is_failing() {
+        let report = get_report(
+            Some("unity/ios_failure.json"),
+            Some("unity/android_failure.json"),
+        );
+
+        assert_eq!(report.status, RemoteStatus::Failing);
+    }
+
+    #[test]
+    fn started_build_without_failures_is_in_progress() {
+        let report = get_report(
+            Some("unity/ios_started.json"),
+            Some("unity/android_success.json"),
+        );
+
+        assert_eq!(report.status, RemoteStatus::InProgress);
+    }
+
+    #[test]
+    fn canceled_builds_are_unknown() {
+        let report = get_report(
+            Some("unity/ios_canceled.json"),
+            Some("unity/android_canceled.json"),
+        );
+
+        assert_eq!(report.status, RemoteStatus::Unknown);
+    }
+
+    #[test]
+    fn platform_without_builds_is_unknown() {
+        let report = get_report(
+            Some("unity/ios_success.json"),
+            Some("unity/android_no_builds.json"),
+        );
+
+        assert_eq!(report.status, RemoteStatus::Unknown);
+        assert!(report.error.is_none());
+    }
+
+    #[test]
+    fn one_unreachable_platform_is_unknown() {
+        let report = get_report(Some("unity/ios_success.json"), None);
+
+        assert_eq!(report.status, RemoteStatus::Unknown);
+        assert!(report.error.is_none());
+    }
+
+    #[test]
+    fn unreachable_unity_cloud_is_an_error() {
+        let report = get_report(None, None);
+
+        assert_eq!(report.status, RemoteStatus::Unknown);
+        assert!(report.error.is_some());
     }
 }
@@ -1,10 +1,15 @@
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 pub struct UnityBuild {
     #[serde(rename = "buildStatus")]
     pub build_status: UnityBuildStatus,
+    pub build: Option<u64>,
+    #[serde(rename = "totalTimeInSeconds")]
+    pub total_time_in_seconds: Option<f64>,
+    #[serde(rename = "lastBuiltRevision")]
+    pub last_built_revision: Option<String>,
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum UnityBuildStatus {
     Queued,
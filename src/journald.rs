@@ -0,0 +1,123 @@
+use events::EVENTS_TARGET;
+use log::{Level, Record};
+use log4rs::append::Append;
+use serde_json;
+use serde_json::Value;
+use std::error::Error;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_IDENTIFIER: &str = "rusty_build_light";
+
+// Sends log records straight to journald over its native protocol, with the log target as a field of its own.
+// Events also get each of their JSON fields as a journal field, so e.g. every transition of the Jenkins light
+// can be found with `journalctl EVENT=transition INTEGRATION=jenkins`.
+#[derive(Debug)]
+pub struct JournaldAppender {
+    socket: UnixDatagram,
+}
+
+impl JournaldAppender {
+    pub fn new() -> io::Result<JournaldAppender> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(JournaldAppender { socket })
+    }
+}
+
+impl Append for JournaldAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.socket.send(&encode_fields(&record_fields(record)))?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+fn record_fields(record: &Record) -> Vec<(String, String)> {
+    let message = record.args().to_string();
+    let mut fields = vec![
+        ("PRIORITY".to_string(), priority(record.level()).to_string()),
+        ("SYSLOG_IDENTIFIER".to_string(), SYSLOG_IDENTIFIER.to_string()),
+        ("TARGET".to_string(), record.target().to_string()),
+    ];
+    if record.target() == EVENTS_TARGET {
+        if let Ok(Value::Object(event)) = serde_json::from_str(&message) {
+            for (key, value) in event {
+                let value = match value {
+                    Value::String(text) => text,
+                    Value::Null => continue,
+                    other => other.to_string(),
+                };
+                fields.push((key.to_uppercase(), value));
+            }
+        }
+    }
+    fields.push(("MESSAGE".to_string(), message));
+    fields
+}
+
+// Syslog priorities, which journald uses for its own.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// KEY=value lines, except that values with newlines in them have to be sent as KEY, a newline,
+// the value's length as a little-endian u64, then the value itself.
+fn encode_fields(fields: &[(String, String)]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    for (key, value) in fields {
+        datagram.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            datagram.push(b'=');
+        }
+        datagram.extend_from_slice(value.as_bytes());
+        datagram.push(b'\n');
+    }
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_line_values_are_length_prefixed() {
+        let datagram = encode_fields(&[
+            ("TARGET".to_string(), "jenkins".to_string()),
+            ("MESSAGE".to_string(), "a\nb".to_string()),
+        ]);
+
+        let mut expected = b"TARGET=jenkins\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(datagram, expected);
+    }
+
+    #[test]
+    fn event_fields_become_journal_fields() {
+        let fields = record_fields(
+            &Record::builder()
+                .target(EVENTS_TARGET)
+                .level(Level::Info)
+                .args(format_args!(
+                    "{}",
+                    r#"{"event":"transition","integration":"jenkins","from":null}"#
+                ))
+                .build(),
+        );
+
+        assert!(fields.contains(&("EVENT".to_string(), "transition".to_string())));
+        assert!(fields.contains(&("INTEGRATION".to_string(), "jenkins".to_string())));
+        assert!(!fields.iter().any(|x| x.0 == "FROM"));
+    }
+}
@@ -0,0 +1,235 @@
+use config_file::Config;
+use integrations::alertmanager_integration::AlertmanagerIntegration;
+use integrations::http_health_integration::{HealthCheck, HttpHealthIntegration};
+use integrations::jenkins_integration::{self, JenkinsIntegration};
+use integrations::remote_integration::RemoteIntegration;
+use integrations::unity_cloud_integration::UnityCloudIntegration;
+use network::HttpClient;
+use debounce::DebouncePolicy;
+use failure::Error;
+use retry::RetryPolicy;
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+
+// Everything about a light that isn't specific to its integration.
+#[derive(Clone, Copy)]
+pub struct Light {
+    // Shown in logs and output.
+    pub name: &'static str,
+    // Used for the light's [schedule.<key>], [retry.<key>], [http.<key>] and [debounce.<key>] config sections,
+    // and its webhook path.
+    pub key: &'static str,
+    pub retry_policy: RetryPolicy,
+    pub debounce_policy: DebouncePolicy,
+}
+
+// Every light's key, for checking the names of per-light config sections.
+pub const LIGHT_KEYS: &[&str] = &["jenkins", "unity", "health", "alertmanager"];
+
+// Something to do with each configured light: run it, poll it once, test its LED, etc.
+pub trait LightVisitor {
+    fn visit<T, F>(&mut self, light: Light, create_integration: F)
+    where
+        T: RemoteIntegration + 'static,
+        F: Fn() -> T + 'static;
+}
+
+// Every light's HTTP client. They're all created before any light is visited, so that a bad [http] section,
+// e.g. a certificate that can't be read, is reported before anything's been started or stopped.
+pub struct HttpClients {
+    jenkins: HttpClient,
+    unity: HttpClient,
+    // Only for the optional lights that are configured.
+    health: Option<HttpClient>,
+    alertmanager: Option<HttpClient>,
+}
+
+impl HttpClients {
+    // The clients make their requests on the given event loop.
+    pub fn new(config_values: &Config, handle: &Handle) -> Result<HttpClients, Error> {
+        Ok(HttpClients {
+            jenkins: create_http_client(config_values, "jenkins", handle)?,
+            unity: create_http_client(config_values, "unity", handle)?,
+            health: match config_values.health_check_led_pins {
+                Some(_) => Some(create_http_client(config_values, "health", handle)?),
+                None => None,
+            },
+            alertmanager: match config_values.alertmanager_base_url {
+                Some(_) => Some(create_http_client(config_values, "alertmanager", handle)?),
+                None => None,
+            },
+        })
+    }
+}
+
+// Calls the visitor once for every light that's configured, in a fixed order.
+pub fn visit_lights<V: LightVisitor>(
+    config_values: &Config,
+    http_clients: &HttpClients,
+    visitor: &mut V,
+) {
+    let jenkins_username = config_values.jenkins_username.clone();
+    let jenkins_password = config_values.jenkins_password.clone();
+    let jenkins_base_url = config_values.jenkins_base_url.clone();
+    let (jenkins_r, jenkins_g, jenkins_b) = (
+        config_values.jenkins_led_pins[0],
+        config_values.jenkins_led_pins[1],
+        config_values.jenkins_led_pins[2],
+    );
+    let jenkins_max_concurrent_fetches = config_values
+        .jenkins_max_concurrent_fetches
+        .unwrap_or(jenkins_integration::DEFAULT_MAX_CONCURRENT_FETCHES);
+    let jenkins_fetch_deadline = config_values
+        .jenkins_fetch_deadline_secs
+        .map(Duration::from_secs)
+        .unwrap_or(jenkins_integration::DEFAULT_FETCH_DEADLINE);
+    let jenkins_retry_policy = config_values.retry_policy("jenkins");
+    let jenkins_http_client = http_clients.jenkins.clone();
+    visitor.visit(
+        Light {
+            name: "Jenkins",
+            key: "jenkins",
+            retry_policy: jenkins_retry_policy,
+            debounce_policy: config_values.debounce_policy("jenkins"),
+        },
+        move || {
+            JenkinsIntegration::new(
+                jenkins_r,
+                jenkins_g,
+                jenkins_b,
+                &jenkins_username,
+                jenkins_password.expose(),
+                &jenkins_base_url,
+                jenkins_http_client.clone(),
+                jenkins_max_concurrent_fetches,
+                jenkins_fetch_deadline,
+            )
+        },
+    );
+
+    let unity_api_token = config_values.unity_cloud_api_token.clone();
+    let unity_base_url = config_values.unity_base_url.clone();
+    let (unity_r, unity_g, unity_b) = (
+        config_values.unity_led_pins[0],
+        config_values.unity_led_pins[1],
+        config_values.unity_led_pins[2],
+    );
+    let unity_retry_policy = config_values.retry_policy("unity");
+    let unity_http_client = http_clients.unity.clone();
+    visitor.visit(
+        Light {
+            name: "Unity Cloud",
+            key: "unity",
+            retry_policy: unity_retry_policy,
+            debounce_policy: config_values.debounce_policy("unity"),
+        },
+        move || {
+            UnityCloudIntegration::new(
+                unity_r,
+                unity_g,
+                unity_b,
+                unity_api_token.expose(),
+                &unity_base_url,
+                unity_http_client.clone(),
+            )
+        },
+    );
+
+    // The health check light is optional, and only runs if it has pins and something to check.
+    let health_checks: Option<Vec<HealthCheck>> = match config_values.health_check_led_pins {
+        Some(_) if config_values.health_check_endpoints.is_empty() => {
+            warn!(target: "health", "health_check_led_pins is set, but no health_check_endpoints are configured. Not starting health check light.");
+            None
+        }
+        Some(_) => config_values
+            .health_check_endpoints
+            .iter()
+            .map(HealthCheck::from_config)
+            .collect::<Result<Vec<HealthCheck>, _>>()
+            .map_err(|err| {
+                error!(target: "health", "Invalid health check configuration, not starting health check light. Error: {}", err);
+            })
+            .ok(),
+        None => None,
+    };
+    if let (Some(health_checks), Some(pins), Some(health_http_client)) = (
+        health_checks,
+        &config_values.health_check_led_pins,
+        &http_clients.health,
+    ) {
+        let (health_r, health_g, health_b) = (pins[0], pins[1], pins[2]);
+        let health_retry_policy = config_values.retry_policy("health");
+        let health_http_client = health_http_client.clone();
+        visitor.visit(
+            Light {
+                name: "Health Check",
+                key: "health",
+                retry_policy: health_retry_policy,
+                debounce_policy: config_values.debounce_policy("health"),
+            },
+            move || {
+                HttpHealthIntegration::new(
+                    health_r,
+                    health_g,
+                    health_b,
+                    health_checks.clone(),
+                    health_http_client.clone(),
+                )
+            },
+        );
+    }
+
+    // Likewise, the Alertmanager light only runs if it's been given somewhere to look.
+    match (
+        &config_values.alertmanager_base_url,
+        &config_values.alertmanager_led_pins,
+        &http_clients.alertmanager,
+    ) {
+        (Some(base_url), Some(pins), Some(alertmanager_http_client)) => {
+            let alertmanager_base_url = base_url.clone();
+            let alertmanager_matchers = config_values.alertmanager_matchers.clone();
+            let alertmanager_severity_label = config_values.alertmanager_severity_label.clone();
+            let (alertmanager_r, alertmanager_g, alertmanager_b) = (pins[0], pins[1], pins[2]);
+            let alertmanager_retry_policy = config_values.retry_policy("alertmanager");
+            let alertmanager_http_client = alertmanager_http_client.clone();
+            visitor.visit(
+                Light {
+                    name: "Alertmanager",
+                    key: "alertmanager",
+                    retry_policy: alertmanager_retry_policy,
+                    debounce_policy: config_values.debounce_policy("alertmanager"),
+                },
+                move || {
+                    AlertmanagerIntegration::new(
+                        alertmanager_r,
+                        alertmanager_g,
+                        alertmanager_b,
+                        &alertmanager_base_url,
+                        &alertmanager_matchers,
+                        &alertmanager_severity_label,
+                        alertmanager_http_client.clone(),
+                    )
+                },
+            );
+        }
+        (Some(_), None, _) => {
+            warn!(target: "alertmanager", "alertmanager_base_url is set, but alertmanager_led_pins is not. Not starting Alertmanager light.");
+        }
+        _ => {}
+    }
+}
+
+fn create_http_client(config: &Config, light: &str, handle: &Handle) -> Result<HttpClient, Error> {
+    HttpClient::new(
+        &config.http_client_config(light),
+        config.retry_policy(light),
+        handle,
+    )
+    .map_err(|err| {
+        format_err!(
+            "Failed to create HTTP client for {} from [http] config. Error: {}",
+            light,
+            err
+        )
+    })
+}
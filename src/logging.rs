@@ -0,0 +1,156 @@
+use config_file::LoggingConfig;
+use events::EVENTS_TARGET;
+use failure::Error;
+use journald::JournaldAppender;
+use log::LevelFilter;
+use log4rs;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::Handle;
+use std::str::FromStr;
+
+// Includes the target, so it's clear which integration each message came from.
+const LOG_PATTERN: &str = "{d} {l} {t} - {m}{n}";
+// Events are already JSON, timestamp included.
+const EVENT_PATTERN: &str = "{m}{n}";
+
+// Logs to the console until the config file has been read, and the [logging] section can be applied.
+pub fn init_default() -> Handle {
+    log4rs::init_config(build_config(&LoggingConfig::default()).unwrap())
+        .unwrap_or_else(|err| panic!("Unable to initialize logging: {}", err))
+}
+
+// Swaps in the logging settings from config.toml. If they can't be applied, the current ones are kept.
+pub fn apply_config(handle: &Handle, logging: &LoggingConfig) {
+    match build_config(logging) {
+        Ok(config) => handle.set_config(config),
+        Err(e) => error!("Unable to apply [logging] config, keeping the current one. {}", e),
+    }
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter, Error> {
+    LevelFilter::from_str(level).map_err(|_| {
+        format_err!(
+            "Unknown log level \"{}\". Expected one of off, error, warn, info, debug or trace.",
+            level
+        )
+    })
+}
+
+fn build_config(logging: &LoggingConfig) -> Result<Config, Error> {
+    let mut builder = Config::builder();
+    let mut appender_names = Vec::new();
+    // Events are kept out of the general log, and only go to the appenders meant for them.
+    let mut event_appender_names = Vec::new();
+
+    if logging.journald {
+        let journald_appender = JournaldAppender::new()
+            .map_err(|err| format_err!("Unable to connect to journald: {}", err))?;
+        builder = builder.appender(Appender::builder().build("journald", Box::new(journald_appender)));
+        appender_names.push("journald");
+        event_appender_names.push("journald");
+    } else {
+        builder = builder.appender(Appender::builder().build(
+            "stdout",
+            Box::new(
+                ConsoleAppender::builder()
+                    .encoder(Box::new(PatternEncoder::new(LOG_PATTERN)))
+                    .build(),
+            ),
+        ));
+        appender_names.push("stdout");
+    }
+
+    if let Some(ref file) = logging.file {
+        builder = builder.appender(Appender::builder().build(
+            "file",
+            Box::new(rolling_file_appender(file, LOG_PATTERN, logging)?),
+        ));
+        appender_names.push("file");
+    }
+
+    if let Some(ref events_file) = logging.events_file {
+        builder = builder.appender(Appender::builder().build(
+            "events",
+            Box::new(rolling_file_appender(events_file, EVENT_PATTERN, logging)?),
+        ));
+        event_appender_names.push("events");
+    }
+    let events_level = if event_appender_names.is_empty() {
+        LevelFilter::Off
+    } else {
+        LevelFilter::Info
+    };
+    builder = builder.logger(
+        Logger::builder()
+            .appenders(event_appender_names)
+            .additive(false)
+            .build(EVENTS_TARGET, events_level),
+    );
+
+    // Targets inherit the root's appenders, so these only change how much each one logs.
+    for (target, level) in logging.levels.iter().filter(|x| x.0 != EVENTS_TARGET) {
+        builder = builder.logger(Logger::builder().build(target.as_str(), parse_level(level)?));
+    }
+
+    builder
+        .build(
+            Root::builder()
+                .appenders(appender_names)
+                .build(parse_level(&logging.level)?),
+        )
+        .map_err(|err| format_err!("Invalid logging config: {}", err))
+}
+
+fn rolling_file_appender(
+    file: &str,
+    pattern: &str,
+    logging: &LoggingConfig,
+) -> Result<RollingFileAppender, Error> {
+    let roller = FixedWindowRoller::builder()
+        .base(1)
+        .build(&format!("{}.{{}}", file), logging.max_files)
+        .map_err(|err| format_err!("Unable to set up log rotation for {}: {}", file, err))?;
+    let policy = CompoundPolicy::new(
+        Box::new(SizeTrigger::new(logging.max_file_size_mb * 1024 * 1024)),
+        Box::new(roller),
+    );
+    RollingFileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(pattern)))
+        .build(file, Box::new(policy))
+        .map_err(|err| format_err!("Unable to open log file {}: {}", file, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_target_levels_are_applied() {
+        let mut logging = LoggingConfig::default();
+        logging.levels.insert("jenkins".to_string(), "debug".to_string());
+        let config = build_config(&logging).unwrap();
+
+        assert_eq!(config.root().level(), LevelFilter::Info);
+        let jenkins = config.loggers().iter().find(|x| x.name() == "jenkins");
+        assert_eq!(jenkins.map(|x| x.level()), Some(LevelFilter::Debug));
+        // Without anywhere for them to go, events are switched off.
+        let events = config.loggers().iter().find(|x| x.name() == EVENTS_TARGET);
+        assert_eq!(events.map(|x| x.level()), Some(LevelFilter::Off));
+    }
+
+    #[test]
+    fn unknown_levels_are_rejected() {
+        let logging = LoggingConfig {
+            level: "loud".to_string(),
+            ..LoggingConfig::default()
+        };
+
+        assert!(build_config(&logging).is_err());
+    }
+}
@@ -1,20 +1,79 @@
+mod cli;
+use cli::Command;
+
+mod debounce;
+use debounce::Debouncer;
+mod durations;
 mod errors;
+mod events;
+use events::Event;
 mod headers;
 mod network;
 
+#[cfg(test)]
+mod fixture_fetcher;
+
 mod integrations;
-use integrations::jenkins_integration::JenkinsIntegration;
-use integrations::remote_integration::RemoteIntegration;
-use integrations::unity_cloud_integration::UnityCloudIntegration;
+use integrations::remote_integration::{RemoteIntegration, ReportFuture};
+
+mod lights;
+use lights::{visit_lights, HttpClients, Light, LightVisitor};
+
+mod heartbeat;
+mod history;
+mod install;
+use heartbeat::{Heartbeat, POLL_STALL_TIMEOUT};
+use history::History;
+
+mod journald;
+mod logging;
 
 mod remote_status;
 use remote_status::RemoteStatus;
 
+mod retry;
+use retry::CircuitBreaker;
+
+mod scheduler;
+use scheduler::Scheduler;
+
+mod secrets;
+
+mod shutdown;
+use shutdown::StopSignal;
+
+mod state_file;
+use state_file::{SavedReport, StateFile};
+
+mod stats;
+use stats::FlakyJobs;
+
+mod status_report;
+use status_report::StatusReport;
+
+mod supervisor;
+use supervisor::{GiveUpAction, Supervisor};
+
+mod systemd;
+use systemd::Notifier;
+
 mod config_file;
 use config_file::*;
 
+mod config_validation;
+use config_validation::{check_config, Severity};
+
+mod config_watcher;
+use config_watcher::ConfigWatcher;
+
 mod pin;
-use pin::RgbLedLight;
+use pin::{RgbLedLight, POWER_ON_TEST_DURATION};
+
+mod webhooks;
+use webhooks::webhook_server::{WebhookLights, WebhookServer};
+
+#[macro_use]
+extern crate clap;
 
 #[macro_use]
 extern crate serde_derive;
@@ -32,229 +91,1034 @@ extern crate log4rs;
 #[macro_use]
 extern crate hyper;
 
+extern crate base64;
 extern crate chrono;
-extern crate ctrlc;
+extern crate futures;
+extern crate hex;
+extern crate hmac;
+extern crate native_tls;
+extern crate rand;
+extern crate regex;
 extern crate reqwest;
+#[macro_use]
+extern crate rusqlite;
 extern crate serde;
+extern crate serde_ignored;
 extern crate serde_json;
+extern crate serde_path_to_error;
+extern crate sha2;
+extern crate signal_hook;
+extern crate tokio_core;
 extern crate toml;
+extern crate url;
 extern crate wiringpi;
 
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Stream};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
-use std::panic;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+const STOP_CHECK_INTERVAL: u64 = 1000;
+// How long lights get to finish up on shutdown, before we exit without them.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+const STALL_GRACE_SECS: u64 = 10;
+// Long enough for a single glow on -> glow off cycle.
+const TURN_OFF_GLOW_MILLIS: u64 = 1400;
+// Slower than any live status's animation, so a restored status can be told apart.
+const RESTORED_GLOW_PERIOD: u64 = 4000;
+
+fn main() {
+    let args = cli::parse_args();
+    if args.command == Command::Version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    pin::set_dry_run(args.dry_run);
+
+    // Installing happens before there's a config file to load.
+    let install_result = match args.command {
+        Command::Install(ref options) => Some(install::install(options)),
+        Command::Uninstall(ref options) => Some(install::uninstall(options)),
+        _ => None,
+    };
+    if let Some(result) = install_result {
+        if let Err(e) = result {
+            exit_with_error(&e.to_string());
+        }
+        return;
+    }
 
-const SLEEP_DURATION: u64 = 10000;
+    let config_file_path = cli::find_file(args.config_path.as_ref(), "config.toml")
+        .unwrap_or_else(|err| exit_with_error(&err.to_string()));
+    if args.command == Command::CheckConfig {
+        process::exit(check_config_file(&config_file_path));
+    }
+
+    // Init logging. log4rs.yml takes precedence if there is one. Otherwise, log to the console until
+    // the config file's been read, then switch to its [logging] settings.
+    let log_handle = match cli::find_file(args.log_config_path.as_ref(), "log4rs.yml") {
+        Ok(log_config_file_path) => {
+            println!("Using log config file at: {:?}", log_config_file_path);
+            log4rs::init_file(log_config_file_path, Default::default()).unwrap();
+            None
+        }
+        Err(e) => {
+            if args.log_config_path.is_some() {
+                exit_with_error(&e.to_string());
+            }
+            Some(logging::init_default())
+        }
+    };
 
-lazy_static! {
-    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+    // Init config file
+    info!("Using config file at: {:?}", config_file_path);
+    let config_values = Config::from_file(&config_file_path).unwrap_or_else(|err| {
+        error!("Failed to load config file. {}", err);
+        process::exit(1);
+    });
+    if let Some(ref log_handle) = log_handle {
+        logging::apply_config(log_handle, &config_values.logging);
+    }
+    if args.dry_run {
+        info!("Dry run: polling as usual, but not touching the GPIO pins.");
+    }
+
+    match args.command {
+        Command::Status => {
+            // Stats are a bonus here, so a missing or unreadable history is fine.
+            let mut printer = StatusPrinter {
+                core: new_core(),
+                any_failed: false,
+                history: open_history(&config_values.history).ok(),
+            };
+            let http_clients = create_http_clients(&config_values, &printer.core.handle());
+            visit_lights(&config_values, &http_clients, &mut printer);
+            if printer.any_failed {
+                process::exit(1);
+            }
+        }
+        Command::TestLeds => {
+            // The lights don't run here, but creating their HTTP clients still needs an event loop.
+            let http_clients = create_http_clients(&config_values, &new_core().handle());
+            visit_lights(&config_values, &http_clients, &mut LedTester);
+        }
+        Command::History { ref light, count } => {
+            let light = light.as_ref().map(String::as_str);
+            let result = open_history(&config_values.history)
+                .and_then(|history| history.print_transitions(light, count));
+            if let Err(e) = result {
+                exit_with_error(&e.to_string());
+            }
+        }
+        Command::Stats { ref light } => {
+            let light = light.as_ref().map(String::as_str);
+            let result = open_history(&config_values.history)
+                .and_then(|history| stats::print_stats(&history, light));
+            if let Err(e) = result {
+                exit_with_error(&e.to_string());
+            }
+        }
+        _ => process::exit(run(config_values, &config_file_path, log_handle)),
+    }
 }
 
-fn main() {
-    let is_running_flag = Arc::new(AtomicBool::new(true));
-    let r = is_running_flag.clone();
-    ctrlc::set_handler(move || {
-        info!("Ctrl-C received, signaling child threads to stop...");
-        r.store(false, Ordering::SeqCst); // signal that main should stop.
-    }).unwrap_or_else(|_| {
-        error!("Error setting Ctrl-C handler.");
+// For the subcommands that read the history.
+fn open_history(config: &HistoryConfig) -> Result<History, failure::Error> {
+    if config.file.is_empty() {
+        return Err(format_err!("History is turned off. Set [history] file in config.toml to record it."));
+    }
+    // Don't create an empty database just to find nothing in it.
+    if !Path::new(&config.file).exists() {
+        return Err(format_err!("No history has been recorded in {} yet.", config.file));
+    }
+    History::open(Path::new(&config.file), config.retention_days)
+}
+
+// For failures before logging is set up, or in commands meant to be run by hand.
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+// Prints every problem in the config file, and returns the exit code: 1 if there were any errors.
+fn check_config_file(config_file_path: &Path) -> i32 {
+    let mut config_text = String::new();
+    if let Err(e) = File::open(config_file_path)
+        .and_then(|mut file| file.read_to_string(&mut config_text))
+    {
+        eprintln!("{}: Unable to read config file: {}", config_file_path.display(), e);
+        return 1;
+    }
+
+    let (_, problems) = check_config(&config_text);
+    for problem in &problems {
+        println!("{}: {}", config_file_path.display(), problem);
+    }
+    let error_count = problems
+        .iter()
+        .filter(|x| x.severity == Severity::Error)
+        .count();
+    if problems.is_empty() {
+        println!("{} is valid.", config_file_path.display());
+    } else {
+        println!(
+            "{}: {} error(s), {} warning(s).",
+            config_file_path.display(),
+            error_count,
+            problems.len() - error_count
+        );
+    }
+    if error_count > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+// Runs the build light until it's asked to stop, and returns the exit code: 1 if a light gave up and stopped it.
+fn run(config_values: Config, config_file_path: &Path, log_handle: Option<log4rs::Handle>) -> i32 {
+    let stop = StopSignal::new();
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    shutdown::handle_signals(stop.clone(), Arc::clone(&reload_requested)).unwrap_or_else(|err| {
+        error!("{}", err);
         panic!("Aborting...");
     });
 
-    let failure_count = Arc::new(Mutex::new(0u32));
-    match std::env::current_exe() {
-        Ok(path) => {
-            // Init logging
-            let mut log_config_file_path = std::path::PathBuf::from(path.parent().unwrap());
-            log_config_file_path.push("log4rs.yml");
-            println!("Looking for log config file at: {:?}", log_config_file_path);
-            log4rs::init_file(log_config_file_path, Default::default()).unwrap();
+    // Every light polls, waits and handles webhooks on this one event loop.
+    let state_file = Arc::new(StateFile::load(&config_values.state_file));
+    let history = Arc::new(History::from_config(&config_values.history));
+    let core = new_core();
+    let http_clients = create_http_clients(&config_values, &core.handle());
+    let mut runner = LightRunner {
+        config_values: Arc::new(config_values),
+        core,
+        state_file,
+        history,
+        stop: stop.clone(),
+        lights: HashMap::new(),
+        webhook_lights: Rc::new(RefCell::new(HashMap::new())),
+        last_polls: Rc::new(RefCell::new(HashMap::new())),
+        log_handle,
+    };
+    runner.start_lights(&http_clients);
+    let mut notifier = Notifier::from_env();
+    notifier.ready();
 
-            // Init config file
-            let mut config_file_path = std::path::PathBuf::from(path.parent().unwrap());
-            config_file_path.push("config.toml");
-            info!("Looking for config file at: {:?}", config_file_path);
-            let mut config_file = File::open(config_file_path).unwrap_or_else(|err| {
-                error!("No config.toml found in /src directory. Error: {}", err);
-                panic!("Aborting...");
-            });
-            let mut config_text = String::new();
-            config_file
-                .read_to_string(&mut config_text)
-                .unwrap_or_else(|err| {
-                    error!("Failed to read config file. Error: {}", err);
-                    panic!("Aborting...");
-                });
-
-            let config_values: Config =
-                toml::from_str(config_text.as_str()).unwrap_or_else(|err| {
-                    error!("Failed to deserialize config file. Error: {}", err);
-                    panic!("Aborting...");
-                });
-            let jenkins_username = config_values.jenkins_username;
-            let jenkins_password = config_values.jenkins_password;
-            let jenkins_base_url = config_values.jenkins_base_url;
-            let jenkins_running_flag = is_running_flag.clone();
-            let (jenkins_r, jenkins_g, jenkins_b) = (
-                config_values.jenkins_led_pins[0],
-                config_values.jenkins_led_pins[1],
-                config_values.jenkins_led_pins[2],
-            );
+    // Init webhook receiver. Statuses pushed to it go straight to the matching light.
+    if let Some(ref listen_address) = runner.config_values.webhook_listen_address {
+        let started = WebhookServer::new(
+            listen_address,
+            runner.config_values.webhook_secret.clone(),
+            Rc::clone(&runner.webhook_lights),
+        )
+        .and_then(|server| server.start(&runner.core.handle()));
+        if let Err(e) = started {
+            error!(target: "webhook", "Unable to start webhook server. Error: {}", e);
+        }
+    }
 
-            let unity_api_token = config_values.unity_cloud_api_token;
-            let unity_base_url = config_values.unity_base_url;
-            let unity_running_flag = is_running_flag.clone();
-            let (unity_r, unity_g, unity_b) = (
-                config_values.unity_led_pins[0],
-                config_values.unity_led_pins[1],
-                config_values.unity_led_pins[2],
-            );
+    // Apply changes to the config file as they happen, until we're asked to stop.
+    let mut config_watcher = ConfigWatcher::new(config_file_path, reload_requested);
+    let mut stalled_lights = Vec::new();
+    while !stop.is_stopped() {
+        runner.run_for(Duration::from_millis(STOP_CHECK_INTERVAL));
 
-            let allowed_total_failures = config_values.allowed_failures;
-
-            // Init main threads
-            let jenkins_counter = Arc::clone(&failure_count);
-            let jenkins_handle = thread::spawn(move || {
-                run_and_recover(
-                    "Jenkins",
-                    allowed_total_failures,
-                    jenkins_counter,
-                    jenkins_running_flag.clone(),
-                    || {
-                        let jenkins_integration = JenkinsIntegration::new(
-                            jenkins_r,
-                            jenkins_g,
-                            jenkins_b,
-                            &jenkins_username,
-                            &jenkins_password,
-                            &jenkins_base_url,
-                        );
-                        start_thread(jenkins_integration, jenkins_running_flag.clone())
-                    },
-                )
-            });
+        if config_watcher.should_reload() {
+            runner.reload(config_file_path);
+        }
 
-            let unity_cloud_counter = Arc::clone(&failure_count);
-            let unity_cloud_handle = thread::spawn(move || {
-                run_and_recover(
-                    "Unity Cloud",
-                    allowed_total_failures,
-                    unity_cloud_counter,
-                    unity_running_flag.clone(),
-                    || {
-                        let unity_cloud_integration = UnityCloudIntegration::new(
-                            unity_r,
-                            unity_g,
-                            unity_b,
-                            &unity_api_token,
-                            &unity_base_url,
-                        );
-                        start_thread(unity_cloud_integration, unity_running_flag.clone())
-                    },
-                )
-            });
+        // Stop pinging the watchdog while any light is stuck, so that systemd restarts us. That includes the event
+        // loop itself getting stuck, since this only runs between turns of it.
+        let now_stalled = runner.stalled_lights();
+        for name in now_stalled.iter().filter(|x| !stalled_lights.contains(*x)) {
+            error!("The {} light has stopped making progress.", name);
+        }
+        stalled_lights = now_stalled;
+        notifier.update(&runner.status_summary(), stalled_lights.is_empty());
+    }
 
-            // Wait for all main threads to finish.
-            jenkins_handle
-                .join()
-                .expect("The Jenkins thread terminated abnormally.");
-            unity_cloud_handle
-                .join()
-                .expect("The Unity Cloud build thread terminated abnormally.");
+    // Wait for all lights to finish, then make sure every LED is left in its offline colour.
+    notifier.stopping();
+    runner.stop_all();
+    pin::freeze_all(runner.config_values.offline_color());
+    runner.history.flush();
 
-            info!("All threads terminated. Terminating program...");
+    info!("All lights stopped. Terminating program...");
+    if stop.gave_up() {
+        1
+    } else {
+        0
+    }
+}
+
+fn new_core() -> Core {
+    Core::new().unwrap_or_else(|err| exit_with_error(&format!("Unable to start the event loop: {}", err)))
+}
+
+fn create_http_clients(config_values: &Config, handle: &Handle) -> HttpClients {
+    HttpClients::new(config_values, handle).unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1);
+    })
+}
+
+// Runs each light on the event loop, polling its integration and driving its LED until stopped.
+// Lights can also be stopped and restarted one at a time, when the config changes.
+struct LightRunner {
+    config_values: Arc<Config>,
+    // Runs the lights, and the webhook server if there is one.
+    core: Core,
+    // Stops the whole build light. Lights use it to force a stop when they run out of restarts.
+    stop: StopSignal,
+    lights: HashMap<&'static str, RunningLight>,
+    webhook_lights: WebhookLights,
+    // Only read at startup, so changes to state_file and [history] are applied on restart.
+    state_file: Arc<StateFile>,
+    history: Arc<History>,
+    // When each light last polled. Kept after a light stops, so that restarting it with new settings doesn't
+    // poll any sooner than its min_spacing allows.
+    last_polls: Rc<RefCell<HashMap<&'static str, Instant>>>,
+    // Set if logging comes from the [logging] section, rather than log4rs.yml.
+    log_handle: Option<log4rs::Handle>,
+}
+
+struct RunningLight {
+    name: &'static str,
+    // Stops just this light.
+    stop: oneshot::Sender<()>,
+    heartbeat: Heartbeat,
+    // Set once the light has finished turning its LED off.
+    stopped: Rc<Cell<bool>>,
+    // Its LED's red, green and blue pins.
+    pins: [u16; 3],
+}
+
+impl LightRunner {
+    // Starts every configured light that isn't already running.
+    fn start_lights(&mut self, http_clients: &HttpClients) {
+        let config_values = Arc::clone(&self.config_values);
+        visit_lights(&config_values, http_clients, self);
+    }
+
+    // Runs the event loop for the given time.
+    fn run_for(&mut self, duration: Duration) {
+        let until = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= until {
+                return;
+            }
+            self.core.turn(Some(until - now));
         }
-        Err(e) => {
-            error!(
-                "Failed to obtain current executable directory. Details: {}. Exiting...",
-                e
-            );
+    }
+
+    fn stalled_lights(&self) -> Vec<&'static str> {
+        let mut stalled: Vec<&'static str> = self
+            .lights
+            .values()
+            .filter(|x| x.heartbeat.is_stalled())
+            .map(|x| x.name)
+            .collect();
+        stalled.sort();
+        stalled
+    }
+
+    // What each light is showing, e.g. "Jenkins: Passing, Unity Cloud: Failing".
+    fn status_summary(&self) -> String {
+        let mut statuses: Vec<String> = self
+            .lights
+            .values()
+            .map(|x| {
+                if x.heartbeat.is_disabled() {
+                    return format!("{}: disabled", x.name);
+                }
+                match (x.heartbeat.is_stalled(), x.heartbeat.status()) {
+                    (true, _) => format!("{}: stalled", x.name),
+                    (false, Some(status)) => format!("{}: {:?}", x.name, status),
+                    (false, None) => format!("{}: starting", x.name),
+                }
+            })
+            .collect();
+        statuses.sort();
+        statuses.join(", ")
+    }
+
+    fn stop_all(&mut self) {
+        let keys: Vec<&'static str> = self.lights.keys().cloned().collect();
+        self.stop_lights(&keys);
+    }
+
+    // Stops the given lights, and runs the event loop for up to SHUTDOWN_TIMEOUT_SECS while they turn their LEDs off.
+    // They're all signalled before waiting on any, so they shut down together. Any poll that's under way is abandoned.
+    fn stop_lights(&mut self, keys: &[&'static str]) {
+        let mut stopping = Vec::new();
+        for key in keys {
+            if let Some(light) = self.lights.remove(key) {
+                self.webhook_lights.borrow_mut().remove(*key);
+                // The light's already finished if it's gone, e.g. after forcing a stop.
+                let _ = light.stop.send(());
+                stopping.push((light.name, light.stopped));
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
+        while Instant::now() < deadline && stopping.iter().any(|x| !x.1.get()) {
+            self.core.turn(Some(Duration::from_millis(50)));
+        }
+        for (name, stopped) in stopping {
+            if !stopped.get() {
+                warn!("The {} light didn't stop in time. Leaving it behind.", name);
+            }
         }
     }
+
+    // Restarts only the lights the new config affects, and starts any it adds. Lights that aren't affected
+    // carry on as they were. If the new config is invalid, everything carries on with the old one.
+    fn reload(&mut self, config_file_path: &Path) {
+        let new_config_values = match Config::from_file(config_file_path) {
+            Ok(config_values) => config_values,
+            Err(e) => {
+                error!("Not applying changes to the config file, keeping the current config. {}", e);
+                return;
+            }
+        };
+        // Validation only checks that certificate files exist, so e.g. a corrupt one only turns up here.
+        let http_clients = match HttpClients::new(&new_config_values, &self.core.handle()) {
+            Ok(http_clients) => http_clients,
+            Err(e) => {
+                error!("Not applying changes to the config file, keeping the current config. {}", e);
+                return;
+            }
+        };
+
+        let changed_lights: Vec<&'static str> = self
+            .lights
+            .keys()
+            .cloned()
+            .filter(|key| new_config_values.light_changed(&self.config_values, key))
+            .collect();
+        for key in &changed_lights {
+            info!("Settings for the {} light have changed. Stopping it...", key);
+        }
+        let old_pins: Vec<[u16; 3]> = changed_lights
+            .iter()
+            .filter_map(|key| self.lights.get(key))
+            .map(|x| x.pins)
+            .collect();
+        self.stop_lights(&changed_lights);
+        // Lights that are started again register their new pins, so shutting down only resets what the config uses.
+        for pins in old_pins {
+            pin::release(pins);
+        }
+
+        if new_config_values.webhook_listen_address != self.config_values.webhook_listen_address
+            || new_config_values.webhook_secret != self.config_values.webhook_secret
+        {
+            warn!(target: "webhook", "Webhook settings have changed. They will only be applied on restart.");
+        }
+
+        if let Some(ref log_handle) = self.log_handle {
+            if new_config_values.logging != self.config_values.logging {
+                logging::apply_config(log_handle, &new_config_values.logging);
+            }
+        }
+
+        self.config_values = Arc::new(new_config_values);
+        self.start_lights(&http_clients);
+        info!("Config file reloaded.");
+    }
+}
+
+impl LightVisitor for LightRunner {
+    fn visit<T, F>(&mut self, light: Light, create_integration: F)
+    where
+        T: RemoteIntegration + 'static,
+        F: Fn() -> T + 'static,
+    {
+        if self.lights.contains_key(light.key) {
+            return;
+        }
+
+        info!("Starting the {} light...", light.name);
+        let (push_tx, push_rx) = mpsc::unbounded();
+        self.webhook_lights
+            .borrow_mut()
+            .insert(light.key.to_string(), push_tx);
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let heartbeat = Heartbeat::new();
+        let handle = self.core.handle();
+
+        let integration = create_integration();
+        let pins = [
+            integration.get_red_id(),
+            integration.get_green_id(),
+            integration.get_blue_id(),
+        ];
+        let mut led = RgbLedLight::new(pins[0], pins[1], pins[2]);
+        led.run_power_on_test();
+        let task = LightTask {
+            light,
+            create_integration,
+            integration: Some(integration),
+            led: StatusLed::new(
+                light.key,
+                led,
+                Arc::clone(&self.state_file),
+                Arc::clone(&self.history),
+            ),
+            state: LightState::Starting(timer(&handle, POWER_ON_TEST_DURATION)),
+            stop: stop_rx,
+            pushed_statuses: push_rx,
+            heartbeat: heartbeat.clone(),
+            global_stop: self.stop.clone(),
+            scheduler: Scheduler::new(
+                self.config_values
+                    .poll_schedule(light.key, T::DEFAULT_SCHEDULE),
+                self.last_polls.borrow().get(light.key).cloned(),
+            ),
+            circuit_breaker: CircuitBreaker::new(light.name, light.retry_policy),
+            supervisor: Supervisor::new(self.config_values.restart_policy(light.key)),
+            debouncer: Debouncer::new(light.debounce_policy),
+            flaky_jobs: FlakyJobs::default(),
+            history: Arc::clone(&self.history),
+            handle: handle.clone(),
+        };
+        let stopped = Rc::new(Cell::new(false));
+        let set_stopped = Rc::clone(&stopped);
+        let last_polls = Rc::clone(&self.last_polls);
+        handle.spawn(task.then(move |last_poll| {
+            if let Ok(Some(last_poll)) = last_poll {
+                last_polls.borrow_mut().insert(light.key, last_poll);
+            }
+            set_stopped.set(true);
+            Ok(())
+        }));
+        self.lights.insert(
+            light.key,
+            RunningLight {
+                name: light.name,
+                stop: stop_tx,
+                heartbeat,
+                stopped,
+                pins,
+            },
+        );
+    }
 }
 
-fn run_and_recover<F: Fn() -> R + panic::UnwindSafe + panic::RefUnwindSafe, R>(
-    thread_name: &str,
-    allowed_total_failures: u32,
-    failure_counter: Arc<Mutex<u32>>,
-    running_flag: Arc<AtomicBool>,
-    func: F,
-) -> thread::Result<R>
+// Polls each light once, and prints what it found.
+struct StatusPrinter {
+    core: Core,
+    any_failed: bool,
+    history: Option<History>,
+}
+
+impl LightVisitor for StatusPrinter {
+    fn visit<T, F>(&mut self, light: Light, create_integration: F)
+    where
+        T: RemoteIntegration + 'static,
+        F: Fn() -> T + 'static,
+    {
+        let report = match self.core.run(create_integration().get_report()) {
+            Ok(report) => report,
+            Err(()) => return,
+        };
+        println!("{}: {:?}", light.name, report.status);
+        let stats = self.history.as_ref().and_then(|history| {
+            stats::integration_stats(history, light.key, chrono::Utc::now().timestamp())
+                .map_err(|err| warn!("Unable to work out stats for {}. Error: {}", light.name, err))
+                .ok()
+        });
+        if let Some(ref stats) = stats {
+            println!("    {}", stats.summary());
+        }
+        for entry in &report.entries {
+            let mut notes: Vec<&str> = entry.detail.iter().map(String::as_str).collect();
+            if stats.as_ref().map_or(false, |x| x.is_flaky(&entry.name)) {
+                notes.push("flaky");
+            }
+            if notes.is_empty() {
+                println!("    {}: {:?}", entry.name, entry.status);
+            } else {
+                println!("    {}: {:?} ({})", entry.name, entry.status, notes.join(", "));
+            }
+        }
+        if let Some(ref error) = report.error {
+            println!("    Error: {}", error);
+            self.any_failed = true;
+        }
+    }
+}
+
+// Runs the power-on test on each light in turn.
+struct LedTester;
+
+impl LightVisitor for LedTester {
+    fn visit<T, F>(&mut self, light: Light, create_integration: F)
+    where
+        T: RemoteIntegration + 'static,
+        F: Fn() -> T + 'static,
+    {
+        let remote = create_integration();
+        info!("Testing the {} light...", light.name);
+        let mut led = RgbLedLight::new(
+            remote.get_red_id(),
+            remote.get_green_id(),
+            remote.get_blue_id(),
+        );
+        led.run_power_on_test();
+        // Let the final glow finish before moving on.
+        thread::sleep(POWER_ON_TEST_DURATION + Duration::from_millis(TURN_OFF_GLOW_MILLIS));
+        led.turn_led_off();
+    }
+}
+
+// A timer on the event loop. Only fails if the event loop's gone, and nothing runs without it.
+fn timer(handle: &Handle, duration: Duration) -> Timeout {
+    Timeout::new(duration, handle).expect("The event loop has shut down.")
+}
+
+// Whether a timer's gone off. Errors only come from the event loop shutting down, so count as going off too.
+fn timer_done(timer: &mut Timeout) -> bool {
+    match timer.poll() {
+        Ok(Async::NotReady) => false,
+        _ => true,
+    }
+}
+
+// Runs a light on the event loop: polls its integration when the scheduler says to, or when a webhook says
+// something's changed, and drives its LED, until it's stopped. Nothing it does blocks, so a slow server
+// only ever holds up its own light.
+struct LightTask<T, F> {
+    light: Light,
+    // Creates the integration again after it's panicked.
+    create_integration: F,
+    // None after a panic, until the next poll creates a new one.
+    integration: Option<T>,
+    led: StatusLed,
+    state: LightState,
+    // Dropping the sender stops the light too.
+    stop: oneshot::Receiver<()>,
+    pushed_statuses: mpsc::UnboundedReceiver<RemoteStatus>,
+    heartbeat: Heartbeat,
+    global_stop: StopSignal,
+    // These outlive any one run of the integration, so a restarted integration picks up where the last one left off.
+    scheduler: Scheduler,
+    circuit_breaker: CircuitBreaker,
+    supervisor: Supervisor,
+    debouncer: Debouncer,
+    flaky_jobs: FlakyJobs,
+    history: Arc<History>,
+    handle: Handle,
+}
+
+enum LightState {
+    // Running the power-on test.
+    Starting(Timeout),
+    // Waiting for the next poll to be due.
+    Waiting(Timeout),
+    Polling {
+        started: Instant,
+        report: ReportFuture,
+    },
+    // Waiting to restart after a panic.
+    Restarting(Timeout),
+    // Given up on after running out of restarts. Waits to be stopped.
+    Disabled,
+    // Glowing white one last time before turning the LED off.
+    Stopping(Timeout),
+    Stopped,
+}
+
+impl LightState {
+    fn is_stopping(&self) -> bool {
+        match *self {
+            LightState::Stopping(_) | LightState::Stopped => true,
+            _ => false,
+        }
+    }
+}
+
+impl<T, F> Future for LightTask<T, F>
 where
-    R: std::fmt::Debug,
+    T: RemoteIntegration,
+    F: Fn() -> T,
 {
-    loop {
-        if let Ok(counter) = failure_counter.lock() {
-            if *counter > allowed_total_failures {
-                running_flag.store(false, Ordering::SeqCst); // Force a global stop
-                return Result::Err(Box::new(format!(
-                    "Failure count for {} exceeded, forcing stop.",
-                    thread_name
-                )));
+    // When the light last polled, if it ever did.
+    type Item = Option<Instant>;
+    type Error = ();
+
+    // A panic, most likely in the integration, restarts the light instead of taking down the event loop
+    // and every other light along with it. HTTP clients share a connection pool that isn't marked unwind safe.
+    // An integration that panics is thrown away and recreated, and the pool copes with requests that were
+    // abandoned partway through.
+    fn poll(&mut self) -> Poll<Option<Instant>, ()> {
+        loop {
+            match panic::catch_unwind(AssertUnwindSafe(|| self.advance())) {
+                Ok(result) => return result,
+                Err(panic) => self.recover(&*panic),
+            }
+        }
+    }
+}
+
+impl<T, F> LightTask<T, F>
+where
+    T: RemoteIntegration,
+    F: Fn() -> T,
+{
+    // Moves on through as many states as are ready, and returns once it's waiting on something.
+    fn advance(&mut self) -> Poll<Option<Instant>, ()> {
+        if !self.state.is_stopping() {
+            match self.stop.poll() {
+                Ok(Async::NotReady) => {}
+                _ => self.stop_light(),
+            }
+        }
+
+        loop {
+            match self.state {
+                LightState::Starting(ref mut timer) => {
+                    if !timer_done(timer) {
+                        return Ok(Async::NotReady);
+                    }
+                    if let Some(saved) = self.led.state_file.restored(self.light.key) {
+                        self.led.show_restored(&saved);
+                    }
+                    self.wait_for_next_poll();
+                }
+                LightState::Waiting(ref mut until_due) => {
+                    if timer_done(until_due) {
+                        self.start_poll();
+                    } else {
+                        self.show_pushed_statuses();
+                        return Ok(Async::NotReady);
+                    }
+                }
+                LightState::Polling {
+                    started,
+                    ref mut report,
+                } => {
+                    match report.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(report)) => {
+                            self.show_report(report, started.elapsed())
+                        }
+                        // Problems are reported in the report, so there's nothing to show.
+                        Err(()) => {}
+                    }
+                    self.wait_for_next_poll();
+                }
+                LightState::Restarting(ref mut timer) => {
+                    if !timer_done(timer) {
+                        return Ok(Async::NotReady);
+                    }
+                    info!("Restarting the {} light...", self.light.name);
+                    self.wait_for_next_poll();
+                }
+                LightState::Disabled => return Ok(Async::NotReady),
+                LightState::Stopping(ref mut timer) => {
+                    if !timer_done(timer) {
+                        return Ok(Async::NotReady);
+                    }
+                    self.led.finish_turning_off();
+                    self.state = LightState::Stopped;
+                }
+                LightState::Stopped => return Ok(Async::Ready(self.scheduler.last_poll())),
             }
         }
-        let thread_result = panic::catch_unwind(|| func());
-        if thread_result.is_ok() {
-            info!("Thread {} terminated gracefully. Ending...", thread_name);
-            return thread_result;
-        } else {
-            error!(
-                "Thread {} terminated abnormally. Details: {:?}. Restarting...",
-                thread_name, thread_result
+    }
+
+    // Waits until the scheduler says the next poll is due. The LED keeps animating the last status in the meantime.
+    fn wait_for_next_poll(&mut self) {
+        let until_next_poll = self.scheduler.time_until_next_poll(Instant::now());
+        // Waiting counts as progress. Allow some slack for a busy system.
+        self.heartbeat
+            .beat(until_next_poll + Duration::from_secs(STALL_GRACE_SECS));
+        self.state = LightState::Waiting(timer(&self.handle, until_next_poll));
+    }
+
+    fn start_poll(&mut self) {
+        self.scheduler.record_poll(Instant::now());
+        if !self.circuit_breaker.allow_request() {
+            info!("Circuit for {} is open. Skipping poll.", self.light.name);
+            events::emit(&Event::PollSkipped {
+                integration: self.light.key,
+                circuit_state: self.circuit_breaker.state(),
+            });
+            self.wait_for_next_poll();
+            return;
+        }
+
+        self.heartbeat.beat(POLL_STALL_TIMEOUT);
+        let create_integration = &self.create_integration;
+        let report = self
+            .integration
+            .get_or_insert_with(|| create_integration())
+            .get_report();
+        self.state = LightState::Polling {
+            started: Instant::now(),
+            report,
+        };
+    }
+
+    fn show_report(&mut self, mut report: StatusReport, poll_duration: Duration) {
+        match report.error {
+            Some(_) => self.circuit_breaker.record_failure(),
+            None => self.circuit_breaker.record_success(),
+        }
+        report.circuit_state = self.circuit_breaker.state();
+        if let Some(ref error) = report.error {
+            warn!(
+                "{} poll failed, circuit is {:?}. Error: {}",
+                self.light.name, report.circuit_state, error
             );
-            if let Ok(mut counter) = failure_counter.lock() {
-                *counter += 1;
-            } else {
-                error!("Attempted to increment failure count for thread {}, but failed to acquire a lock on the counter.", thread_name);
+        }
+        events::emit(&Event::poll(self.light.key, &report, poll_duration));
+        self.history.record_results(self.light.key, &report);
+        self.history.record_polled_status(self.light.key, report.status);
+        filter_failures(
+            self.light,
+            &mut report,
+            &mut self.debouncer,
+            self.led.status,
+            &mut self.flaky_jobs,
+            &self.history,
+        );
+        self.led.show(&report, "poll");
+        self.heartbeat.set_status(report.status);
+    }
+
+    // Shows any statuses pushed via webhook since last time, straight away. A pushed status is only about one job
+    // or pipeline, so the next scheduled poll puts it right if others the light's watching say otherwise.
+    fn show_pushed_statuses(&mut self) {
+        // Nothing more will arrive once the sender's gone, e.g. because webhooks aren't set up.
+        while let Ok(Async::Ready(Some(status))) = self.pushed_statuses.poll() {
+            info!("{} was sent {:?} by webhook.", self.light.name, status);
+            let mut report = StatusReport::new(status);
+            filter_failures(
+                self.light,
+                &mut report,
+                &mut self.debouncer,
+                self.led.status,
+                &mut self.flaky_jobs,
+                &self.history,
+            );
+            self.led.show(&report, "webhook");
+            self.heartbeat.set_status(report.status);
+        }
+    }
+
+    // Abandons any poll that's under way.
+    fn stop_light(&mut self) {
+        self.integration = None;
+        self.led.start_turning_off();
+        self.state = LightState::Stopping(timer(&self.handle, Duration::from_millis(TURN_OFF_GLOW_MILLIS)));
+    }
+
+    // Restarts the light after a delay, until the supervisor gives up on it. Giving up either disables the light,
+    // or stops every light, depending on the restart policy.
+    fn recover(&mut self, panic: &(dyn Any + Send)) {
+        error!(
+            "The {} light failed. Details: {}.",
+            self.light.name,
+            panic_message(panic)
+        );
+        self.integration = None;
+        if self.state.is_stopping() {
+            self.state = LightState::Stopped;
+            return;
+        }
+
+        let policy = *self.supervisor.policy();
+        match self.supervisor.record_failure(Instant::now()) {
+            Some(delay) => {
+                info!("Restarting the {} light in {:?}...", self.light.name, delay);
+                self.heartbeat
+                    .beat(delay + Duration::from_secs(STALL_GRACE_SECS));
+                self.state = LightState::Restarting(timer(&self.handle, delay));
+            }
+            None if policy.on_give_up == GiveUpAction::Stop => {
+                error!(
+                    "The {} light failed more than {} times in {:?}. Stopping the build light.",
+                    self.light.name, policy.max_restarts, policy.window
+                );
+                self.global_stop.give_up(); // Force a global stop, and exit with an error
+                self.heartbeat.set_disabled();
+                self.state = LightState::Disabled;
+            }
+            None => {
+                error!(
+                    "The {} light failed more than {} times in {:?}. Disabling it.",
+                    self.light.name, policy.max_restarts, policy.window
+                );
+                self.show_disabled();
             }
         }
     }
+
+    // Leaves a light that's been given up on blinking teal, until it's stopped.
+    fn show_disabled(&mut self) {
+        self.heartbeat.set_disabled();
+        self.led.led.blink_led(RgbLedLight::TEAL);
+        events::emit(&Event::LedChanged {
+            integration: self.light.key,
+            led: "blinking teal",
+        });
+        self.state = LightState::Disabled;
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown panic", String::as_str),
+    }
 }
 
-fn start_thread<T: RemoteIntegration>(mut remote: T, running_flag: Arc<AtomicBool>) {
-    let mut led = RgbLedLight::new(
-        remote.get_red_id(),
-        remote.get_green_id(),
-        remote.get_blue_id(),
-    );
-    run_power_on_test(&mut led);
-    loop {
-        match remote.get_status() {
-            RemoteStatus::Unknown => led.glow_led(RgbLedLight::PURPLE),
-            RemoteStatus::InProgress => led.glow_led_period(RgbLedLight::GREEN, 700),
-            RemoteStatus::Passing => led.set_led_rgb_values(RgbLedLight::GREEN),
-            RemoteStatus::Failing => led.blink_led(RgbLedLight::RED),
-        }
-
-        if !running_flag.load(Ordering::SeqCst) {
-            led.glow_led(RgbLedLight::WHITE);
-            thread::sleep(Duration::from_millis(1400)); // Should be long enough for a single "glow on -> glow off" cycle
-            led.turn_led_off();
+// Applies the light's [debounce.<light>] settings to a poll's report, after it's been recorded in the history as is.
+fn filter_failures(
+    light: Light,
+    report: &mut StatusReport,
+    debouncer: &mut Debouncer,
+    showing: Option<RemoteStatus>,
+    flaky_jobs: &mut FlakyJobs,
+    history: &History,
+) {
+    if light.debounce_policy.flaky_jobs_warn && report.status == RemoteStatus::Failing {
+        match flaky_jobs.refresh(history, light.key, Instant::now()) {
+            Ok(()) => debounce::warn_for_flaky_jobs(report, |job| flaky_jobs.is_flaky(job)),
+            Err(e) => debug!("Unable to check {} for flaky jobs. {}", light.name, e),
+        }
+    }
+    let status = debouncer.filter(report.status, showing, Instant::now());
+    if status != report.status {
+        info!(
+            "{} is failing, but not for long enough to show yet. Still showing {:?}.",
+            light.name, status
+        );
+        report.status = status;
+    }
+}
+
+// A light's LED, along with the status it's showing, so that changes can be logged as events, saved for the
+// next run and recorded in the history.
+struct StatusLed {
+    integration: &'static str,
+    led: RgbLedLight,
+    status: Option<RemoteStatus>,
+    state_file: Arc<StateFile>,
+    history: Arc<History>,
+}
+
+impl StatusLed {
+    fn new(
+        integration: &'static str,
+        led: RgbLedLight,
+        state_file: Arc<StateFile>,
+        history: Arc<History>,
+    ) -> StatusLed {
+        StatusLed {
+            integration,
+            led,
+            status: None,
+            state_file,
+            history,
+        }
+    }
+
+    // Source is where the report came from, i.e. "poll" or "webhook".
+    fn show(&mut self, report: &StatusReport, source: &str) {
+        let status = report.status;
+        let description = match status {
+            RemoteStatus::Unknown => {
+                self.led.glow_led(RgbLedLight::PURPLE);
+                "glowing purple"
+            }
+            RemoteStatus::InProgress => {
+                self.led.glow_led_period(RgbLedLight::GREEN, 700);
+                "glowing green"
+            }
+            RemoteStatus::Passing => {
+                self.led.set_led_rgb_values(RgbLedLight::GREEN);
+                "solid green"
+            }
+            RemoteStatus::Warning => {
+                self.led.set_led_rgb_values(RgbLedLight::YELLOW);
+                "solid yellow"
+            }
+            RemoteStatus::Failing => {
+                self.led.blink_led(RgbLedLight::RED);
+                "blinking red"
+            }
+        };
+        if self.status == Some(status) {
             return;
         }
 
-        thread::sleep(Duration::from_millis(SLEEP_DURATION));
+        events::emit(&Event::Transition {
+            integration: self.integration,
+            from: self.status,
+            to: status,
+            source,
+        });
+        events::emit(&Event::LedChanged {
+            integration: self.integration,
+            led: description,
+        });
+        self.state_file.save(self.integration, report);
+        self.history
+            .record_transition(self.integration, self.status, status, source);
+        self.status = Some(status);
     }
-}
 
-fn run_power_on_test(test_led: &mut pin::RgbLedLight) {
-    test_led.turn_led_off();
-    thread::sleep(Duration::from_millis(1000));
-    test_led.set_led_rgb_values(RgbLedLight::RED);
-    thread::sleep(Duration::from_millis(250));
-    test_led.set_led_rgb_values(RgbLedLight::GREEN);
-    thread::sleep(Duration::from_millis(250));
-    test_led.set_led_rgb_values(RgbLedLight::BLUE);
-    thread::sleep(Duration::from_millis(250));
-    test_led.turn_led_off();
-    thread::sleep(Duration::from_millis(250));
-    test_led.set_led_rgb_values(RgbLedLight::WHITE);
-    thread::sleep(Duration::from_millis(250));
-    test_led.turn_led_off();
-
-    test_led.glow_led(RgbLedLight::PURPLE);
+    // Shows the status saved by an earlier run as a slow glow, until the first poll or webhook replaces it.
+    // Not a status of its own, so the first real status always counts as a change.
+    fn show_restored(&mut self, saved: &SavedReport) {
+        let (color, description) = match saved.status {
+            // Already glowing purple after the power-on test.
+            RemoteStatus::Unknown => return,
+            RemoteStatus::InProgress | RemoteStatus::Passing => {
+                (RgbLedLight::GREEN, "slowly glowing green (restored)")
+            }
+            RemoteStatus::Warning => (RgbLedLight::YELLOW, "slowly glowing yellow (restored)"),
+            RemoteStatus::Failing => (RgbLedLight::RED, "slowly glowing red (restored)"),
+        };
+        info!(
+            "Showing the {} light's last known status, {:?} as of {}, until it's polled.",
+            self.integration, saved.status, saved.saved_at
+        );
+        self.led.glow_led_period(color, RESTORED_GLOW_PERIOD);
+        events::emit(&Event::LedChanged {
+            integration: self.integration,
+            led: description,
+        });
+    }
+
+    // Glows white once. Call finish_turning_off after TURN_OFF_GLOW_MILLIS.
+    fn start_turning_off(&mut self) {
+        self.led.glow_led(RgbLedLight::WHITE);
+    }
+
+    fn finish_turning_off(&mut self) {
+        self.led.turn_led_off();
+        events::emit(&Event::LedChanged {
+            integration: self.integration,
+            led: "off",
+        });
+    }
 }
@@ -1,10 +1,294 @@
 extern crate serde;
 extern crate serde_json;
 
+use base64;
+use config_file::HttpClientConfig;
+use errors::NetworkError;
 use failure::Error;
-use reqwest::header::{Basic, Headers};
-use reqwest::{StatusCode, Url};
-use HTTP_CLIENT;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use reqwest::header::{
+    Basic, ETag, EntityTag, Headers, HttpDate, IfModifiedSince, IfNoneMatch, LastModified, UserAgent,
+};
+use reqwest::unstable::async::Client;
+use reqwest::{Certificate, Identity, Method, Proxy, StatusCode, Url};
+use retry::{retry_with_backoff, RetryPolicy};
+use secrets::{redact_url, Secret};
+use serde_path_to_error;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const PEM_CERTIFICATE_HEADER: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_FOOTER: &str = "-----END CERTIFICATE-----";
+
+// A request that's under way on the event loop.
+pub type ResponseFuture<T> = Box<dyn Future<Item = T, Error = NetworkError>>;
+
+// A raw HTTP response, before anything has decided whether it's any good.
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: String,
+    pub latency: Duration,
+}
+
+// Makes the actual HTTP requests for an HttpClient. Lets tests swap in canned responses for a real server.
+pub trait HttpFetcher {
+    fn fetch(&self, method: Method, url: Url, headers: Headers) -> ResponseFuture<HttpResponse>;
+}
+
+pub struct ReqwestFetcher {
+    client: Client,
+    // reqwest's async client doesn't time requests out by itself, and can't time out connecting on its own.
+    timeout: Duration,
+    handle: Handle,
+}
+
+impl ReqwestFetcher {
+    pub fn new(config: &HttpClientConfig, handle: &Handle) -> Result<ReqwestFetcher, Error> {
+        Ok(ReqwestFetcher {
+            client: build_client(config, handle)?,
+            timeout: Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            handle: handle.clone(),
+        })
+    }
+}
+
+impl HttpFetcher for ReqwestFetcher {
+    fn fetch(&self, method: Method, url: Url, headers: Headers) -> ResponseFuture<HttpResponse> {
+        let start = Instant::now();
+        let url_string = url.to_string();
+        let timeout = match Timeout::new(self.timeout, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                return Box::new(future::err(NetworkError::ConnectionFailed {
+                    url: redact_url(&url_string),
+                    message: e.to_string(),
+                }))
+            }
+        };
+        let response = self
+            .client
+            .request(method, url)
+            .headers(headers)
+            .send()
+            .and_then(move |response| {
+                let status = response.status();
+                //todo: Do we have to clone this?
+                let headers = response.headers().clone();
+                response
+                    .into_body()
+                    .fold(Vec::new(), |mut body, chunk| {
+                        body.extend_from_slice(&chunk);
+                        Ok::<_, ::reqwest::Error>(body)
+                    })
+                    .map(move |body| HttpResponse {
+                        status,
+                        headers,
+                        body: String::from_utf8_lossy(&body).into_owned(),
+                        latency: start.elapsed(),
+                    })
+            });
+        Box::new(response.select2(timeout).then(move |result| match result {
+            Ok(Either::A((response, _))) => Ok(response),
+            Err(Either::A((err, _))) => Err(NetworkError::from_reqwest(&url_string, &err)),
+            Ok(Either::B(_)) | Err(Either::B(_)) => Err(NetworkError::TimedOut {
+                url: redact_url(&url_string),
+            }),
+        }))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    // Requests answered with 304 Not Modified, and served from the cache.
+    pub hits: u64,
+    // Requests that had to download the full response.
+    pub misses: u64,
+}
+
+// The last full response for a URL, and the validators to send to find out whether it's changed since.
+struct CachedResponse {
+    etag: Option<EntityTag>,
+    last_modified: Option<HttpDate>,
+    headers: Headers,
+    body: String,
+}
+
+#[derive(Default)]
+struct ResponseCache {
+    responses: HashMap<String, CachedResponse>,
+    stats: CacheStats,
+}
+
+// What integrations use to talk to their servers, along with how failed requests should be retried.
+// Clones share a response cache, so it survives an integration being restarted.
+#[derive(Clone)]
+pub struct HttpClient {
+    fetcher: Rc<dyn HttpFetcher>,
+    retry_policy: RetryPolicy,
+    cache: Rc<RefCell<ResponseCache>>,
+    handle: Handle,
+}
+
+impl HttpClient {
+    pub fn new(
+        config: &HttpClientConfig,
+        retry_policy: RetryPolicy,
+        handle: &Handle,
+    ) -> Result<HttpClient, Error> {
+        Ok(HttpClient::with_fetcher(
+            Rc::new(ReqwestFetcher::new(config, handle)?),
+            retry_policy,
+            handle,
+        ))
+    }
+
+    pub fn with_fetcher(
+        fetcher: Rc<dyn HttpFetcher>,
+        retry_policy: RetryPolicy,
+        handle: &Handle,
+    ) -> HttpClient {
+        HttpClient {
+            fetcher,
+            retry_policy,
+            cache: Rc::new(RefCell::new(ResponseCache::default())),
+            handle: handle.clone(),
+        }
+    }
+
+    // The event loop the client's requests run on.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.borrow().stats
+    }
+
+    pub fn get_url_response<T>(&self, url_string: &str, headers: Headers) -> ResponseFuture<(T, Headers)>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        let url = match Url::parse(url_string) {
+            Ok(url) => url,
+            Err(_) => {
+                return Box::new(future::err(NetworkError::InvalidUrl {
+                    url: url_string.to_string(),
+                }))
+            }
+        };
+        let mut headers = headers;
+        if let Some(cached) = self.cache.borrow().responses.get(url_string) {
+            if let Some(ref etag) = cached.etag {
+                headers.set(IfNoneMatch::Items(vec![etag.clone()]));
+            }
+            if let Some(last_modified) = cached.last_modified {
+                headers.set(IfModifiedSince(last_modified));
+            }
+        }
+
+        let cache = Rc::clone(&self.cache);
+        let url_string = url_string.to_string();
+        Box::new(
+            self.fetcher
+                .fetch(Method::Get, url, headers)
+                .and_then(move |response| match response.status {
+                    StatusCode::Ok => {
+                        let deser = deserialize_response::<T>(&url_string, &response.body)?;
+                        let mut cache = cache.borrow_mut();
+                        cache.stats.misses += 1;
+                        let etag = response.headers.get::<ETag>().map(|x| x.0.clone());
+                        let last_modified = response.headers.get::<LastModified>().map(|x| x.0);
+                        if etag.is_some() || last_modified.is_some() {
+                            cache.responses.insert(
+                                url_string,
+                                CachedResponse {
+                                    etag,
+                                    last_modified,
+                                    headers: response.headers.clone(),
+                                    body: response.body,
+                                },
+                            );
+                        } else {
+                            cache.responses.remove(&url_string);
+                        }
+                        Ok((deser, response.headers))
+                    }
+                    StatusCode::NotModified => {
+                        let mut cache = cache.borrow_mut();
+                        let (body, cached_headers) = match cache.responses.get(&url_string) {
+                            Some(cached) => (cached.body.clone(), cached.headers.clone()),
+                            // We never asked for this, so something between us and the server is confused.
+                            None => {
+                                return Err(NetworkError::from_status(
+                                    &url_string,
+                                    response.status,
+                                    &response.headers,
+                                ))
+                            }
+                        };
+                        cache.stats.hits += 1;
+                        debug!("Cache hit for {}.", url_string);
+                        let deser = deserialize_response::<T>(&url_string, &body)?;
+                        Ok((deser, cached_headers))
+                    }
+                    other_code => Err(NetworkError::from_status(
+                        &url_string,
+                        other_code,
+                        &response.headers,
+                    )),
+                }),
+        )
+    }
+
+    // Retries get_url_response with backoff, according to the client's retry policy.
+    pub fn get_url_response_with_retry<T>(
+        &self,
+        url_string: &str,
+        headers: Headers,
+    ) -> ResponseFuture<(T, Headers)>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        let client = self.clone();
+        let url = url_string.to_string();
+        retry_with_backoff(
+            &self.handle,
+            &self.retry_policy,
+            &redact_url(url_string),
+            move || client.get_url_response(&url, headers.clone()),
+        )
+    }
+
+    // Unlike get_url_response, any status code counts as a successful probe. It's up to the caller to decide what's healthy.
+    pub fn probe_url(&self, method: Method, url_string: &str) -> ResponseFuture<HttpResponse> {
+        match Url::parse(url_string) {
+            Ok(url) => self.fetcher.fetch(method, url, Headers::new()),
+            Err(_) => Box::new(future::err(NetworkError::InvalidUrl {
+                url: url_string.to_string(),
+            })),
+        }
+    }
+}
+
+fn deserialize_response<T>(url_string: &str, body: &str) -> Result<T, NetworkError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let deserializer = &mut serde_json::Deserializer::from_str(body);
+    serde_path_to_error::deserialize(deserializer).map_err(|err| NetworkError::InvalidResponse {
+        url: redact_url(url_string),
+        path: err.path().to_string(),
+        message: err.inner().to_string(),
+    })
+}
 
 pub fn get_basic_credentials(username: &str, password: Option<String>) -> Basic {
     Basic {
@@ -13,27 +297,288 @@ pub fn get_basic_credentials(username: &str, password: Option<String>) -> Basic
     }
 }
 
-pub fn get_url_response<T>(url_string: &str, headers: Headers) -> Result<(T, Headers), Error>
-where
-    T: serde::de::DeserializeOwned,
-{
-    if let Ok(url) = Url::parse(&url_string) {
-        let mut response = HTTP_CLIENT.get(url).headers(headers).send()?;
+fn build_client(config: &HttpClientConfig, handle: &Handle) -> Result<Client, Error> {
+    let mut builder = Client::builder();
 
-        match response.status() {
-            StatusCode::Ok => {
-                let body_string = response.text()?;
-                let deser = serde_json::from_str::<T>(body_string.as_str())?;
-                //todo: Do we have to clone this?
-                Ok((deser, response.headers().clone()))
+    let user_agent = config.user_agent.clone().unwrap_or_else(|| {
+        format!(
+            "{}/{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )
+    });
+    let mut default_headers = Headers::new();
+    default_headers.set(UserAgent::new(user_agent));
+    builder.default_headers(default_headers);
+
+    if config.http_proxy.is_some() || config.https_proxy.is_some() {
+        builder.proxy(build_proxy(config)?);
+    }
+
+    for ca_bundle_path in config.ca_bundles.iter().flatten() {
+        for certificate in read_certificates(ca_bundle_path)? {
+            builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let Some(ref identity_path) = config.client_identity_file {
+        let password = config
+            .client_identity_password
+            .as_ref()
+            .map_or("", Secret::expose);
+        let identity = Identity::from_pkcs12_der(&read_file(identity_path)?, password)
+            .map_err(|err| {
+                format_err!(
+                    "Unable to load client identity from {}: {}",
+                    identity_path,
+                    err
+                )
+            })?;
+        builder.identity(identity);
+    }
+
+    Ok(builder.build(handle)?)
+}
+
+fn build_proxy(config: &HttpClientConfig) -> Result<Proxy, Error> {
+    let parse_proxy_url = |proxy_url: &Option<String>| -> Result<Option<Url>, Error> {
+        match *proxy_url {
+            Some(ref proxy_url) => Url::parse(proxy_url)
+                .map(Some)
+                .map_err(|err| format_err!("Invalid proxy url {}: {}", proxy_url, err)),
+            None => Ok(None),
+        }
+    };
+    let http_proxy = parse_proxy_url(&config.http_proxy)?;
+    let https_proxy = parse_proxy_url(&config.https_proxy)?;
+    let no_proxy = config.no_proxy.clone().unwrap_or_default();
+
+    Ok(Proxy::custom(move |url| {
+        if url
+            .host_str()
+            .map_or(false, |host| is_no_proxy_host(&no_proxy, host))
+        {
+            return None;
+        }
+        match url.scheme() {
+            "http" => http_proxy.clone(),
+            "https" => https_proxy.clone(),
+            _ => None,
+        }
+    }))
+}
+
+// Matches the usual no_proxy conventions: "*" matches everything, and "example.com" or ".example.com"
+// match example.com itself and all of its subdomains.
+fn is_no_proxy_host(no_proxy: &[String], host: &str) -> bool {
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim_start_matches('.');
+        entry == "*" || host == entry || host.ends_with(&format!(".{}", entry))
+    })
+}
+
+// Accepts either a PEM bundle with any number of certificates, or a single DER-encoded certificate.
+fn read_certificates(path: &str) -> Result<Vec<Certificate>, Error> {
+    let contents = read_file(path)?;
+    let der_certificates = match String::from_utf8(contents.clone()) {
+        Ok(ref text) if text.contains(PEM_CERTIFICATE_HEADER) => text
+            .split(PEM_CERTIFICATE_HEADER)
+            .skip(1)
+            .map(|block| {
+                let base64_body: String = block
+                    .split(PEM_CERTIFICATE_FOOTER)
+                    .next()
+                    .unwrap_or("")
+                    .split_whitespace()
+                    .collect();
+                base64::decode(&base64_body)
+                    .map_err(|err| format_err!("Invalid certificate in {}: {}", path, err))
+            })
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?,
+        _ => vec![contents],
+    };
+
+    der_certificates
+        .iter()
+        .map(|der| {
+            Certificate::from_der(der)
+                .map_err(|err| format_err!("Invalid certificate in {}: {}", path, err))
+        })
+        .collect()
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    let mut file =
+        File::open(path).map_err(|err| format_err!("Unable to open {}: {}", path, err))?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .map_err(|err| format_err!("Unable to read {}: {}", path, err))?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixture_fetcher::FixtureFetcher;
+    use integrations::jenkins_response::{JenkinsBuildResult, JenkinsJobResponse};
+    use tokio_core::reactor::Core;
+
+    const JOBS_URL: &str = "http://jenkins.example.com/api/json";
+    const BUILD_URL: &str = "http://jenkins.example.com/job/api-server/lastBuild/api/json";
+
+    fn get<T>(core: &mut Core, client: &HttpClient, url: &str) -> Result<(T, Headers), NetworkError>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        core.run(client.get_url_response(url, Headers::new()))
+    }
+
+    #[test]
+    fn not_modified_response_is_served_from_cache() {
+        let mut core = Core::new().unwrap();
+        let client = FixtureFetcher::new()
+            .with_etag_fixture(BUILD_URL, "jenkins/build_success.json", "build-142")
+            .into_client(&core.handle());
+
+        let (first, _): (JenkinsBuildResult, Headers) =
+            get(&mut core, &client, BUILD_URL).unwrap();
+        let (second, second_headers): (JenkinsBuildResult, Headers) =
+            get(&mut core, &client, BUILD_URL).unwrap();
+
+        assert_eq!(first.build_result, second.build_result);
+        assert!(second_headers.get::<ETag>().is_some());
+        assert_eq!(client.cache_stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn clones_share_a_cache() {
+        let mut core = Core::new().unwrap();
+        let client = FixtureFetcher::new()
+            .with_etag_fixture(BUILD_URL, "jenkins/build_success.json", "build-142")
+            .into_client(&core.handle());
+        let clone = client.clone();
+
+        let _: (JenkinsBuildResult, Headers) =
+            get(&mut core, &client, BUILD_URL).unwrap();
+        let _: (JenkinsBuildResult, Headers) =
+            get(&mut core, &clone, BUILD_URL).unwrap();
+
+        assert_eq!(client.cache_stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn responses_without_validators_are_not_cached() {
+        let mut core = Core::new().unwrap();
+        let client = FixtureFetcher::new()
+            .with_fixture(BUILD_URL, "jenkins/build_success.json")
+            .into_client(&core.handle());
+
+        for _ in 0..2 {
+            let _: (JenkinsBuildResult, Headers) =
+                get(&mut core, &client, BUILD_URL).unwrap();
+        }
+
+        assert_eq!(client.cache_stats(), CacheStats { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn not_modified_without_cached_response_is_an_error() {
+        let mut core = Core::new().unwrap();
+        let client = FixtureFetcher::new()
+            .with_status(BUILD_URL, StatusCode::NotModified)
+            .into_client(&core.handle());
+
+        let result: Result<(JenkinsBuildResult, Headers), NetworkError> =
+            get(&mut core, &client, BUILD_URL);
+
+        match result {
+            Err(NetworkError::UnexpectedStatus { status, .. }) => {
+                assert_eq!(status, StatusCode::NotModified)
             }
-            other_code => Err(format_err!(
-                "HTTP call to {} failed with code: {}",
-                &url_string,
-                other_code
-            )),
-        }
-    } else {
-        Err(format_err!("Unable to parse url: {}", url_string))
+            _ => panic!("Expected an unexpected status error."),
+        }
+        assert_eq!(client.cache_stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn mismatched_json_reports_the_path() {
+        let mut core = Core::new().unwrap();
+        let client = FixtureFetcher::new()
+            .with_fixture(JOBS_URL, "jenkins/jobs_unknown_color.json")
+            .into_client(&core.handle());
+
+        let result: Result<(JenkinsJobResponse, Headers), NetworkError> =
+            get(&mut core, &client, JOBS_URL);
+
+        match result {
+            Err(NetworkError::InvalidResponse { path, .. }) => assert_eq!(path, "jobs[1].color"),
+            _ => panic!("Expected an invalid response error."),
+        }
+    }
+
+    #[test]
+    fn status_codes_are_classified() {
+        let classify = |status| NetworkError::from_status(BUILD_URL, status, &Headers::new());
+
+        match classify(StatusCode::Forbidden) {
+            NetworkError::Unauthorized { .. } => {}
+            other => panic!("Expected Unauthorized, got {:?}", other),
+        }
+        match classify(StatusCode::NotFound) {
+            NetworkError::NotFound { .. } => {}
+            other => panic!("Expected NotFound, got {:?}", other),
+        }
+        match classify(StatusCode::TooManyRequests) {
+            NetworkError::RateLimited { .. } => {}
+            other => panic!("Expected RateLimited, got {:?}", other),
+        }
+        match classify(StatusCode::BadGateway) {
+            NetworkError::ServerError { .. } => {}
+            other => panic!("Expected ServerError, got {:?}", other),
+        }
+    }
+
+    fn fixture_path(fixture: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), fixture)
+    }
+
+    #[test]
+    fn no_proxy_matches_hosts_and_their_subdomains() {
+        let no_proxy = vec!["example.com".to_string(), ".internal".to_string()];
+
+        assert!(is_no_proxy_host(&no_proxy, "example.com"));
+        assert!(is_no_proxy_host(&no_proxy, "jenkins.example.com"));
+        assert!(is_no_proxy_host(&no_proxy, "internal"));
+        assert!(is_no_proxy_host(&no_proxy, "ci.internal"));
+        assert!(!is_no_proxy_host(&no_proxy, "notexample.com"));
+        assert!(!is_no_proxy_host(&no_proxy, "example.com.evil.net"));
+        assert!(!is_no_proxy_host(&[], "example.com"));
+    }
+
+    #[test]
+    fn no_proxy_wildcard_matches_everything() {
+        assert!(is_no_proxy_host(&["*".to_string()], "build-api.cloud.unity3d.com"));
+    }
+
+    #[test]
+    fn pem_bundle_can_hold_several_certificates() {
+        let certificates = read_certificates(&fixture_path("certs/bundle.pem")).unwrap();
+
+        assert_eq!(certificates.len(), 2);
+    }
+
+    #[test]
+    fn file_without_pem_header_is_read_as_der() {
+        let certificates = read_certificates(&fixture_path("certs/single.der")).unwrap();
+
+        assert_eq!(certificates.len(), 1);
+    }
+
+    #[test]
+    fn invalid_certificates_are_rejected() {
+        assert!(read_certificates(&fixture_path("certs/invalid.pem")).is_err());
+        // Neither PEM nor DER.
+        assert!(read_certificates(&fixture_path("jenkins/build_success.json")).is_err());
+        assert!(read_certificates(&fixture_path("certs/missing.pem")).is_err());
     }
 }
@@ -1,5 +1,6 @@
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{mpsc, Mutex};
 use std::thread;
 use std::time::Duration;
 use wiringpi;
@@ -7,14 +8,68 @@ use wiringpi::*;
 
 lazy_static! {
     static ref PI: WiringPi<pin::Gpio> = wiringpi::setup_gpio();
+    // The red, green and blue pins of each LED in use, so they can all be reset on shutdown.
+    static ref USED_PINS: Mutex<Vec<[u16; 3]>> = Mutex::new(Vec::new());
+}
+
+// In dry-run mode, lights keep track of what they'd show, but never set up or write to the GPIO pins.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::SeqCst);
+}
+
+// How long RgbLedLight::run_power_on_test takes, before it settles on glowing purple.
+pub const POWER_ON_TEST_DURATION: Duration = Duration::from_millis(2250);
+
+// Once set, writes to the pins are ignored.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+// Sets every pin that's been used to the given colour, then ignores any further writes, so the LEDs stay that way
+// until we exit, even if a light's animation is still running. Soft PWM stops with the process, so each colour
+// component ends up either fully on or off: use 0 or 100.
+pub fn freeze_all(rgb: (i32, i32, i32)) {
+    FROZEN.store(true, Ordering::SeqCst);
+    if DRY_RUN.load(Ordering::SeqCst) {
+        return;
+    }
+    let used_pins = USED_PINS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for led in used_pins.iter() {
+        let (r, g, b) = rgb;
+        PI.soft_pwm_pin(led[0]).pwm_write(r);
+        PI.soft_pwm_pin(led[1]).pwm_write(g);
+        PI.soft_pwm_pin(led[2]).pwm_write(b);
+    }
+    // Give the soft PWM threads a cycle to pick up the new values.
+    thread::sleep(Duration::from_millis(50));
+}
+
+// Stops freeze_all from driving an LED's pins, e.g. once its light has been stopped by a config reload. If the light
+// is started again with the same pins, they're used again.
+pub fn release(pins: [u16; 3]) {
+    let mut used_pins = USED_PINS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    used_pins.retain(|x| *x != pins);
+}
+
+struct LedPins {
+    red: wiringpi::pin::SoftPwmPin<wiringpi::pin::Gpio>,
+    green: wiringpi::pin::SoftPwmPin<wiringpi::pin::Gpio>,
+    blue: wiringpi::pin::SoftPwmPin<wiringpi::pin::Gpio>,
 }
 
 pub struct RgbLedLight {
-    red_pin: wiringpi::pin::SoftPwmPin<wiringpi::pin::Gpio>,
-    green_pin: wiringpi::pin::SoftPwmPin<wiringpi::pin::Gpio>,
-    blue_pin: wiringpi::pin::SoftPwmPin<wiringpi::pin::Gpio>,
-    is_blinking: Arc<Mutex<bool>>,
-    stop_blinking_transmitter: Option<Sender<bool>>,
+    red_id: u16,
+    green_id: u16,
+    blue_id: u16,
+    // None in dry-run mode.
+    pins: Option<LedPins>,
+    // The blink or glow that's currently running, if any.
+    animation: Option<Animation>,
+}
+
+struct Animation {
+    stop_transmitter: Sender<bool>,
+    handle: thread::JoinHandle<()>,
 }
 
 impl RgbLedLight {
@@ -27,12 +82,25 @@ impl RgbLedLight {
     pub const PURPLE: (i32, i32, i32) = (100, 0, 100);
 
     pub fn new(red: u16, green: u16, blue: u16) -> RgbLedLight {
+        let pins = if DRY_RUN.load(Ordering::SeqCst) {
+            None
+        } else {
+            let mut used_pins = USED_PINS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if !used_pins.contains(&[red, green, blue]) {
+                used_pins.push([red, green, blue]);
+            }
+            Some(LedPins {
+                red: PI.soft_pwm_pin(red),
+                green: PI.soft_pwm_pin(green),
+                blue: PI.soft_pwm_pin(blue),
+            })
+        };
         RgbLedLight {
-            red_pin: PI.soft_pwm_pin(red),
-            green_pin: PI.soft_pwm_pin(green),
-            blue_pin: PI.soft_pwm_pin(blue),
-            is_blinking: Arc::new(Mutex::new(false)),
-            stop_blinking_transmitter: None,
+            red_id: red,
+            green_id: green,
+            blue_id: blue,
+            pins,
+            animation: None,
         }
     }
 
@@ -53,127 +121,128 @@ impl RgbLedLight {
     }
 
     pub fn blink_led(&mut self, rgb: (i32, i32, i32)) {
-        if self.is_blinking() {
-            self.stop_blinking();
-        }
-
-        let mut led_clone = RgbLedLight {
-            red_pin: PI.soft_pwm_pin(self.red_pin.number() as u16),
-            green_pin: PI.soft_pwm_pin(self.green_pin.number() as u16),
-            blue_pin: PI.soft_pwm_pin(self.blue_pin.number() as u16),
-            is_blinking: Arc::new(Mutex::new(false)),
-            stop_blinking_transmitter: None,
-        };
-
         let (r, g, b) = rgb; //destructure the tuple, so we can refer to individual values
-
-        self.start_blinking();
-        let (tx, rx): (Sender<bool>, Receiver<bool>) = mpsc::channel();
-        self.stop_blinking_transmitter = Some(tx);
-        // reference to self.is_blinking, so the thread can safely watch it for value changes
-        let is_blinking = self.is_blinking.clone();
-        thread::spawn(move || loop {
-            if rx.try_recv().is_ok() {
+        self.animate(move |led, rx| loop {
+            led.set_led_rgb_values_internal(r, g, b);
+            if stop_requested(rx, Duration::from_millis(750)) {
                 return;
             }
-            led_clone.set_led_rgb_values_internal(r, g, b);
-            thread::sleep(Duration::from_millis(750));
-
-            if rx.try_recv().is_ok() {
+            led.turn_led_off_internal();
+            if stop_requested(rx, Duration::from_millis(750)) {
                 return;
             }
-            led_clone.turn_led_off_internal();
-            thread::sleep(Duration::from_millis(750));
         });
-    }        
+    }
 
     pub fn glow_led(&mut self, rgb: (i32, i32, i32)) {
         self.glow_led_period(rgb, 1400);
     }
 
     pub fn glow_led_period(&mut self, rgb: (i32, i32, i32), period: u64) {
-        if self.is_blinking() {
-            self.stop_blinking();
-        }
-
-        let period = if period >= 200 { period } else { 200 };
-        let sleep_per_tick = period / 200;
-
-        let mut led_clone = RgbLedLight {
-            red_pin: PI.soft_pwm_pin(self.red_pin.number() as u16),
-            green_pin: PI.soft_pwm_pin(self.green_pin.number() as u16),
-            blue_pin: PI.soft_pwm_pin(self.blue_pin.number() as u16),
-            is_blinking: Arc::new(Mutex::new(false)),
-            stop_blinking_transmitter: None,
-        };
-
-        let (r, g, b) = rgb; //destructure the tuple, so we can refer to individual values
+        self.animate(move |led, rx| glow(led, rgb, period, rx));
+    }
 
-        self.start_blinking();
-        let (tx, rx): (Sender<bool>, Receiver<bool>) = mpsc::channel();
-        self.stop_blinking_transmitter = Some(tx);
-        thread::spawn(move || loop {
-            if rx.try_recv().is_ok() {
-                return;
-            }
-            for i in 0..101 {
-                if rx.try_recv().is_ok() {
+    // Flashes red, green, blue and white in turn, so a dead LED or a miswired pin is easy to spot, then glows
+    // purple until something else is shown. Returns straight away: the test takes POWER_ON_TEST_DURATION.
+    pub fn run_power_on_test(&mut self) {
+        self.animate(|led, rx| {
+            let steps = [
+                ((0, 0, 0), 1000),
+                (RgbLedLight::RED, 250),
+                (RgbLedLight::GREEN, 250),
+                (RgbLedLight::BLUE, 250),
+                ((0, 0, 0), 250),
+                (RgbLedLight::WHITE, 250),
+            ];
+            for &((r, g, b), millis) in &steps {
+                led.set_led_rgb_values_internal(r, g, b);
+                if stop_requested(rx, Duration::from_millis(millis)) {
                     return;
                 }
-                let partial_red = ((i as f32 / 100f32) * r as f32) as i32;
-                let partial_green = ((i as f32 / 100f32) * g as f32) as i32;
-                let partial_blue = ((i as f32 / 100f32) * b as f32) as i32;
-                led_clone.set_led_rgb_values_internal(partial_red, partial_green, partial_blue);
-                thread::sleep(Duration::from_millis(sleep_per_tick));
             }
+            glow(led, RgbLedLight::PURPLE, 1400, rx);
+        });
+    }
 
-            for i in (0..101).rev() {
-                if rx.try_recv().is_ok() {
-                    return;
-                }
+    // Runs an animation on its own thread, until the next change to the LED stops it.
+    fn animate<F>(&mut self, animation: F)
+    where
+        F: FnOnce(&mut RgbLedLight, &Receiver<bool>) + Send + 'static,
+    {
+        if self.is_blinking() {
+            self.stop_blinking();
+        }
 
-                let partial_red = ((i as f32 / 100f32) * r as f32) as i32;
-                let partial_green = ((i as f32 / 100f32) * g as f32) as i32;
-                let partial_blue = ((i as f32 / 100f32) * b as f32) as i32;
-                led_clone.set_led_rgb_values_internal(partial_red, partial_green, partial_blue);
-                thread::sleep(Duration::from_millis(sleep_per_tick));
-            }
-        });        
-    }    
+        let mut led_clone = RgbLedLight::new(self.red_id, self.green_id, self.blue_id);
+        let (tx, rx): (Sender<bool>, Receiver<bool>) = mpsc::channel();
+        let handle = thread::spawn(move || animation(&mut led_clone, &rx));
+        self.animation = Some(Animation {
+            stop_transmitter: tx,
+            handle,
+        });
+    }
 
     fn turn_led_on_internal(&mut self) {
-        self.red_pin.pwm_write(100);
-        self.green_pin.pwm_write(100);
-        self.blue_pin.pwm_write(100);
+        self.set_led_rgb_values_internal(100, 100, 100);
     }
 
     fn turn_led_off_internal(&mut self) {
-        self.red_pin.pwm_write(0);
-        self.green_pin.pwm_write(0);
-        self.blue_pin.pwm_write(0);
+        self.set_led_rgb_values_internal(0, 0, 0);
     }
 
     fn set_led_rgb_values_internal(&mut self, r: i32, g: i32, b: i32) {
-        self.red_pin.pwm_write(r);
-        self.green_pin.pwm_write(g);
-        self.blue_pin.pwm_write(b);
-    }
-
-    fn start_blinking(&mut self) {
-        let mut is_blinking = self.is_blinking.lock().unwrap();
-        *is_blinking = true;
+        if FROZEN.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(ref pins) = self.pins {
+            pins.red.pwm_write(r);
+            pins.green.pwm_write(g);
+            pins.blue.pwm_write(b);
+        }
     }
 
+    // Waits for the animation thread to finish, so nothing else writes to the pins once this returns.
     fn stop_blinking(&mut self) {
-        if let Some(ref tx) = self.stop_blinking_transmitter {
-            tx.send(true);
+        if let Some(animation) = self.animation.take() {
+            let _ = animation.stop_transmitter.send(true);
+            let _ = animation.handle.join();
         }
-        let mut is_blinking = self.is_blinking.lock().unwrap();
-        *is_blinking = false;
     }
 
     fn is_blinking(&mut self) -> bool {
-        let is_blinking = self.is_blinking.lock().unwrap();
-        return *is_blinking;
+        self.animation.is_some()
+    }
+}
+
+impl Drop for RgbLedLight {
+    fn drop(&mut self) {
+        self.stop_blinking();
+    }
+}
+
+// Fades in and out, over and over, until the animation's asked to stop.
+fn glow(led: &mut RgbLedLight, rgb: (i32, i32, i32), period: u64, rx: &Receiver<bool>) {
+    let period = if period >= 200 { period } else { 200 };
+    let sleep_per_tick = period / 200;
+
+    let (r, g, b) = rgb; //destructure the tuple, so we can refer to individual values
+    loop {
+        for i in (0..101).chain((0..101).rev()) {
+            let partial_red = ((i as f32 / 100f32) * r as f32) as i32;
+            let partial_green = ((i as f32 / 100f32) * g as f32) as i32;
+            let partial_blue = ((i as f32 / 100f32) * b as f32) as i32;
+            led.set_led_rgb_values_internal(partial_red, partial_green, partial_blue);
+            if stop_requested(rx, Duration::from_millis(sleep_per_tick)) {
+                return;
+            }
+        }
+    }
+}
+
+// Sleeps between animation frames. Returns early, with true, if the animation's been asked to stop.
+fn stop_requested(receiver: &Receiver<bool>, timeout: Duration) -> bool {
+    match receiver.recv_timeout(timeout) {
+        Err(RecvTimeoutError::Timeout) => false,
+        _ => true,
     }
 }
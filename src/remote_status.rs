@@ -1,7 +1,33 @@
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RemoteStatus {
     Unknown,    // Glowing Purple
     InProgress, // Rapid glowing green
     Passing,    // Green
+    Warning,    // Yellow
     Failing,    // Blinking red
 }
+
+impl RemoteStatus {
+    // The same names it's serialized with, e.g. "in_progress".
+    pub fn name(self) -> &'static str {
+        match self {
+            RemoteStatus::Unknown => "unknown",
+            RemoteStatus::InProgress => "in_progress",
+            RemoteStatus::Passing => "passing",
+            RemoteStatus::Warning => "warning",
+            RemoteStatus::Failing => "failing",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RemoteStatus> {
+        match name {
+            "unknown" => Some(RemoteStatus::Unknown),
+            "in_progress" => Some(RemoteStatus::InProgress),
+            "passing" => Some(RemoteStatus::Passing),
+            "warning" => Some(RemoteStatus::Warning),
+            "failing" => Some(RemoteStatus::Failing),
+            _ => None,
+        }
+    }
+}
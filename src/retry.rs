@@ -0,0 +1,287 @@
+use durations;
+use errors::NetworkError;
+use futures::future::{self, Loop};
+use futures::Future;
+use network::ResponseFuture;
+use rand::{thread_rng, Rng};
+use std::cmp;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+
+#[derive(Copy, Clone, Debug)]
+pub struct RetryPolicy {
+    // Total attempts per request, including the first one.
+    pub max_attempts: u32,
+    // Delay before the first retry. Doubles with each subsequent retry, up to max_delay.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    // Consecutive failed polls before the circuit opens, and polls stop for a while.
+    pub failure_threshold: u32,
+    // How long the circuit stays open before a single trial poll is let through.
+    // Doubles every time the trial poll fails, up to max_open_duration.
+    pub open_duration: Duration,
+    pub max_open_duration: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            failure_threshold: 3,
+            open_duration: Duration::from_secs(60),
+            max_open_duration: Duration::from_secs(600),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff with jitter: somewhere between half and all of initial_delay * 2^retry.
+    fn delay_before_retry(&self, retry: u32) -> Duration {
+        let multiplier = 1u32.checked_shl(retry).unwrap_or(u32::max_value());
+        let delay = cmp::min(
+            self.initial_delay
+                .checked_mul(multiplier)
+                .unwrap_or(self.max_delay),
+            self.max_delay,
+        );
+        let delay_millis = durations::millis(delay);
+        if delay_millis < 2 {
+            return delay;
+        }
+        Duration::from_millis(thread_rng().gen_range(delay_millis / 2, delay_millis + 1))
+    }
+}
+
+// How long to wait before the given attempt's retry, or None if it shouldn't be retried at all.
+// Errors that won't go away by themselves, like bad credentials, are never retried.
+fn delay_before_retry(policy: &RetryPolicy, attempt: u32, error: &NetworkError) -> Option<Duration> {
+    if attempt >= policy.max_attempts || !error.is_transient() {
+        return None;
+    }
+    match *error {
+        // No point asking again before the server says we can. If that's longer than
+        // we'd ever wait between retries, leave it for the next poll.
+        NetworkError::RateLimited {
+            retry_after: Some(retry_after),
+            ..
+        } => {
+            if retry_after > policy.max_delay {
+                return None;
+            }
+            Some(cmp::max(retry_after, policy.delay_before_retry(attempt - 1)))
+        }
+        _ => Some(policy.delay_before_retry(attempt - 1)),
+    }
+}
+
+// Calls func until its request succeeds, or until the policy's attempts run out. Fails with the last error.
+// Waits between attempts on the event loop, so other requests carry on in the meantime.
+pub fn retry_with_backoff<T, F>(
+    handle: &Handle,
+    policy: &RetryPolicy,
+    description: &str,
+    func: F,
+) -> ResponseFuture<T>
+where
+    T: 'static,
+    F: FnMut() -> ResponseFuture<T> + 'static,
+{
+    let handle = handle.clone();
+    let policy = *policy;
+    let description = description.to_string();
+    Box::new(future::loop_fn((func, 1), move |(mut func, attempt)| {
+        let handle = handle.clone();
+        let description = description.clone();
+        func().then(move |result| -> ResponseFuture<Loop<T, (F, u32)>> {
+            let e = match result {
+                Ok(result) => return Box::new(future::ok(Loop::Break(result))),
+                Err(e) => e,
+            };
+            let delay = match delay_before_retry(&policy, attempt, &e) {
+                Some(delay) => delay,
+                None => return Box::new(future::err(e)),
+            };
+            warn!(
+                "Attempt {} of {} for {} failed. Retrying in {} ms. Error: {}",
+                attempt,
+                policy.max_attempts,
+                description,
+                durations::millis(delay),
+                e
+            );
+            match Timeout::new(delay, &handle) {
+                Ok(timeout) => Box::new(timeout.then(move |_| Ok(Loop::Continue((func, attempt + 1))))),
+                Err(_) => Box::new(future::err(e)),
+            }
+        })
+    }))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    // Polling normally.
+    Closed,
+    // Too many failures in a row. Not polling until the open duration has passed.
+    Open,
+    // The open duration has passed, and a single trial poll is allowed through.
+    HalfOpen,
+}
+
+pub struct CircuitBreaker {
+    name: String,
+    policy: RetryPolicy,
+    state: CircuitState,
+    consecutive_failures: u32,
+    open_duration: Duration,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, policy: RetryPolicy) -> CircuitBreaker {
+        CircuitBreaker {
+            name: name.to_string(),
+            policy,
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            open_duration: policy.open_duration,
+            opened_at: None,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    // Whether a poll should go ahead. Moves an open circuit to half-open once it's waited long enough.
+    pub fn allow_request(&mut self) -> bool {
+        match self.state {
+            CircuitState::Closed | CircuitState::HalfOpen => true,
+            CircuitState::Open => {
+                let waited_long_enough = self
+                    .opened_at
+                    .map_or(true, |opened_at| opened_at.elapsed() >= self.open_duration);
+                if waited_long_enough {
+                    info!(
+                        "Circuit for {} is now half-open. Allowing a trial poll.",
+                        self.name
+                    );
+                    self.state = CircuitState::HalfOpen;
+                }
+                waited_long_enough
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        if self.state != CircuitState::Closed {
+            info!("Circuit for {} is now closed. Resuming polling.", self.name);
+        }
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.open_duration = self.policy.open_duration;
+        self.opened_at = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        match self.state {
+            CircuitState::HalfOpen => {
+                // The trial poll failed too, so back off for longer this time.
+                self.open_duration = cmp::min(
+                    self.open_duration
+                        .checked_mul(2)
+                        .unwrap_or(self.policy.max_open_duration),
+                    self.policy.max_open_duration,
+                );
+                self.open();
+            }
+            CircuitState::Closed if self.consecutive_failures >= self.policy.failure_threshold => {
+                self.open();
+            }
+            _ => {}
+        }
+    }
+
+    fn open(&mut self) {
+        warn!(
+            "Circuit for {} is now open after {} consecutive failures. Not polling for {} seconds.",
+            self.name,
+            self.consecutive_failures,
+            self.open_duration.as_secs()
+        );
+        self.state = CircuitState::Open;
+        self.opened_at = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tokio_core::reactor::Core;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(0),
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn attempts_until_gave_up(error: fn() -> NetworkError) -> u32 {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&attempts);
+        let retries = retry_with_backoff(&core.handle(), &policy(), "test", move || -> ResponseFuture<()> {
+            counter.set(counter.get() + 1);
+            Box::new(future::err(error()))
+        });
+        assert!(core.run(retries).is_err());
+        attempts.get()
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let attempts = attempts_until_gave_up(|| NetworkError::ServerError {
+            url: "http://example.com".to_string(),
+            status: StatusCode::ServiceUnavailable,
+        });
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn auth_errors_are_not_retried() {
+        let attempts = attempts_until_gave_up(|| NetworkError::Unauthorized {
+            url: "http://example.com".to_string(),
+            status: StatusCode::Unauthorized,
+        });
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn circuit_opens_after_threshold_and_half_opens_after_waiting() {
+        let mut breaker = CircuitBreaker::new(
+            "test",
+            RetryPolicy {
+                failure_threshold: 2,
+                open_duration: Duration::from_millis(0),
+                ..policy()
+            },
+        );
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        assert!(breaker.allow_request());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,161 @@
+use durations;
+use rand::{thread_rng, Rng};
+use std::cmp;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug)]
+pub struct PollSchedule {
+    // How long to wait between polls.
+    pub interval: Duration,
+    // Each wait is randomly lengthened or shortened by up to this much, so lights don't all poll in lockstep.
+    pub jitter: Duration,
+    // Polls are never closer together than this, even across restarts of the light, including by a config reload.
+    pub min_spacing: Duration,
+}
+
+impl PollSchedule {
+    pub const fn from_secs(interval: u64, jitter: u64, min_spacing: u64) -> PollSchedule {
+        PollSchedule {
+            interval: Duration::from_secs(interval),
+            jitter: Duration::from_secs(jitter),
+            min_spacing: Duration::from_secs(min_spacing),
+        }
+    }
+}
+
+// Tracks when an integration should next poll.
+// Lives outside the integration itself, so a restarted integration doesn't poll any sooner than it should have.
+// Takes the time as a parameter, so it can be tested.
+pub struct Scheduler {
+    schedule: PollSchedule,
+    last_poll: Option<Instant>,
+    next_poll: Option<Instant>,
+}
+
+impl Scheduler {
+    // Given when a light that's been restarted last polled, e.g. before a reload changed its schedule,
+    // the first poll waits for min_spacing.
+    pub fn new(schedule: PollSchedule, last_poll: Option<Instant>) -> Scheduler {
+        Scheduler {
+            schedule,
+            last_poll,
+            next_poll: last_poll.map(|x| x + schedule.min_spacing),
+        }
+    }
+
+    pub fn last_poll(&self) -> Option<Instant> {
+        self.last_poll
+    }
+
+    pub fn time_until_next_poll(&self, now: Instant) -> Duration {
+        match self.next_poll {
+            Some(next_poll) => duration_until(next_poll, now),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    pub fn record_poll(&mut self, now: Instant) {
+        let jitter_millis = durations::millis(self.schedule.jitter) as i64;
+        let offset_millis = if jitter_millis > 0 {
+            thread_rng().gen_range(-jitter_millis, jitter_millis + 1)
+        } else {
+            0
+        };
+        let interval_millis = durations::millis(self.schedule.interval) as i64 + offset_millis;
+        let wait = cmp::max(
+            Duration::from_millis(cmp::max(interval_millis, 0) as u64),
+            self.schedule.min_spacing,
+        );
+
+        self.last_poll = Some(now);
+        self.next_poll = Some(now + wait);
+    }
+}
+
+// Zero if the instant has already passed.
+fn duration_until(instant: Instant, now: Instant) -> Duration {
+    if instant > now {
+        instant - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(interval: u64, jitter: u64, min_spacing: u64) -> PollSchedule {
+        PollSchedule {
+            interval: Duration::from_millis(interval),
+            jitter: Duration::from_millis(jitter),
+            min_spacing: Duration::from_millis(min_spacing),
+        }
+    }
+
+    #[test]
+    fn first_poll_is_due_straight_away() {
+        let scheduler = Scheduler::new(schedule(10_000, 0, 5_000), None);
+        let now = Instant::now();
+
+        assert_eq!(scheduler.time_until_next_poll(now), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn next_poll_is_an_interval_after_the_last() {
+        let mut scheduler = Scheduler::new(schedule(10_000, 0, 0), None);
+        let polled = Instant::now();
+
+        scheduler.record_poll(polled);
+
+        assert_eq!(scheduler.last_poll(), Some(polled));
+        assert_eq!(scheduler.time_until_next_poll(polled), Duration::from_millis(10_000));
+        assert_eq!(
+            scheduler.time_until_next_poll(polled + Duration::from_millis(4_000)),
+            Duration::from_millis(6_000)
+        );
+        assert_eq!(
+            scheduler.time_until_next_poll(polled + Duration::from_millis(12_000)),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut scheduler = Scheduler::new(schedule(10_000, 2_000, 0), None);
+        let polled = Instant::now();
+
+        for _ in 0..200 {
+            scheduler.record_poll(polled);
+            let wait = scheduler.time_until_next_poll(polled);
+            assert!(wait >= Duration::from_millis(8_000), "{:?}", wait);
+            assert!(wait <= Duration::from_millis(12_000), "{:?}", wait);
+        }
+    }
+
+    #[test]
+    fn min_spacing_holds_however_the_jitter_falls() {
+        // Jitter alone could make the wait anywhere from nothing to 6 seconds.
+        let mut scheduler = Scheduler::new(schedule(1_000, 5_000, 3_000), None);
+        let polled = Instant::now();
+
+        for _ in 0..200 {
+            scheduler.record_poll(polled);
+            let wait = scheduler.time_until_next_poll(polled);
+            assert!(wait >= Duration::from_millis(3_000), "{:?}", wait);
+            assert!(wait <= Duration::from_millis(6_000), "{:?}", wait);
+        }
+    }
+
+    #[test]
+    fn resumed_scheduler_keeps_min_spacing_from_the_last_poll() {
+        let polled = Instant::now();
+
+        let scheduler = Scheduler::new(schedule(60_000, 0, 5_000), Some(polled));
+
+        assert_eq!(
+            scheduler.time_until_next_poll(polled + Duration::from_millis(1_000)),
+            Duration::from_millis(4_000)
+        );
+    }
+}
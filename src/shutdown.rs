@@ -0,0 +1,63 @@
+use failure::Error;
+use signal_hook;
+use signal_hook::iterator::Signals;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// Tells the build light to stop. It's checked between turns of the event loop. Clones share the same state.
+#[derive(Clone)]
+pub struct StopSignal {
+    stopped: Arc<AtomicBool>,
+    // Set if a light ran out of restarts, so that the build light exits with an error.
+    gave_up: Arc<AtomicBool>,
+}
+
+impl StopSignal {
+    pub fn new() -> StopSignal {
+        StopSignal {
+            stopped: Arc::new(AtomicBool::new(false)),
+            gave_up: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    // Stops because a light ran out of restarts.
+    pub fn give_up(&self) {
+        self.gave_up.store(true, Ordering::SeqCst);
+        self.stop();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.gave_up.load(Ordering::SeqCst)
+    }
+}
+
+// Stops on SIGINT (i.e. Ctrl-C) or SIGTERM (e.g. `systemctl stop`). SIGHUP sets reload_requested instead, for
+// ConfigWatcher to pick up. All three are registered together, so SIGHUP's default action can't kill us.
+pub fn handle_signals(stop: StopSignal, reload_requested: Arc<AtomicBool>) -> Result<(), Error> {
+    let signals = Signals::new([signal_hook::SIGINT, signal_hook::SIGTERM, signal_hook::SIGHUP])
+        .map_err(|err| format_err!("Unable to register signal handlers: {}", err))?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let name = match signal {
+                signal_hook::SIGHUP => {
+                    reload_requested.store(true, Ordering::SeqCst);
+                    continue;
+                }
+                signal_hook::SIGINT => "SIGINT",
+                _ => "SIGTERM",
+            };
+            info!("{} received, stopping...", name);
+            stop.stop();
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use chrono::Utc;
+use remote_status::RemoteStatus;
+use serde_json;
+use status_report::StatusReport;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+// The last status each light showed, kept on disk so the lights can show it again straight after a restart,
+// instead of glowing purple until their first poll. Shared by every light.
+pub struct StateFile {
+    // None if state_file is set to "", in which case nothing is saved or restored.
+    path: Option<PathBuf>,
+    reports: Mutex<HashMap<String, SavedReport>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedReport {
+    pub status: RemoteStatus,
+    pub entries: Vec<SavedEntry>,
+    // When the light changed to this status, in RFC 3339 format.
+    pub saved_at: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedEntry {
+    pub name: String,
+    pub status: RemoteStatus,
+    pub detail: Option<String>,
+}
+
+impl StateFile {
+    // A missing or unreadable file just means there's nothing to restore.
+    pub fn load(path: &str) -> StateFile {
+        let path = if path.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(path))
+        };
+        let reports = match path {
+            Some(ref path) => match fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text).unwrap_or_else(|err| {
+                    warn!(
+                        "Ignoring the saved statuses in {}, they couldn't be read. Error: {}",
+                        path.display(),
+                        err
+                    );
+                    HashMap::new()
+                }),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => {
+                    warn!(
+                        "Unable to read the saved statuses in {}. Error: {}",
+                        path.display(),
+                        err
+                    );
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+        StateFile {
+            path,
+            reports: Mutex::new(reports),
+        }
+    }
+
+    // What the light was showing when it last changed, if it's been saved.
+    pub fn restored(&self, light: &str) -> Option<SavedReport> {
+        self.lock().get(light).cloned()
+    }
+
+    // Called whenever a light changes status. Failing to save is logged, but otherwise doesn't matter.
+    pub fn save(&self, light: &str, report: &StatusReport) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let saved = SavedReport {
+            status: report.status,
+            entries: report
+                .entries
+                .iter()
+                .map(|x| SavedEntry {
+                    name: x.name.clone(),
+                    status: x.status,
+                    detail: x.detail.clone(),
+                })
+                .collect(),
+            saved_at: Utc::now().to_rfc3339(),
+        };
+
+        let mut reports = self.lock();
+        reports.insert(light.to_string(), saved);
+        // Written to a temporary file first, so a power cut part way through can't leave a half-written file.
+        let temp_path = path.with_extension("tmp");
+        let result = serde_json::to_string_pretty(&*reports)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .and_then(|text| fs::write(&temp_path, text))
+            .and_then(|_| fs::rename(&temp_path, path));
+        if let Err(e) = result {
+            warn!(
+                "Unable to save the {} light's status to {}. Error: {}",
+                light,
+                path.display(),
+                e
+            );
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, SavedReport>> {
+        self.reports
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status_report::StatusEntry;
+    use std::env;
+
+    #[test]
+    fn saved_reports_are_restored_by_the_next_run() {
+        let path = env::temp_dir().join("rusty_build_light_test_state.json");
+        let _ = fs::remove_file(&path);
+        let path = path.to_string_lossy().into_owned();
+        assert_eq!(StateFile::load(&path).restored("jenkins"), None);
+
+        let report = StatusReport::with_entries(
+            RemoteStatus::Failing,
+            vec![StatusEntry {
+                name: "web-client".to_string(),
+                status: RemoteStatus::Failing,
+                latency: None,
+                detail: None,
+                build: None,
+            }],
+        );
+        StateFile::load(&path).save("jenkins", &report);
+
+        let restored = StateFile::load(&path).restored("jenkins").unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.status, RemoteStatus::Failing);
+        assert_eq!(restored.entries[0].name, "web-client");
+        assert!(StateFile::load("").restored("jenkins").is_none());
+    }
+}
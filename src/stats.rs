@@ -0,0 +1,393 @@
+use chrono::{Duration, Utc};
+use failure::Error;
+use history::{format_duration, History, JobResult};
+use remote_status::RemoteStatus;
+use std::collections::HashSet;
+use std::time;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+// A job's flaky once it's gone from passing to failing, or back, this many times in the last 30 days
+// without the code it built changing.
+pub const FLAKY_FLIPS: usize = 2;
+// Flips age out of those 30 days even when nothing new is recorded, so flaky jobs are worked out again this often.
+const FLAKY_JOBS_MAX_AGE: time::Duration = time::Duration::from_secs(60 * 60);
+
+// Rolling statistics for one light, worked out from its history.
+pub struct IntegrationStats {
+    pub red: RedStreaks,
+    // The share of the time the light's polls found it red. None if nothing was recorded over that time.
+    pub red_share_7_days: Option<f64>,
+    pub red_share_30_days: Option<f64>,
+    pub jobs: Vec<JobStats>,
+}
+
+pub struct JobStats {
+    pub job: String,
+    pub red: RedStreaks,
+    pub failure_rate_7_days: FailureRate,
+    pub failure_rate_30_days: FailureRate,
+    // Times it went from passing to failing, or back, in the last 30 days while building the same revision.
+    pub flaky_flips: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RedStreaks {
+    // From failing to passing again, averaged over every time it was fixed.
+    pub mean_time_to_recovery: Option<Duration>,
+    pub recoveries: usize,
+    // Counts the current streak, if it's still red.
+    pub longest: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FailureRate {
+    pub failed: usize,
+    // Builds that passed or failed. Canceled and unfinished builds don't count.
+    pub finished: usize,
+}
+
+impl JobStats {
+    pub fn is_flaky(&self) -> bool {
+        self.flaky_flips >= FLAKY_FLIPS
+    }
+}
+
+impl FailureRate {
+    fn describe(&self) -> String {
+        if self.finished == 0 {
+            "no builds".to_string()
+        } else {
+            format!(
+                "{}/{} failed ({:.0}%)",
+                self.failed,
+                self.finished,
+                self.failed as f64 * 100.0 / self.finished as f64
+            )
+        }
+    }
+}
+
+impl RedStreaks {
+    fn describe(&self) -> String {
+        let mean_time_to_recovery = match self.mean_time_to_recovery {
+            Some(mean) => format!(
+                "mean time to recovery {} over {} fix(es)",
+                format_duration(mean),
+                self.recoveries
+            ),
+            None => "never fixed".to_string(),
+        };
+        match self.longest {
+            Some(longest) => format!("{}, longest red streak {}", mean_time_to_recovery, format_duration(longest)),
+            None => "never red".to_string(),
+        }
+    }
+}
+
+// `now` is in seconds since the Unix epoch, like the times in the history.
+pub fn integration_stats(history: &History, integration: &str, now: i64) -> Result<IntegrationStats, Error> {
+    let statuses = history.polled_statuses(integration)?;
+    let results = history.job_results(integration)?;
+    let jobs = by_job(&results)
+        .into_iter()
+        .map(|results| job_stats(results, now))
+        .collect();
+    Ok(IntegrationStats {
+        red: red_streaks(&statuses, now),
+        red_share_7_days: red_share(&statuses, now, 7 * DAY_SECS),
+        red_share_30_days: red_share(&statuses, now, 30 * DAY_SECS),
+        jobs,
+    })
+}
+
+// A light's flaky jobs, for its [debounce.<light>] flaky_jobs_warn. Working them out reads the light's whole
+// history, so it's only redone once new results have been recorded, or the last answer's FLAKY_JOBS_MAX_AGE old.
+#[derive(Default)]
+pub struct FlakyJobs {
+    jobs: HashSet<String>,
+    // When they were last worked out, and History::results_recorded at the time.
+    worked_out: Option<(time::Instant, usize)>,
+}
+
+impl FlakyJobs {
+    pub fn refresh(&mut self, history: &History, integration: &str, now: time::Instant) -> Result<(), Error> {
+        let results_recorded = history.results_recorded();
+        if self.worked_out.map_or(false, |(time, recorded)| {
+            recorded == results_recorded && now.duration_since(time) < FLAKY_JOBS_MAX_AGE
+        }) {
+            return Ok(());
+        }
+        let results = history.job_results(integration)?;
+        let unix_now = Utc::now().timestamp();
+        self.jobs = by_job(&results)
+            .into_iter()
+            .map(|results| job_stats(results, unix_now))
+            .filter(JobStats::is_flaky)
+            .map(|x| x.job)
+            .collect();
+        self.worked_out = Some((now, results_recorded));
+        Ok(())
+    }
+
+    pub fn is_flaky(&self, job: &str) -> bool {
+        self.jobs.contains(job)
+    }
+}
+
+// For the stats subcommand.
+pub fn print_stats(history: &History, light: Option<&str>) -> Result<(), Error> {
+    let integrations = match light {
+        Some(light) => vec![light.to_string()],
+        None => history.integrations()?,
+    };
+    if integrations.is_empty() {
+        println!("No history recorded yet.");
+    }
+    let now = Utc::now().timestamp();
+    for integration in integrations {
+        let stats = integration_stats(history, &integration, now)?;
+        println!("{}:", integration);
+        println!("    {}", stats.summary());
+        for job in &stats.jobs {
+            println!("    {}{}:", job.job, if job.is_flaky() { " (flaky)" } else { "" });
+            println!("        Last 7 days: {}", job.failure_rate_7_days.describe());
+            println!("        Last 30 days: {}", job.failure_rate_30_days.describe());
+            println!("        Red streaks: {}", job.red.describe());
+            if job.flaky_flips > 0 {
+                println!(
+                    "        Flipped between passing and failing {} time(s) without a code change",
+                    job.flaky_flips
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+impl IntegrationStats {
+    // One line, for the status subcommand.
+    pub fn summary(&self) -> String {
+        let share = |share: Option<f64>| match share {
+            Some(share) => format!("{:.0}%", share * 100.0),
+            None => "-".to_string(),
+        };
+        format!(
+            "Red {} of the last 7 days, {} of the last 30; {}",
+            share(self.red_share_7_days),
+            share(self.red_share_30_days),
+            self.red.describe()
+        )
+    }
+
+    pub fn is_flaky(&self, job: &str) -> bool {
+        self.jobs.iter().any(|x| x.job == job && x.is_flaky())
+    }
+}
+
+// Splits results that are grouped by job, like History::job_results, into one slice per job.
+fn by_job(results: &[JobResult]) -> Vec<&[JobResult]> {
+    let mut jobs = Vec::new();
+    let mut start = 0;
+    for end in 1..=results.len() {
+        if end == results.len() || results[end].job != results[start].job {
+            jobs.push(&results[start..end]);
+            start = end;
+        }
+    }
+    jobs
+}
+
+// `results` are all for the same job, oldest first.
+fn job_stats(results: &[JobResult], now: i64) -> JobStats {
+    let finished: Vec<&JobResult> = results
+        .iter()
+        .filter(|x| x.status == RemoteStatus::Passing || x.status == RemoteStatus::Failing)
+        .collect();
+    let statuses: Vec<(i64, RemoteStatus)> = finished.iter().map(|x| (x.time, x.status)).collect();
+    let failure_rate = |window: i64| {
+        let recent = finished.iter().filter(|x| x.time >= now - window);
+        FailureRate {
+            failed: recent.clone().filter(|x| x.status == RemoteStatus::Failing).count(),
+            finished: recent.count(),
+        }
+    };
+    let flaky_flips = finished
+        .windows(2)
+        .filter(|pair| {
+            pair[1].time >= now - 30 * DAY_SECS
+                && pair[0].status != pair[1].status
+                && pair[0].revision.is_some()
+                && pair[0].revision == pair[1].revision
+        })
+        .count();
+    JobStats {
+        job: results[0].job.clone(),
+        red: red_streaks(&statuses, now),
+        failure_rate_7_days: failure_rate(7 * DAY_SECS),
+        failure_rate_30_days: failure_rate(30 * DAY_SECS),
+        flaky_flips,
+    }
+}
+
+// Red starts at the first Failing status, and ends at the next Passing or Warning one.
+// Anything else, e.g. being in progress, doesn't change whether it's red.
+fn red_streaks(statuses: &[(i64, RemoteStatus)], now: i64) -> RedStreaks {
+    let mut red_since = None;
+    let mut fixed_after = Vec::new();
+    for &(time, status) in statuses {
+        match status {
+            RemoteStatus::Failing => {
+                red_since = red_since.or(Some(time));
+            }
+            RemoteStatus::Passing | RemoteStatus::Warning => {
+                if let Some(since) = red_since.take() {
+                    fixed_after.push(time - since);
+                }
+            }
+            _ => {}
+        }
+    }
+    let ongoing = red_since.map(|since| now - since);
+    RedStreaks {
+        mean_time_to_recovery: if fixed_after.is_empty() {
+            None
+        } else {
+            Some(Duration::seconds(
+                fixed_after.iter().sum::<i64>() / fixed_after.len() as i64,
+            ))
+        },
+        recoveries: fixed_after.len(),
+        longest: fixed_after.iter().chain(ongoing.iter()).max().map(|x| Duration::seconds(*x)),
+    }
+}
+
+// Each status lasts until the next one. Only the part of the window that's covered by the history counts,
+// so a light that's only been recorded for a day isn't 1/7th as red as it should be.
+fn red_share(statuses: &[(i64, RemoteStatus)], now: i64, window: i64) -> Option<f64> {
+    let start = now - window;
+    let mut covered = 0;
+    let mut red = 0;
+    for (index, &(time, status)) in statuses.iter().enumerate() {
+        let until = statuses.get(index + 1).map_or(now, |x| x.0);
+        let lasted = until.min(now) - time.max(start);
+        if lasted > 0 {
+            covered += lasted;
+            if status == RemoteStatus::Failing {
+                red += lasted;
+            }
+        }
+    }
+    if covered == 0 {
+        None
+    } else {
+        Some(red as f64 / covered as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status_report::{BuildInfo, StatusEntry, StatusReport};
+    use std::env;
+    use std::fs;
+
+    fn result(time: i64, status: RemoteStatus, revision: &str) -> JobResult {
+        JobResult {
+            job: "web-client".to_string(),
+            time,
+            status,
+            revision: Some(revision.to_string()),
+        }
+    }
+
+    #[test]
+    fn job_stats_count_recoveries_failures_and_flips() {
+        let now = 40 * DAY_SECS;
+        let hour = 60 * 60;
+        let results = vec![
+            // Over 30 days ago, so only counts towards time to recovery.
+            result(now - 31 * DAY_SECS, RemoteStatus::Failing, "a"),
+            result(now - 31 * DAY_SECS + 3 * hour, RemoteStatus::Passing, "b"),
+            // Passed, failed and passed again, all on revision b.
+            result(now - 10 * DAY_SECS, RemoteStatus::Failing, "b"),
+            result(now - 10 * DAY_SECS + hour, RemoteStatus::Passing, "b"),
+            result(now - 2 * DAY_SECS, RemoteStatus::InProgress, "c"),
+            result(now - 2 * DAY_SECS + hour, RemoteStatus::Failing, "c"),
+            // Still red.
+            result(now - 4 * hour, RemoteStatus::Failing, "d"),
+        ];
+
+        let stats = job_stats(&results, now);
+
+        assert_eq!(
+            stats.red,
+            RedStreaks {
+                mean_time_to_recovery: Some(Duration::hours(2)),
+                recoveries: 2,
+                longest: Some(Duration::hours(2 * 24 - 1)),
+            }
+        );
+        assert_eq!(stats.failure_rate_7_days, FailureRate { failed: 2, finished: 2 });
+        assert_eq!(stats.failure_rate_30_days, FailureRate { failed: 3, finished: 4 });
+        assert_eq!(stats.flaky_flips, 2);
+        assert!(stats.is_flaky());
+    }
+
+    #[test]
+    fn red_share_only_counts_recorded_time() {
+        let now = 10 * DAY_SECS;
+        let statuses = vec![
+            (now - 4 * DAY_SECS, RemoteStatus::Passing),
+            (now - DAY_SECS, RemoteStatus::Failing),
+        ];
+
+        assert_eq!(red_share(&statuses, now, 7 * DAY_SECS), Some(0.25));
+        assert_eq!(red_share(&statuses, now, DAY_SECS / 2), Some(1.0));
+        assert_eq!(red_share(&[], now, DAY_SECS), None);
+    }
+
+    #[test]
+    fn flaky_jobs_are_only_worked_out_again_after_new_results_or_an_hour() {
+        let path = env::temp_dir().join("rusty_build_light_test_flaky_jobs.db");
+        let _ = fs::remove_file(&path);
+        let history = History::open(&path, 90).unwrap();
+        // Passing, failing and passing again on the same revision makes a job flaky.
+        let record_flips = |history: &History, job: &str| {
+            for (number, &status) in [RemoteStatus::Passing, RemoteStatus::Failing, RemoteStatus::Passing]
+                .iter()
+                .enumerate()
+            {
+                let entry = StatusEntry {
+                    name: job.to_string(),
+                    status,
+                    latency: None,
+                    detail: None,
+                    build: Some(BuildInfo {
+                        number: number as u64,
+                        duration: None,
+                        revision: Some("a".to_string()),
+                    }),
+                };
+                history.record_results("jenkins", &StatusReport::with_entries(status, vec![entry]));
+            }
+            history.flush();
+        };
+        let mut flaky_jobs = FlakyJobs::default();
+        let now = time::Instant::now();
+
+        flaky_jobs.refresh(&history, "jenkins", now).unwrap();
+        record_flips(&history, "web-client");
+        flaky_jobs.refresh(&history, "jenkins", now).unwrap();
+        assert!(flaky_jobs.is_flaky("web-client"));
+
+        // Recorded by something else, e.g. an earlier run, so this history doesn't know they're new.
+        record_flips(&History::open(&path, 90).unwrap(), "api");
+        flaky_jobs.refresh(&history, "jenkins", now).unwrap();
+        assert!(!flaky_jobs.is_flaky("api"));
+        flaky_jobs
+            .refresh(&history, "jenkins", now + FLAKY_JOBS_MAX_AGE)
+            .unwrap();
+        assert!(flaky_jobs.is_flaky("api"));
+        fs::remove_file(&path).unwrap();
+    }
+}
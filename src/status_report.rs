@@ -0,0 +1,58 @@
+use remote_status::RemoteStatus;
+use retry::CircuitState;
+use std::time::Duration;
+
+// The overall status of an integration, along with whatever it knows about the individual
+// jobs, builds or endpoints that status was derived from.
+pub struct StatusReport {
+    pub status: RemoteStatus,
+    pub entries: Vec<StatusEntry>,
+    // Set if the integration couldn't retrieve anything at all from its remote.
+    pub error: Option<String>,
+    // What sort of error it was, e.g. "timed_out". See NetworkError::kind.
+    pub error_kind: Option<&'static str>,
+    // Filled in by the light running the integration, not the integration itself.
+    pub circuit_state: CircuitState,
+}
+
+pub struct StatusEntry {
+    pub name: String,
+    pub status: RemoteStatus,
+    pub latency: Option<Duration>,
+    pub detail: Option<String>,
+    // Set for entries that are builds, if the server says which build it was.
+    pub build: Option<BuildInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildInfo {
+    pub number: u64,
+    // None while the build's still running.
+    pub duration: Option<Duration>,
+    // The commit that was built, if the server says. Used to tell flaky jobs from broken ones.
+    pub revision: Option<String>,
+}
+
+impl StatusReport {
+    pub fn new(status: RemoteStatus) -> StatusReport {
+        StatusReport::with_entries(status, Vec::new())
+    }
+
+    pub fn with_entries(status: RemoteStatus, entries: Vec<StatusEntry>) -> StatusReport {
+        StatusReport {
+            status,
+            entries,
+            error: None,
+            error_kind: None,
+            circuit_state: CircuitState::Closed,
+        }
+    }
+
+    pub fn failed(error_kind: &'static str, error: String) -> StatusReport {
+        StatusReport {
+            error: Some(error),
+            error_kind: Some(error_kind),
+            ..StatusReport::new(RemoteStatus::Unknown)
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// What to do once a light has used up its restart budget.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GiveUpAction {
+    // Stop just that light, and show an error animation on its LED. The other lights carry on.
+    DisableLight,
+    // Stop the whole build light, e.g. so systemd restarts it.
+    Stop,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RestartPolicy {
+    // How many times a light may be restarted within window before giving up on it.
+    pub max_restarts: u32,
+    pub window: Duration,
+    // Delay before the first restart in a window. Doubles with each subsequent restart, up to max_delay.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub on_give_up: GiveUpAction,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: 5,
+            window: Duration::from_secs(600),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            on_give_up: GiveUpAction::DisableLight,
+        }
+    }
+}
+
+// Keeps track of when a light has had to be restarted, and decides whether it should be again.
+// Restarts older than the policy's window are forgotten, so occasional failures never add up to giving up.
+pub struct Supervisor {
+    policy: RestartPolicy,
+    restarts: VecDeque<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Supervisor {
+        Supervisor {
+            policy,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    pub fn policy(&self) -> &RestartPolicy {
+        &self.policy
+    }
+
+    // Called when the light has failed. Returns how long to wait before restarting it,
+    // or None if it's out of restarts.
+    pub fn record_failure(&mut self, now: Instant) -> Option<Duration> {
+        while self
+            .restarts
+            .front()
+            .map_or(false, |&restart| now.duration_since(restart) >= self.policy.window)
+        {
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() as u32 >= self.policy.max_restarts {
+            return None;
+        }
+
+        let multiplier = 1u32
+            .checked_shl(self.restarts.len() as u32)
+            .unwrap_or(u32::max_value());
+        self.restarts.push_back(now);
+        Some(cmp::min(
+            self.policy
+                .initial_delay
+                .checked_mul(multiplier)
+                .unwrap_or(self.policy.max_delay),
+            self.policy.max_delay,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_back_off_and_are_forgotten_after_the_window() {
+        let mut supervisor = Supervisor::new(RestartPolicy {
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3),
+            on_give_up: GiveUpAction::DisableLight,
+        });
+        let start = Instant::now();
+
+        assert_eq!(supervisor.record_failure(start), Some(Duration::from_secs(1)));
+        assert_eq!(supervisor.record_failure(start), Some(Duration::from_secs(2)));
+        assert_eq!(supervisor.record_failure(start), Some(Duration::from_secs(3)));
+        assert_eq!(supervisor.record_failure(start), None);
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(supervisor.record_failure(later), Some(Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,156 @@
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+
+// Talks to systemd when we're running as a Type=notify service: says when we're ready, pings the watchdog,
+// and keeps the status text shown by `systemctl status` up to date. Does nothing when not run by systemd.
+pub struct Notifier {
+    socket: Option<(UnixDatagram, PathBuf)>,
+    // How often to ping the watchdog, if WatchdogSec= is set. Half the watchdog timeout, as systemd recommends.
+    watchdog_interval: Option<Duration>,
+    last_watchdog_ping: Option<Instant>,
+    last_status: String,
+}
+
+impl Notifier {
+    pub fn from_env() -> Notifier {
+        let watchdog_usec = env::var("WATCHDOG_USEC").ok();
+        let watchdog_pid = env::var("WATCHDOG_PID").ok();
+        Notifier::new(
+            env::var("NOTIFY_SOCKET").ok(),
+            watchdog_interval(
+                watchdog_usec.as_ref().map(String::as_str),
+                watchdog_pid.as_ref().map(String::as_str),
+            ),
+        )
+    }
+
+    fn new(notify_socket: Option<String>, watchdog_interval: Option<Duration>) -> Notifier {
+        let socket = notify_socket.and_then(|path| {
+            connect(&path)
+                .map_err(|err| {
+                    warn!("Unable to connect to systemd's notify socket {}. Error: {}", path, err)
+                })
+                .ok()
+        });
+        Notifier {
+            socket,
+            watchdog_interval,
+            last_watchdog_ping: None,
+            last_status: String::new(),
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    // Called regularly from the main loop. Updates the status text if it's changed, and pings the watchdog if
+    // a ping is due. If we're not healthy, the ping is held back, so systemd restarts us once the watchdog times out.
+    pub fn update(&mut self, status: &str, healthy: bool) {
+        if status != self.last_status {
+            self.notify(&format!("STATUS={}", status));
+            self.last_status = status.to_string();
+        }
+
+        let interval = match self.watchdog_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let ping_due = self
+            .last_watchdog_ping
+            .map_or(true, |last_ping| last_ping.elapsed() >= interval);
+        if healthy && ping_due {
+            self.notify("WATCHDOG=1");
+            self.last_watchdog_ping = Some(Instant::now());
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((ref socket, ref path)) = self.socket {
+            if let Err(e) = socket.send_to(state.as_bytes(), path) {
+                warn!("Unable to notify systemd of {}. Error: {}", state, e);
+            }
+        }
+    }
+}
+
+// NOTIFY_SOCKET is either a path, or the name of a socket in the abstract namespace, starting with @.
+// Sending to an abstract socket needs Rust 1.70, so only paths are supported.
+fn connect(path: &str) -> io::Result<(UnixDatagram, PathBuf)> {
+    if path.starts_with('@') {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "sockets in the abstract namespace aren't supported",
+        ));
+    }
+    Ok((UnixDatagram::unbound()?, PathBuf::from(path)))
+}
+
+// WATCHDOG_USEC is only meant for us if WATCHDOG_PID is unset, or is our own PID.
+fn watchdog_interval(watchdog_usec: Option<&str>, watchdog_pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = watchdog_pid {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+    watchdog_usec
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|&usec| usec > 0)
+        .map(|usec| Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn watchdog_is_only_pinged_while_healthy() {
+        let path = env::temp_dir().join("rusty_build_light_test_notify.sock");
+        let _ = fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+        let mut notifier = Notifier::new(
+            Some(path.to_string_lossy().into_owned()),
+            Some(Duration::from_secs(0)),
+        );
+
+        notifier.ready();
+        notifier.update("Jenkins: stalled", false);
+        notifier.update("Jenkins: Passing", true);
+        let mut messages = Vec::new();
+        let mut buffer = [0; 64];
+        while let Ok(length) = systemd.recv(&mut buffer) {
+            messages.push(String::from_utf8_lossy(&buffer[..length]).into_owned());
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            messages,
+            vec![
+                "READY=1",
+                "STATUS=Jenkins: stalled",
+                "STATUS=Jenkins: Passing",
+                "WATCHDOG=1"
+            ]
+        );
+    }
+
+    #[test]
+    fn watchdog_for_another_process_is_ignored() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod webhook_server;
+mod webhook_payloads;
@@ -0,0 +1,254 @@
+use integrations::jenkins_response::JenkinsBuildStatus;
+use remote_status::RemoteStatus;
+
+// Sent by the Jenkins Notification plugin. See https://plugins.jenkins.io/notification
+#[derive(Deserialize)]
+pub struct JenkinsNotification {
+    pub name: String,
+    pub build: JenkinsNotificationBuild,
+}
+
+#[derive(Deserialize)]
+pub struct JenkinsNotificationBuild {
+    pub phase: JenkinsBuildPhase,
+    pub status: Option<JenkinsBuildStatus>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum JenkinsBuildPhase {
+    Queued,
+    Started,
+    Completed,
+    Finalized,
+}
+
+impl JenkinsNotification {
+    pub fn to_remote_status(&self) -> Option<RemoteStatus> {
+        match self.build.phase {
+            JenkinsBuildPhase::Queued | JenkinsBuildPhase::Started => {
+                Some(RemoteStatus::InProgress)
+            }
+            // Completed and Finalized both carry the result. Each one is acted on, but they agree, so the
+            // second one changes nothing.
+            JenkinsBuildPhase::Completed | JenkinsBuildPhase::Finalized => match self.build.status {
+                Some(JenkinsBuildStatus::Success) => Some(RemoteStatus::Passing),
+                Some(JenkinsBuildStatus::Failure) | Some(JenkinsBuildStatus::Unstable) => {
+                    Some(RemoteStatus::Failing)
+                }
+                _ => Some(RemoteStatus::Unknown),
+            },
+        }
+    }
+}
+
+// GitHub sends the same shape of object for check suites, check runs and workflow runs,
+// just under a different key.
+#[derive(Deserialize)]
+pub struct GitHubRun {
+    pub status: String,
+    pub conclusion: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GitHubCheckSuiteEvent {
+    pub check_suite: GitHubRun,
+}
+
+#[derive(Deserialize)]
+pub struct GitHubCheckRunEvent {
+    pub check_run: GitHubRun,
+}
+
+#[derive(Deserialize)]
+pub struct GitHubWorkflowRunEvent {
+    pub workflow_run: GitHubRun,
+}
+
+#[derive(Deserialize)]
+pub struct GitHubStatusEvent {
+    pub state: String,
+}
+
+impl GitHubRun {
+    pub fn to_remote_status(&self) -> Option<RemoteStatus> {
+        if self.status != "completed" {
+            return Some(RemoteStatus::InProgress);
+        }
+        match self.conclusion.as_ref().map(String::as_str) {
+            Some("success") => Some(RemoteStatus::Passing),
+            Some("failure") | Some("timed_out") | Some("startup_failure") => {
+                Some(RemoteStatus::Failing)
+            }
+            // Cancelled, skipped, neutral etc. say nothing about the health of the build.
+            _ => None,
+        }
+    }
+}
+
+impl GitHubStatusEvent {
+    pub fn to_remote_status(&self) -> Option<RemoteStatus> {
+        match self.state.as_str() {
+            "pending" => Some(RemoteStatus::InProgress),
+            "success" => Some(RemoteStatus::Passing),
+            "failure" | "error" => Some(RemoteStatus::Failing),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GitLabPipelineEvent {
+    pub object_attributes: GitLabPipelineAttributes,
+}
+
+#[derive(Deserialize)]
+pub struct GitLabPipelineAttributes {
+    pub status: String,
+}
+
+#[derive(Deserialize)]
+pub struct GitLabJobEvent {
+    pub build_status: String,
+}
+
+pub fn gitlab_status_to_remote_status(status: &str) -> Option<RemoteStatus> {
+    match status {
+        "created" | "waiting_for_resource" | "preparing" | "pending" | "running" => {
+            Some(RemoteStatus::InProgress)
+        }
+        "success" => Some(RemoteStatus::Passing),
+        "failed" => Some(RemoteStatus::Failing),
+        // Canceled, skipped and manual pipelines don't tell us anything.
+        _ => None,
+    }
+}
+
+// Our own minimal format, for anything that can make an HTTP call: {"status": "passing"}
+#[derive(Deserialize)]
+pub struct GenericStatusEvent {
+    pub status: GenericStatus,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GenericStatus {
+    Unknown,
+    InProgress,
+    Passing,
+    Warning,
+    Failing,
+}
+
+impl GenericStatusEvent {
+    pub fn to_remote_status(&self) -> Option<RemoteStatus> {
+        match self.status {
+            GenericStatus::Unknown => Some(RemoteStatus::Unknown),
+            GenericStatus::InProgress => Some(RemoteStatus::InProgress),
+            GenericStatus::Passing => Some(RemoteStatus::Passing),
+            GenericStatus::Warning => Some(RemoteStatus::Warning),
+            GenericStatus::Failing => Some(RemoteStatus::Failing),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn jenkins(json: &str) -> Option<RemoteStatus> {
+        serde_json::from_str::<JenkinsNotification>(json)
+            .unwrap()
+            .to_remote_status()
+    }
+
+    fn github_run(status: &str, conclusion: Option<&str>) -> Option<RemoteStatus> {
+        GitHubRun {
+            status: status.to_string(),
+            conclusion: conclusion.map(str::to_string),
+        }
+        .to_remote_status()
+    }
+
+    fn github_status(state: &str) -> Option<RemoteStatus> {
+        GitHubStatusEvent {
+            state: state.to_string(),
+        }
+        .to_remote_status()
+    }
+
+    fn generic(json: &str) -> Option<RemoteStatus> {
+        serde_json::from_str::<GenericStatusEvent>(json)
+            .unwrap()
+            .to_remote_status()
+    }
+
+    #[test]
+    fn jenkins_notifications_map_to_statuses() {
+        assert_eq!(
+            jenkins(r#"{"name": "api", "build": {"phase": "QUEUED"}}"#),
+            Some(RemoteStatus::InProgress)
+        );
+        assert_eq!(
+            jenkins(r#"{"name": "api", "build": {"phase": "STARTED"}}"#),
+            Some(RemoteStatus::InProgress)
+        );
+        assert_eq!(
+            jenkins(r#"{"name": "api", "build": {"phase": "COMPLETED", "status": "SUCCESS"}}"#),
+            Some(RemoteStatus::Passing)
+        );
+        assert_eq!(
+            jenkins(r#"{"name": "api", "build": {"phase": "FINALIZED", "status": "FAILURE"}}"#),
+            Some(RemoteStatus::Failing)
+        );
+        assert_eq!(
+            jenkins(r#"{"name": "api", "build": {"phase": "COMPLETED", "status": "UNSTABLE"}}"#),
+            Some(RemoteStatus::Failing)
+        );
+        assert_eq!(
+            jenkins(r#"{"name": "api", "build": {"phase": "COMPLETED", "status": "ABORTED"}}"#),
+            Some(RemoteStatus::Unknown)
+        );
+    }
+
+    #[test]
+    fn github_runs_map_to_statuses() {
+        assert_eq!(github_run("queued", None), Some(RemoteStatus::InProgress));
+        assert_eq!(github_run("in_progress", None), Some(RemoteStatus::InProgress));
+        assert_eq!(github_run("completed", Some("success")), Some(RemoteStatus::Passing));
+        assert_eq!(github_run("completed", Some("failure")), Some(RemoteStatus::Failing));
+        assert_eq!(github_run("completed", Some("timed_out")), Some(RemoteStatus::Failing));
+        assert_eq!(github_run("completed", Some("cancelled")), None);
+        assert_eq!(github_run("completed", None), None);
+    }
+
+    #[test]
+    fn github_statuses_map_to_statuses() {
+        assert_eq!(github_status("pending"), Some(RemoteStatus::InProgress));
+        assert_eq!(github_status("success"), Some(RemoteStatus::Passing));
+        assert_eq!(github_status("failure"), Some(RemoteStatus::Failing));
+        assert_eq!(github_status("error"), Some(RemoteStatus::Failing));
+        assert_eq!(github_status("something_new"), None);
+    }
+
+    #[test]
+    fn gitlab_statuses_map_to_statuses() {
+        assert_eq!(gitlab_status_to_remote_status("pending"), Some(RemoteStatus::InProgress));
+        assert_eq!(gitlab_status_to_remote_status("running"), Some(RemoteStatus::InProgress));
+        assert_eq!(gitlab_status_to_remote_status("success"), Some(RemoteStatus::Passing));
+        assert_eq!(gitlab_status_to_remote_status("failed"), Some(RemoteStatus::Failing));
+        assert_eq!(gitlab_status_to_remote_status("canceled"), None);
+        assert_eq!(gitlab_status_to_remote_status("manual"), None);
+    }
+
+    #[test]
+    fn generic_statuses_map_to_statuses() {
+        assert_eq!(generic(r#"{"status": "unknown"}"#), Some(RemoteStatus::Unknown));
+        assert_eq!(generic(r#"{"status": "in_progress"}"#), Some(RemoteStatus::InProgress));
+        assert_eq!(generic(r#"{"status": "passing"}"#), Some(RemoteStatus::Passing));
+        assert_eq!(generic(r#"{"status": "warning"}"#), Some(RemoteStatus::Warning));
+        assert_eq!(generic(r#"{"status": "failing"}"#), Some(RemoteStatus::Failing));
+        assert!(serde_json::from_str::<GenericStatusEvent>(r#"{"status": "green"}"#).is_err());
+    }
+}
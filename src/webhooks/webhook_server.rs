@@ -0,0 +1,555 @@
+use failure::Error;
+use futures::future;
+use futures::sync::mpsc::UnboundedSender;
+use futures::{Future, Stream};
+use hex;
+use hmac::{Hmac, Mac};
+use hyper;
+use hyper::header::{ContentLength, Headers};
+use hyper::server::{Http, Request, Response, Service};
+use hyper::{Method, StatusCode};
+use remote_status::RemoteStatus;
+use secrets::Secret;
+use serde_json;
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str;
+use tokio_core::reactor::Handle;
+use url::form_urlencoded;
+use webhooks::webhook_payloads::*;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Far bigger than any payload we understand. Bodies are read before they can be authenticated,
+// so anything bigger is turned away rather than held in memory.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+// Where to send statuses for each light, by name. Shared with whatever starts and stops lights,
+// so lights can come and go while the server is running.
+pub type WebhookLights = Rc<RefCell<HashMap<String, UnboundedSender<RemoteStatus>>>>;
+
+#[derive(Debug)]
+enum WebhookSource {
+    Jenkins,
+    GitHub,
+    GitLab,
+    Generic,
+}
+
+pub struct WebhookServer {
+    listen_address: SocketAddr,
+    handler: WebhookHandler,
+}
+
+impl WebhookServer {
+    pub fn new(
+        listen_address: &str,
+        secret: Option<Secret>,
+        lights: WebhookLights,
+    ) -> Result<WebhookServer, Error> {
+        let parsed_address = listen_address.parse::<SocketAddr>().map_err(|err| {
+            format_err!(
+                "Unable to parse webhook listen address {}: {}",
+                listen_address,
+                err
+            )
+        })?;
+        Ok(WebhookServer {
+            listen_address: parsed_address,
+            handler: WebhookHandler {
+                secret: secret.filter(|x| !x.expose().is_empty()),
+                lights,
+            },
+        })
+    }
+
+    // Starts listening, and serves webhooks on the given event loop for as long as it runs.
+    pub fn start(self, handle: &Handle) -> Result<(), Error> {
+        if self.handler.secret.is_none() {
+            warn!(target: "webhook", "No webhook_secret configured. Incoming webhooks will NOT be verified.");
+        }
+
+        let handler = Rc::new(self.handler);
+        let connections = Http::new().serve_addr_handle(&self.listen_address, handle, move || {
+            Ok(WebhookService {
+                handler: handler.clone(),
+            })
+        })?;
+        info!(
+            target: "webhook",
+            "Listening for webhooks on http://{}",
+            connections.incoming_ref().local_addr()
+        );
+        let connection_handle = handle.clone();
+        handle.spawn(
+            connections
+                .for_each(move |connection| {
+                    connection_handle.spawn(connection.map(|_| ()).map_err(|err| {
+                        debug!(target: "webhook", "Webhook connection failed. Error: {}", err)
+                    }));
+                    Ok(())
+                })
+                .map_err(|err| error!(target: "webhook", "Webhook server stopped. Error: {}", err)),
+        );
+        Ok(())
+    }
+}
+
+struct WebhookService {
+    handler: Rc<WebhookHandler>,
+}
+
+impl Service for WebhookService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+    fn call(&self, request: Request) -> Self::Future {
+        if *request.method() != Method::Post {
+            return Box::new(future::ok(respond(
+                StatusCode::MethodNotAllowed,
+                "Webhooks must be POSTed.",
+            )));
+        }
+
+        let (_, uri, _, headers, body) = request.deconstruct();
+        if headers
+            .get::<ContentLength>()
+            .map_or(false, |length| length.0 > MAX_BODY_BYTES as u64)
+        {
+            return Box::new(future::ok(payload_too_large()));
+        }
+
+        let handler = self.handler.clone();
+        Box::new(read_body(body).map(move |body| match body {
+            Some(body) => handler.handle(uri.path(), uri.query(), &headers, &body),
+            None => payload_too_large(),
+        }))
+    }
+}
+
+// None if the body's bigger than MAX_BODY_BYTES. Stops reading as soon as it is, since the Content-Length
+// header may be missing, or wrong.
+fn read_body<S>(body: S) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = hyper::Error>>
+where
+    S: Stream<Item = hyper::Chunk, Error = hyper::Error> + 'static,
+{
+    Box::new(
+        body.map_err(Some)
+            .fold(Vec::new(), |mut body, chunk| {
+                if body.len() + chunk.len() > MAX_BODY_BYTES {
+                    return Err(None);
+                }
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .then(|body| match body {
+                Ok(body) => Ok(Some(body)),
+                Err(None) => Ok(None),
+                Err(Some(err)) => Err(err),
+            }),
+    )
+}
+
+fn payload_too_large() -> Response {
+    respond(
+        StatusCode::PayloadTooLarge,
+        &format!("Payloads must be at most {} bytes.", MAX_BODY_BYTES),
+    )
+}
+
+struct WebhookHandler {
+    secret: Option<Secret>,
+    lights: WebhookLights,
+}
+
+impl WebhookHandler {
+    // Expects paths of the form /<source>/<light>, e.g. /github/jenkins
+    fn handle(&self, path: &str, query: Option<&str>, headers: &Headers, body: &[u8]) -> Response {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if segments.len() != 2 {
+            return respond(
+                StatusCode::NotFound,
+                "Expected a path of the form /<source>/<light>.",
+            );
+        }
+
+        let source = match segments[0] {
+            "jenkins" => WebhookSource::Jenkins,
+            "github" => WebhookSource::GitHub,
+            "gitlab" => WebhookSource::GitLab,
+            "generic" => WebhookSource::Generic,
+            other => {
+                return respond(
+                    StatusCode::NotFound,
+                    &format!("Unknown webhook source: {}", other),
+                )
+            }
+        };
+
+        // Authenticate before looking up the light, so that which lights exist isn't given away.
+        let light_name = segments[1];
+        if !self.is_authentic(&source, query, headers, body) {
+            warn!(
+                target: "webhook",
+                "Rejected {:?} webhook for light {} due to a missing or invalid signature.",
+                source, light_name
+            );
+            return respond(StatusCode::Unauthorized, "Invalid signature or token.");
+        }
+
+        let light = match self.lights.borrow().get(light_name).cloned() {
+            Some(light) => light,
+            None => {
+                return respond(
+                    StatusCode::NotFound,
+                    &format!("No light named {}", light_name),
+                )
+            }
+        };
+
+        match parse_status(&source, headers, body) {
+            Ok(Some(status)) => {
+                info!(
+                    target: "webhook",
+                    "{:?} webhook reported {:?} for light {}.",
+                    source, status, light_name
+                );
+                match light.unbounded_send(status) {
+                    Ok(_) => respond(StatusCode::Ok, "OK"),
+                    Err(_) => respond(
+                        StatusCode::ServiceUnavailable,
+                        &format!("Light {} is not running.", light_name),
+                    ),
+                }
+            }
+            Ok(None) => respond(StatusCode::Ok, "Event ignored."),
+            Err(e) => {
+                warn!(
+                    target: "webhook",
+                    "Unable to parse {:?} webhook for light {}. Error: {}",
+                    source, light_name, e
+                );
+                respond(
+                    StatusCode::BadRequest,
+                    &format!("Unable to parse payload: {}", e),
+                )
+            }
+        }
+    }
+
+    fn is_authentic(
+        &self,
+        source: &WebhookSource,
+        query: Option<&str>,
+        headers: &Headers,
+        body: &[u8],
+    ) -> bool {
+        let secret = match self.secret {
+            Some(ref secret) => secret.expose(),
+            None => return true,
+        };
+
+        match *source {
+            // The notification plugin can't sign its payloads, so the secret goes in the URL instead.
+            WebhookSource::Jenkins => query_param(query, "token").map_or(false, |token| {
+                constant_time_eq(token.as_bytes(), secret.as_bytes())
+            }),
+            WebhookSource::GitLab => header_value(headers, "X-Gitlab-Token")
+                .map_or(false, |token| constant_time_eq(token, secret.as_bytes())),
+            WebhookSource::GitHub => header_value(headers, "X-Hub-Signature-256")
+                .map_or(false, |signature| verify_signature(secret, body, signature)),
+            WebhookSource::Generic => header_value(headers, "X-Build-Light-Signature")
+                .map_or(false, |signature| verify_signature(secret, body, signature)),
+        }
+    }
+}
+
+fn parse_status(
+    source: &WebhookSource,
+    headers: &Headers,
+    body: &[u8],
+) -> Result<Option<RemoteStatus>, Error> {
+    match *source {
+        WebhookSource::Jenkins => {
+            let notification: JenkinsNotification = serde_json::from_slice(body)?;
+            info!(
+                target: "webhook",
+                "Jenkins job {} reported phase {:?}.",
+                notification.name, notification.build.phase
+            );
+            Ok(notification.to_remote_status())
+        }
+        WebhookSource::GitHub => {
+            let event = header_value(headers, "X-GitHub-Event")
+                .and_then(|x| str::from_utf8(x).ok())
+                .unwrap_or("");
+            match event {
+                "status" => Ok(serde_json::from_slice::<GitHubStatusEvent>(body)?.to_remote_status()),
+                "check_suite" => Ok(serde_json::from_slice::<GitHubCheckSuiteEvent>(body)?
+                    .check_suite
+                    .to_remote_status()),
+                "check_run" => Ok(serde_json::from_slice::<GitHubCheckRunEvent>(body)?
+                    .check_run
+                    .to_remote_status()),
+                "workflow_run" => Ok(serde_json::from_slice::<GitHubWorkflowRunEvent>(body)?
+                    .workflow_run
+                    .to_remote_status()),
+                // Includes "ping", which GitHub sends when the hook is first created.
+                _ => Ok(None),
+            }
+        }
+        WebhookSource::GitLab => {
+            let event = header_value(headers, "X-Gitlab-Event")
+                .and_then(|x| str::from_utf8(x).ok())
+                .unwrap_or("");
+            match event {
+                "Pipeline Hook" => {
+                    let pipeline: GitLabPipelineEvent = serde_json::from_slice(body)?;
+                    Ok(gitlab_status_to_remote_status(
+                        &pipeline.object_attributes.status,
+                    ))
+                }
+                "Job Hook" => {
+                    let job: GitLabJobEvent = serde_json::from_slice(body)?;
+                    Ok(gitlab_status_to_remote_status(&job.build_status))
+                }
+                _ => Ok(None),
+            }
+        }
+        WebhookSource::Generic => {
+            Ok(serde_json::from_slice::<GenericStatusEvent>(body)?.to_remote_status())
+        }
+    }
+}
+
+// Signatures are expected in GitHub's format: "sha256=<hex-encoded HMAC-SHA256 of the body>"
+fn verify_signature(secret: &str, body: &[u8], signature_header: &[u8]) -> bool {
+    let signature = match str::from_utf8(signature_header) {
+        Ok(signature) => signature.trim(),
+        Err(_) => return false,
+    };
+    if !signature.starts_with("sha256=") {
+        return false;
+    }
+    let expected = match hex::decode(&signature["sha256=".len()..]) {
+        Ok(expected) => expected,
+        Err(_) => return false,
+    };
+    let mut mac = match HmacSha256::new_varkey(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.input(body);
+    mac.verify(&expected).is_ok()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn header_value<'a>(headers: &'a Headers, name: &str) -> Option<&'a [u8]> {
+    headers.get_raw(name).and_then(|raw| raw.one())
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query.and_then(|query| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    })
+}
+
+fn respond(code: StatusCode, message: &str) -> Response {
+    Response::new()
+        .with_status(code)
+        .with_header(ContentLength(message.len() as u64))
+        .with_body(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use futures::sync::mpsc;
+    use futures::sync::mpsc::UnboundedReceiver;
+    use hyper::Chunk;
+
+    const SECRET: &str = "s3cret";
+    const GENERIC_BODY: &[u8] = br#"{"status": "failing"}"#;
+
+    fn handler() -> (WebhookHandler, UnboundedReceiver<RemoteStatus>) {
+        let (tx, rx) = mpsc::unbounded();
+        let lights = Rc::new(RefCell::new(HashMap::new()));
+        lights.borrow_mut().insert("jenkins".to_string(), tx);
+        let handler = WebhookHandler {
+            secret: Some(serde_json::from_str(&format!("\"{}\"", SECRET)).unwrap()),
+            lights,
+        };
+        (handler, rx)
+    }
+
+    // Everything the handler passed on to the light.
+    fn delivered(handler: WebhookHandler, rx: UnboundedReceiver<RemoteStatus>) -> Vec<RemoteStatus> {
+        drop(handler);
+        rx.collect().wait().unwrap()
+    }
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(body);
+        format!("sha256={}", hex::encode(mac.result().code()))
+    }
+
+    fn headers(name: &'static str, value: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.set_raw(name, value.to_string());
+        headers
+    }
+
+    #[test]
+    fn valid_signature_is_accepted() {
+        let signature = sign(SECRET, GENERIC_BODY);
+
+        assert!(verify_signature(SECRET, GENERIC_BODY, signature.as_bytes()));
+    }
+
+    #[test]
+    fn invalid_signatures_are_rejected() {
+        let signature = sign(SECRET, GENERIC_BODY);
+
+        assert!(!verify_signature("wrong", GENERIC_BODY, signature.as_bytes()));
+        assert!(!verify_signature(SECRET, br#"{"status": "passing"}"#, signature.as_bytes()));
+        assert!(!verify_signature(SECRET, GENERIC_BODY, &signature.as_bytes()["sha256=".len()..]));
+        assert!(!verify_signature(SECRET, GENERIC_BODY, b"sha256=not-hex"));
+        assert!(!verify_signature(SECRET, GENERIC_BODY, b""));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents_and_length() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+
+    #[test]
+    fn signed_generic_webhook_is_delivered() {
+        let (handler, rx) = handler();
+        let headers = headers("X-Build-Light-Signature", &sign(SECRET, GENERIC_BODY));
+
+        let response = handler.handle("/generic/jenkins", None, &headers, GENERIC_BODY);
+
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(delivered(handler, rx), vec![RemoteStatus::Failing]);
+    }
+
+    #[test]
+    fn badly_signed_github_webhook_is_rejected() {
+        let (handler, rx) = handler();
+        let mut headers = headers("X-Hub-Signature-256", &sign("wrong", GENERIC_BODY));
+        headers.set_raw("X-GitHub-Event", "status");
+
+        let body = br#"{"state": "success"}"#;
+
+        let response = handler.handle("/github/jenkins", None, &headers, body);
+
+        assert_eq!(response.status(), StatusCode::Unauthorized);
+        assert!(delivered(handler, rx).is_empty());
+    }
+
+    #[test]
+    fn jenkins_webhook_needs_the_token() {
+        let body = br#"{"name": "api-server", "build": {"phase": "STARTED"}}"#;
+        let (handler, rx) = handler();
+
+        let missing = handler.handle("/jenkins/jenkins", None, &Headers::new(), body);
+        let wrong = handler.handle("/jenkins/jenkins", Some("token=wrong"), &Headers::new(), body);
+        let right = handler.handle("/jenkins/jenkins", Some("token=s3cret"), &Headers::new(), body);
+
+        assert_eq!(missing.status(), StatusCode::Unauthorized);
+        assert_eq!(wrong.status(), StatusCode::Unauthorized);
+        assert_eq!(right.status(), StatusCode::Ok);
+        assert_eq!(delivered(handler, rx), vec![RemoteStatus::InProgress]);
+    }
+
+    #[test]
+    fn gitlab_webhook_needs_the_token() {
+        let body = br#"{"object_attributes": {"status": "failed"}}"#;
+        let (handler, rx) = handler();
+        let mut right = headers("X-Gitlab-Token", SECRET);
+        right.set_raw("X-Gitlab-Event", "Pipeline Hook");
+        let mut wrong = headers("X-Gitlab-Token", "wrong");
+        wrong.set_raw("X-Gitlab-Event", "Pipeline Hook");
+
+        let rejected = handler.handle("/gitlab/jenkins", None, &wrong, body);
+        let accepted = handler.handle("/gitlab/jenkins", None, &right, body);
+
+        assert_eq!(rejected.status(), StatusCode::Unauthorized);
+        assert_eq!(accepted.status(), StatusCode::Ok);
+        assert_eq!(delivered(handler, rx), vec![RemoteStatus::Failing]);
+    }
+
+    #[test]
+    fn unknown_sources_lights_and_paths_are_not_found() {
+        let (handler, rx) = handler();
+        let headers = headers("X-Build-Light-Signature", &sign(SECRET, GENERIC_BODY));
+
+        for path in &["/travis/jenkins", "/generic/unity", "/generic", "/generic/jenkins/extra"] {
+            let response = handler.handle(path, None, &headers, GENERIC_BODY);
+            assert_eq!(response.status(), StatusCode::NotFound, "{}", path);
+        }
+        assert!(delivered(handler, rx).is_empty());
+    }
+
+    #[test]
+    fn webhooks_are_authenticated_before_the_light_is_looked_up() {
+        let (handler, rx) = handler();
+        let headers = headers("X-Build-Light-Signature", &sign("wrong", GENERIC_BODY));
+
+        let unknown = handler.handle("/generic/unity", None, &headers, GENERIC_BODY);
+        let known = handler.handle("/generic/jenkins", None, &headers, GENERIC_BODY);
+
+        assert_eq!(unknown.status(), StatusCode::Unauthorized);
+        assert_eq!(known.status(), StatusCode::Unauthorized);
+        assert!(delivered(handler, rx).is_empty());
+    }
+
+    #[test]
+    fn ignored_events_are_not_delivered() {
+        let (handler, rx) = handler();
+        let body = br#"{"zen": "Keep it logically awesome."}"#;
+        let mut headers = headers("X-Hub-Signature-256", &sign(SECRET, body));
+        headers.set_raw("X-GitHub-Event", "ping");
+
+        let response = handler.handle("/github/jenkins", None, &headers, body);
+
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(delivered(handler, rx).is_empty());
+    }
+
+    #[test]
+    fn bodies_are_read_up_to_the_limit() {
+        let chunks = vec![Chunk::from(vec![b'a'; MAX_BODY_BYTES - 1]), Chunk::from(vec![b'b'])];
+
+        let body = read_body(stream::iter_ok(chunks)).wait().unwrap();
+
+        assert_eq!(body.map(|x| x.len()), Some(MAX_BODY_BYTES));
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_not_read() {
+        let chunks = vec![Chunk::from(vec![b'a'; MAX_BODY_BYTES]), Chunk::from(vec![b'b'])];
+
+        let body = read_body(stream::iter_ok(chunks)).wait().unwrap();
+
+        assert!(body.is_none());
+    }
+}